@@ -0,0 +1,131 @@
+//! Content hashes kept across runs, for files which didn't change since.
+
+use std::collections;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path;
+use std::sync;
+
+use crate::sys::{self, MetadataExt, OsStrExt};
+use crate::{compute_hash, vardbapi, CheckOptions, Error};
+
+/// Content hashes of files, keyed by path and trusted while their mtime and size are unchanged.
+///
+/// A cache shared through `CheckOptions::hash_cache` is consulted before hashing a file,
+/// and filled with each newly computed hash; `save` it at the end of a run.
+///
+/// The file format is one `<mtime> <mtime nsec> <size> <hash> <path>` line per file.
+/// Malformed lines are skipped: at worst, a file gets hashed again.
+#[derive(Debug, Default)]
+pub struct HashCache {
+    entries: sync::Mutex<collections::HashMap<path::PathBuf, Entry>>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    hash: vardbapi::FileHash,
+}
+
+impl Entry {
+    /// Whether this entry still describes a file with the given metadata.
+    fn is_fresh(&self, metadata: &fs::Metadata) -> bool {
+        (self.mtime, self.mtime_nsec, self.size) == (metadata.mtime(), metadata.mtime_nsec(), metadata.size())
+    }
+}
+
+impl HashCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a cache saved by `save`; a missing file is an empty cache.
+    pub fn load(path: &path::Path) -> Result<HashCache, Error> {
+        let failed = |e| Error::HashCacheFailed { path: path.into(), source: e };
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashCache::new()),
+            Err(e) => return Err(failed(e)),
+        };
+        let mut entries = collections::HashMap::new();
+        for (index, line) in io::BufReader::new(file).split(b'\n').enumerate() {
+            let line = line.map_err(failed)?;
+            if let Some((file_path, entry)) = parse_line(path, index + 1, &line) {
+                entries.insert(file_path, entry);
+            }
+        }
+        Ok(HashCache { entries: sync::Mutex::new(entries) })
+    }
+
+    /// Write the cache to `path`, replacing any previous content.
+    ///
+    /// Paths containing a newline can't be represented, and are left out.
+    pub fn save(&self, path: &path::Path) -> Result<(), Error> {
+        let failed = |e| Error::HashCacheFailed { path: path.into(), source: e };
+        let mut out = io::BufWriter::new(fs::File::create(path).map_err(failed)?);
+        let entries = self.entries.lock().expect("Hash cache poisoned");
+        for (file_path, entry) in entries.iter() {
+            let raw_path = file_path.as_os_str().as_bytes();
+            if raw_path.contains(&b'\n') {
+                continue;
+            }
+            write!(out, "{} {} {} {} ", entry.mtime, entry.mtime_nsec, entry.size, entry.hash).map_err(failed)?;
+            out.write_all(raw_path).map_err(failed)?;
+            out.write_all(b"\n").map_err(failed)?;
+        }
+        out.flush().map_err(failed)
+    }
+
+    /// Number of cached hashes.
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("Hash cache poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the file at `path` doesn't match `expected`, hashing it only if not cached.
+    pub(crate) fn changed(&self, path: &path::Path, metadata: &fs::Metadata, expected: &vardbapi::FileHash, options: &CheckOptions) -> Result<bool, Error> {
+        let cached = self.entries.lock().expect("Hash cache poisoned")
+            .get(path)
+            .filter(|entry| entry.is_fresh(metadata) && same_algorithm(&entry.hash, expected))
+            .map(|entry| entry.hash.clone());
+        let actual = match cached {
+            Some(hash) => hash,
+            None => {
+                let hash = compute_hash(path, expected, options)?;
+                let entry = Entry { mtime: metadata.mtime(), mtime_nsec: metadata.mtime_nsec(), size: metadata.size(), hash: hash.clone() };
+                self.entries.lock().expect("Hash cache poisoned").insert(path.into(), entry);
+                hash
+            },
+        };
+        Ok(&actual != expected)
+    }
+}
+
+/// Whether two hashes come from the same algorithm, and can be compared.
+fn same_algorithm(a: &vardbapi::FileHash, b: &vardbapi::FileHash) -> bool {
+    match (a, b) {
+        (vardbapi::FileHash::Blake2b(a), vardbapi::FileHash::Blake2b(b)) => a.len() == b.len(),
+        (vardbapi::FileHash::Tagged { algorithm: a, .. }, vardbapi::FileHash::Tagged { algorithm: b, .. }) => a == b,
+        _ => std::mem::discriminant(a) == std::mem::discriminant(b),
+    }
+}
+
+/// Parse a `<mtime> <mtime nsec> <size> <hash> <path>` line.
+fn parse_line(cache: &path::Path, line: usize, raw: &[u8]) -> Option<(path::PathBuf, Entry)> {
+    let mut fields = raw.splitn(5, |byte| *byte == b' ');
+    let mut field = || std::str::from_utf8(fields.next()?).ok();
+    let mtime = field()?.parse().ok()?;
+    let mtime_nsec = field()?.parse().ok()?;
+    let size = field()?.parse().ok()?;
+    let hash = vardbapi::parse_hash(cache, line, field()?).ok()?;
+    let file_path = path::PathBuf::from(sys::os_str_from_bytes(fields.next()?)?);
+    if file_path.as_os_str().is_empty() {
+        return None;
+    }
+    Some((file_path, Entry { mtime, mtime_nsec, size, hash }))
+}
@@ -0,0 +1,71 @@
+//! Filesystem types, as reported by `statfs(2)` in `f_type`.
+
+use std::collections;
+#[cfg(unix)]
+use std::ffi;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path;
+
+/// Magic numbers of the filesystem types known by name.
+const KNOWN_TYPES: &[(&str, i64)] = &[
+    ("nfs", 0x6969),
+    ("cifs", 0xff53_4d42),
+    ("smb", 0x517b),
+    ("smb2", 0xfe53_4d42),
+    ("afs", 0x5346_414f),
+    ("ceph", 0x00c3_6400),
+    ("9p", 0x0102_1997),
+    ("fuse", 0x6573_5546),
+    ("ext4", 0xef53),
+    ("btrfs", 0x9123_683e),
+    ("xfs", 0x5846_5342),
+    ("tmpfs", 0x0102_1994),
+    ("overlay", 0x794c_7630),
+];
+
+/// The `f_type` magic number of a filesystem type name, e.g. `nfs`.
+pub fn magic(name: &str) -> Option<i64> {
+    KNOWN_TYPES.iter().find(|(known, _)| *known == name).map(|(_, magic)| *magic)
+}
+
+/// The filesystem type holding `path`.
+#[cfg(unix)]
+pub(crate) fn of(path: &path::Path) -> io::Result<i64> {
+    let raw = ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: `raw` is a valid C string, and `stats` is only read once filled by a successful call.
+    let stats = unsafe {
+        if libc::statfs(raw.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stats.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_type as i64)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn of(_path: &path::Path) -> io::Result<i64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "filesystem types are only known on Unix"))
+}
+
+/// Filesystem types of the devices seen so far, to call `statfs` once per device.
+#[derive(Default)]
+pub(crate) struct Cache {
+    by_device: collections::HashMap<u64, i64>,
+}
+
+impl Cache {
+    /// The filesystem type of `path`, stored on device `device`.
+    pub(crate) fn get(&mut self, path: &path::Path, device: u64) -> io::Result<i64> {
+        if let Some(fs_type) = self.by_device.get(&device) {
+            return Ok(*fs_type);
+        }
+        let fs_type = of(path)?;
+        self.by_device.insert(device, fs_type);
+        Ok(fs_type)
+    }
+}
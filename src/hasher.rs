@@ -0,0 +1,48 @@
+//! Hash algorithms provided by the caller, for tagged hashes estatus can't compute itself.
+
+use std::collections;
+use std::fmt;
+use std::io;
+use std::path;
+use std::sync;
+
+/// Computes the digest of a file, for hashes recorded as `<algorithm>:<hex digest>`.
+///
+/// See `vardbapi::FileHash::Tagged`; register implementations in `CheckOptions::hashers`.
+pub trait Hasher: Send + Sync {
+    /// The tag of the hashes this computes, e.g. `sha512`.
+    fn algorithm(&self) -> &str;
+
+    /// The raw digest of the file at `path`.
+    fn hash(&self, path: &path::Path) -> io::Result<Vec<u8>>;
+}
+
+/// Hashers, by algorithm.
+#[derive(Clone, Default)]
+pub struct Hashers {
+    by_algorithm: collections::HashMap<String, sync::Arc<dyn Hasher>>,
+}
+
+impl Hashers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a hasher, replacing any other one for the same algorithm.
+    pub fn register(&mut self, hasher: impl Hasher + 'static) {
+        self.by_algorithm.insert(hasher.algorithm().into(), sync::Arc::new(hasher));
+    }
+
+    /// The hasher for `algorithm`, if registered.
+    pub fn get(&self, algorithm: &str) -> Option<&dyn Hasher> {
+        self.by_algorithm.get(algorithm).map(|hasher| hasher.as_ref())
+    }
+}
+
+impl fmt::Debug for Hashers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut algorithms: Vec<&String> = self.by_algorithm.keys().collect();
+        algorithms.sort();
+        f.debug_set().entries(algorithms).finish()
+    }
+}
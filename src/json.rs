@@ -0,0 +1,41 @@
+//! JSON output, for machine consumption.
+//!
+//! Paths and extended attribute names are serialized as strings; as JSON strings
+//! must be valid UTF-8, invalid sequences are replaced with U+FFFD.
+
+use std::error;
+use std::ffi;
+use std::path;
+use std::time;
+
+use crate::ResultSet;
+
+pub(crate) fn lossy_path<S: serde::Serializer>(path: &path::Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+pub(crate) fn lossy_names<S: serde::Serializer>(names: &[ffi::OsString], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(names.iter().map(|name| name.to_string_lossy()))
+}
+
+pub(crate) fn lossy_paths<S: serde::Serializer>(paths: &[path::PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(paths.iter().map(|path| path.to_string_lossy()))
+}
+
+pub(crate) fn sorted_results<S: serde::Serializer>(results: &ResultSet, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(crate::order_by_path(results))
+}
+
+pub(crate) fn messages<S: serde::Serializer, E: error::Error>(errors: &[E], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(errors.iter().map(|error| error.to_string()))
+}
+
+/// Seconds since the epoch; earlier times are clamped to it.
+pub(crate) fn timestamp<S: serde::Serializer>(time: &time::SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(time.duration_since(time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()))
+}
+
+/// Serialize results as a JSON array, sorted by path.
+pub fn to_json(results: &ResultSet) -> String {
+    serde_json::to_string(&crate::order_by_path(results)).expect("Results always serialize")
+}
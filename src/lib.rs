@@ -1,17 +1,46 @@
+use std::cmp;
 use std::collections;
 use std::fs;
-use std::os::unix::fs::FileTypeExt;
 use std::io;
 use std::path;
+use std::sync;
 use std::time;
 use std::fmt;
+use std::convert::TryFrom;
 use std::error;
+use std::ffi;
 
 use md5::{Md5, Digest};
 
-mod vardbapi;
+use sys::{FileTypeExt, MetadataExt, OsStrExt};
+
+pub mod vardbapi;
+pub mod fstype;
+pub mod hasher;
+mod cache;
+mod sys;
+#[cfg(feature = "inotify")]
+mod watch;
+#[cfg(feature = "parallel")]
+mod parallel;
+#[cfg(feature = "serde")]
+mod json;
+
+pub use cache::HashCache;
+pub use hasher::Hashers;
+#[cfg(feature = "inotify")]
+pub use watch::watch;
+#[cfg(feature = "parallel")]
+pub use parallel::statuses_parallel;
+#[cfg(feature = "serde")]
+pub use json::to_json;
+
+// The checks rely on Unix file semantics, and so do their tests.
+#[cfg(all(test, unix))]
+mod tests;
 
 #[derive(Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
 pub enum FileType {
     FIFO,
     CHR,  // Character device
@@ -22,29 +51,59 @@ pub enum FileType {
     SOCK,
 }
 
-impl From<fs::FileType> for FileType {
-    fn from(item: fs::FileType) -> Self {
-        if cfg!(unix) && item.is_block_device() {
-            Self::BLK
-        } else if cfg!(unix) && item.is_char_device() {
-            Self::CHR
-        } else if cfg!(unix) && item.is_fifo() {
-            Self::FIFO
-        } else if cfg!(unix) && item.is_socket() {
-            Self::SOCK
-        } else if item.is_dir() {
-            Self::DIR
-        } else if item.is_symlink() {
-            Self::LNK
-        } else if item.is_file() {
-            Self::REG
-        } else {
-            panic!("Invalid file type {:?}", item);
-        }
+/// The type bits of an inode, as exposed by `fs::FileType`.
+trait FileKind {
+    fn is_block_device(&self) -> bool;
+    fn is_char_device(&self) -> bool;
+    fn is_fifo(&self) -> bool;
+    fn is_socket(&self) -> bool;
+    fn is_dir(&self) -> bool;
+    fn is_symlink(&self) -> bool;
+    fn is_file(&self) -> bool;
+}
+
+impl FileKind for fs::FileType {
+    fn is_block_device(&self) -> bool { FileTypeExt::is_block_device(self) }
+    fn is_char_device(&self) -> bool { FileTypeExt::is_char_device(self) }
+    fn is_fifo(&self) -> bool { FileTypeExt::is_fifo(self) }
+    fn is_socket(&self) -> bool { FileTypeExt::is_socket(self) }
+    fn is_dir(&self) -> bool { fs::FileType::is_dir(self) }
+    fn is_symlink(&self) -> bool { fs::FileType::is_symlink(self) }
+    fn is_file(&self) -> bool { fs::FileType::is_file(self) }
+}
+
+/// The `FileType` of an inode, if a known one.
+fn file_type_of(item: &impl FileKind) -> Option<FileType> {
+    if cfg!(unix) && item.is_block_device() {
+        Some(FileType::BLK)
+    } else if cfg!(unix) && item.is_char_device() {
+        Some(FileType::CHR)
+    } else if cfg!(unix) && item.is_fifo() {
+        Some(FileType::FIFO)
+    } else if cfg!(unix) && item.is_socket() {
+        Some(FileType::SOCK)
+    } else if item.is_dir() {
+        Some(FileType::DIR)
+    } else if item.is_symlink() {
+        Some(FileType::LNK)
+    } else if item.is_file() {
+        Some(FileType::REG)
+    } else {
+        None
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+impl TryFrom<fs::FileType> for FileType {
+    /// The unknown type, e.g. a Solaris door.
+    type Error = fs::FileType;
+
+    fn try_from(item: fs::FileType) -> Result<Self, Self::Error> {
+        file_type_of(&item).ok_or(item)
+    }
+}
+
+#[derive(Clone, Copy, Eq, Hash, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
 pub enum FileStatus {
     Aligned,  // Complies with the expected hash
     Touched,  // Right type and content, wrong mtime
@@ -52,128 +111,1843 @@ pub enum FileStatus {
     Changed,  // Wrong type
     Absent,   // Expected file is not present on disk
     Orphan,   // File on disk, not owned by any package
+    Skipped,  // Content not verified, e.g. recorded with a placeholder hash
+    Broken,   // Right symlink, but its chain of links leads nowhere
+    Unreadable,  // Content couldn't be read, see `CheckOptions::continue_on_read_error`
+}
+
+/// How an on-disk mtime compares to the recorded one.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum MtimeDrift {
+    /// Usually a legitimate modification.
+    Newer,
+    /// Often a restore from a backup.
+    Older,
+}
+
+/// Additional findings attached to a result, orthogonal to its status.
+#[derive(Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "kind", rename_all = "snake_case"))]
+pub enum Detail {
+    /// Extended attributes differ from the recorded ones.
+    ///
+    /// Lists the names of attributes which were added, removed or modified.
+    XattrDrift {
+        #[cfg_attr(feature = "serde", serde(serialize_with = "json::lossy_names"))]
+        names: Vec<ffi::OsString>,
+    },
+    /// An orphan regular file is writable by anyone.
+    WorldWritable,
+    /// An orphan file was moved into the quarantine folder, at `to`.
+    Quarantined {
+        #[cfg_attr(feature = "serde", serde(serialize_with = "json::lossy_path"))]
+        to: path::PathBuf,
+    },
+    /// An orphan file was deleted.
+    Deleted,
+    /// An owned file's mtime is after `CheckOptions::future_mtime_after`.
+    #[cfg_attr(feature = "serde", serde(rename = "future_mtime"))]
+    FutureMTime,
+}
+
+/// What to do with an orphan file, as decided by an orphan handler.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum OrphanAction {
+    /// Leave the file in place.
+    Keep,
+    /// Move the file below `CheckOptions::quarantine_dir`, keeping its full path.
+    ///
+    /// Requires `CheckOptions::allow_orphan_removal`.
+    Quarantine,
+    /// Delete the file.
+    ///
+    /// Requires `CheckOptions::allow_orphan_removal`.
+    Delete,
 }
 
 #[derive(Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ResultItem {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::lossy_path"))]
     pub path: path::PathBuf,
     pub ftype: FileType,
     pub status: FileStatus,
+    pub details: Vec<Detail>,
+    /// The on-disk mtime, as a POSIX timestamp; `None` if the file is missing.
+    pub mtime: Option<u64>,
+    /// The package owning the file, as `<category>/<name>-<version>`, if known.
+    pub owner: Option<String>,
+    /// The recorded content hash, for regular files.
+    pub recorded_hash: Option<vardbapi::FileHash>,
+    /// The on-disk owner user id, with `CheckOptions::capture_ownership`.
+    pub uid: Option<u32>,
+    /// The on-disk owner group id, with `CheckOptions::capture_ownership`.
+    pub gid: Option<u32>,
+    /// The on-disk `st_mode`, file type bits included, with `CheckOptions::capture_mode`.
+    pub mode: Option<u32>,
+    /// Whether an `Edited` or `Touched` file lives below `CheckOptions::config_protect`.
+    ///
+    /// As for portage, such changes are expected: reports may downgrade them.
+    pub protected: bool,
+    /// The recorded mtime, for files present on disk and recorded with one.
+    pub expected_mtime: Option<u64>,
+    /// Whether the content differs from the recorded hash; `None` unless the file was hashed.
+    ///
+    /// Regular files are only hashed when their mtime isn't enough to conclude.
+    pub hash_mismatch: Option<bool>,
+    /// The recorded file type, for owned files; differs from `ftype` when `Changed`.
+    ///
+    /// CONTENTS doesn't tell block and character devices apart: devices are expected as `CHR`.
+    pub expected_ftype: Option<FileType>,
+    /// The on-disk size in bytes, for files other than folders; `None` if the file is missing.
+    pub size: Option<u64>,
+    /// For `Touched` and `Edited` files, whether the on-disk mtime is after or before the recorded one.
+    pub mtime_drift: Option<MtimeDrift>,
+}
+
+/// Options tuning how files are checked.
+///
+/// The default value performs the historical checks only. In all cases, a regular file
+/// whose mtime matches the recorded one is trusted: its content is never hashed.
+#[derive(Clone, Debug, Default)]
+pub struct CheckOptions {
+    /// Compare the extended attributes of owned files against the recorded ones.
+    ///
+    /// This is only performed for entries whose backend recorded xattrs.
+    pub check_xattrs: bool,
+    /// Flag orphan regular files which are world-writable.
+    pub flag_world_writable_orphans: bool,
+    /// Rewrite rules between recorded paths and on-disk paths, as `(recorded prefix, disk prefix)`.
+    ///
+    /// The first rule matching a path applies; results are reported with their recorded path.
+    pub path_map: Vec<(path::PathBuf, path::PathBuf)>,
+    /// Where the checked system is mounted, e.g. `/mnt/gentoo` for a chroot; empty for `/`.
+    ///
+    /// Recorded paths are looked up below it, after applying `path_map`; as with `path_map`,
+    /// searched paths are given on disk, and results are reported with their recorded path.
+    pub offset_root: path::PathBuf,
+    /// The Gentoo Prefix of the checked system, which CONTENTS paths are relative to.
+    ///
+    /// Unlike `offset_root`, the prefix is part of the layout of installed files: results are
+    /// reported with their prefixed path, and `path_map` and `offset_root` apply to it.
+    pub eprefix: Option<path::PathBuf>,
+    /// Only classify regular files, ignoring entries recorded as anything but `obj`.
+    ///
+    /// This skips all structural checks (directories, symlinks, devices, ...).
+    pub regular_files_only: bool,
+    /// Allow an orphan handler to quarantine or delete files.
+    ///
+    /// Without this, any such request fails with `Error::OrphanRemovalDenied`.
+    pub allow_orphan_removal: bool,
+    /// Where quarantined orphan files are moved; must be on the same filesystem.
+    ///
+    /// Without this, quarantining fails with `Error::QuarantineDirUnset`.
+    pub quarantine_dir: Option<path::PathBuf>,
+    /// Config-protected folders, as in portage's `CONFIG_PROTECT`, matched against reported paths.
+    ///
+    /// Files edited or touched below them are flagged as `ResultItem::protected`.
+    pub config_protect: Vec<path::PathBuf>,
+    /// Paths left out of the results, matched against the reported absolute path.
+    ///
+    /// A matching folder isn't walked: nothing below it is reported either.
+    pub ignore: Vec<glob::Pattern>,
+    /// How deep to walk below each checked path; unlimited when `None`.
+    ///
+    /// Depth 0 only checks the entries of the checked paths themselves. Deeper entries,
+    /// recorded or not, are left out of the results.
+    pub max_depth: Option<usize>,
+    /// Filesystem types not to descend into, as `statfs` magic numbers (see `fstype::magic`).
+    ///
+    /// Bind mounts of other filesystem types are still walked.
+    pub skipped_fs_types: Vec<i64>,
+    /// Flag owned files modified after this POSIX timestamp, usually the current time.
+    ///
+    /// Such files hint at clock tampering or bad restores.
+    pub future_mtime_after: Option<u64>,
+    /// Report files recorded with a placeholder hash as `Skipped` instead of `Edited`.
+    ///
+    /// See `vardbapi::FileHash::is_placeholder` for the recognized placeholders.
+    pub skip_placeholder_hashes: bool,
+    /// Only verify owned regular files with an execute bit, reporting the others as `Skipped`.
+    pub executables_only: bool,
+    /// How many seconds an mtime may drift from the recorded one and still match.
+    ///
+    /// Helps with restores or copies keeping mtimes imprecisely.
+    pub mtime_tolerance_secs: u64,
+    /// Only keep results with one of these statuses; all of them when `None`.
+    ///
+    /// Other results are dropped as soon as they are known, and never collected.
+    pub wanted_statuses: Option<collections::HashSet<FileStatus>>,
+    /// Follow owned symlinks, reporting them as `Broken` unless they lead to an existing file.
+    ///
+    /// At most `MAX_SYMLINK_DEPTH` links are followed; absolute targets go through `path_map`.
+    pub resolve_symlinks: bool,
+    /// Record the on-disk uid and gid of each file in its result.
+    ///
+    /// CONTENTS doesn't record ownership: this is for audits, not compared to anything.
+    pub capture_ownership: bool,
+    /// Record the on-disk mode of each file in its result, e.g. to flag setuid files.
+    ///
+    /// As for ownership, CONTENTS doesn't record modes to compare with.
+    pub capture_mode: bool,
+    /// Hashes kept from previous runs, used instead of hashing unchanged files.
+    ///
+    /// New hashes are added to it as they are computed.
+    pub hash_cache: Option<sync::Arc<HashCache>>,
+    /// Size of the buffer files are read through when hashed; `DEFAULT_HASH_BUFFER_SIZE` when `None`.
+    ///
+    /// Larger buffers may help with large files on spinning disks.
+    pub hash_buffer_size: Option<usize>,
+    /// Hashers for tagged hashes, e.g. `sha512:...`, by algorithm.
+    ///
+    /// Files recorded with a tagged hash of another algorithm are `UnsupportedHash`.
+    pub hashers: Hashers,
+    /// Report owned files which can't be read for hashing as `Unreadable`, instead of failing.
+    ///
+    /// Unlike `lenient`, the file still gets a result, and any caller sees it.
+    pub continue_on_read_error: bool,
+    /// Record unreadable files and folders, files of unknown types, and hashes which can't be computed,
+    /// as non-fatal errors, and carry on.
+    ///
+    /// Such errors are only reported by `statuses_report`.
+    pub lenient: bool,
+}
+
+impl CheckOptions {
+    /// Whether results with this status should be kept.
+    fn wants(&self, status: &FileStatus) -> bool {
+        self.wanted_statuses.as_ref().is_none_or(|wanted| wanted.contains(status))
+    }
+
+    /// Whether `path`, or a folder holding it, matches one of the `ignore` patterns.
+    fn ignores(&self, path: &path::Path) -> bool {
+        !self.ignore.is_empty()
+            && path.ancestors().any(|ancestor| self.ignore.iter().any(|pattern| pattern.matches_path(ancestor)))
+    }
+
+    /// Whether an entry `depth` levels below a checked path is within `max_depth`.
+    fn within_depth(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max_depth| depth <= max_depth)
+    }
+
+    fn hash_buffer_size(&self) -> usize {
+        self.hash_buffer_size.unwrap_or(DEFAULT_HASH_BUFFER_SIZE)
+    }
+
+    /// Whether an on-disk mtime matches the recorded one, within the tolerance.
+    fn mtime_matches(&self, recorded: u64, actual: u64) -> bool {
+        recorded.abs_diff(actual) <= self.mtime_tolerance_secs
+    }
+
+    /// The absolute path of a CONTENTS path, below `eprefix`.
+    fn prefixed(&self, contents_path: &path::Path) -> path::PathBuf {
+        match self.eprefix {
+            Some(ref prefix) => prefix.join(contents_path.strip_prefix("/").unwrap_or(contents_path)),
+            None => contents_path.into(),
+        }
+    }
+
+    /// The CONTENTS path matching a recorded folder, or `None` if outside of `eprefix`.
+    ///
+    /// A folder above the prefix holds all of it, as `/`.
+    fn unprefixed(&self, recorded: &path::Path) -> Option<path::PathBuf> {
+        let prefix = match self.eprefix {
+            Some(ref prefix) => prefix,
+            None => return Some(recorded.into()),
+        };
+        match recorded.strip_prefix(prefix) {
+            Ok(rest) => Some(path::Path::new("/").join(rest)),
+            Err(_) if prefix.starts_with(recorded) => Some(path::PathBuf::from("/")),
+            Err(_) => None,
+        }
+    }
+
+    /// Where a recorded path is found on disk.
+    fn disk_path(&self, recorded: &path::Path) -> path::PathBuf {
+        let mapped = remap(recorded, self.path_map.iter().map(|(recorded, disk)| (recorded, disk)));
+        if self.offset_root.as_os_str().is_empty() {
+            return mapped;
+        }
+        self.offset_root.join(mapped.strip_prefix("/").unwrap_or(&mapped))
+    }
+
+    /// The recorded path matching a path on disk.
+    fn recorded_path(&self, disk: &path::Path) -> path::PathBuf {
+        let unrooted = match disk.strip_prefix(&self.offset_root) {
+            Ok(rest) if !self.offset_root.as_os_str().is_empty() => path::Path::new("/").join(rest),
+            _ => disk.into(),
+        };
+        remap(&unrooted, self.path_map.iter().map(|(recorded, disk)| (disk, recorded)))
+    }
+}
+
+/// Rewrite `path` with the first matching `(from, to)` prefix rule.
+fn remap<'a>(path: &path::Path, rules: impl Iterator<Item=(&'a path::PathBuf, &'a path::PathBuf)>) -> path::PathBuf {
+    for (from, to) in rules {
+        if let Ok(rest) = path.strip_prefix(from) {
+            if rest.as_os_str().is_empty() {
+                return to.clone();
+            }
+            return to.join(rest);
+        }
+    }
+    path.into()
 }
 
 
 type MD5Hash = [u8; 16];
+type SHA256Hash = [u8; 32];
 type SearchPaths = Vec<path::PathBuf>;
 pub type ResultSet = collections::HashMap<path::PathBuf, ResultItem>;
 
 
-fn compute_md5(path: &path::Path) -> io::Result<MD5Hash> {
-    let mut hasher = Md5::new();
+/// Size of the buffer files are read through when hashed, unless set in `CheckOptions`.
+pub const DEFAULT_HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Feed the content of a file to `hasher`, `buffer_size` bytes at a time.
+fn read_into(path: &path::Path, hasher: &mut impl io::Write, buffer_size: usize) -> io::Result<()> {
     let mut file = fs::File::open(path)?;
-    io::copy(&mut file, &mut hasher)?;
+    let mut buffer = vec![0; buffer_size.max(1)];
+    loop {
+        match io::Read::read(&mut file, &mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => hasher.write_all(&buffer[..read])?,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn compute_md5(path: &path::Path, buffer_size: usize) -> io::Result<MD5Hash> {
+    let mut hasher = Md5::new();
+    read_into(path, &mut hasher, buffer_size)?;
+    let digest = hasher.result().into();
+    Ok(digest)
+}
+
+fn compute_sha256(path: &path::Path, buffer_size: usize) -> io::Result<SHA256Hash> {
+    let mut hasher = sha2::Sha256::new();
+    read_into(path, &mut hasher, buffer_size)?;
     let digest = hasher.result().into();
     Ok(digest)
 }
 
-fn changed_hash(path: &path::Path, hash: &vardbapi::FileHash) -> Result<bool, Error> {
-    match hash {
-        vardbapi::FileHash::MD5(expected_md5) => {
-            let actual = compute_md5(&path).map_err(|e| Error::FileUnreadable { path: path.into(), source: e })?;
-            Ok(expected_md5 != &actual)
+/// The BLAKE2B digest of a file, `size` bytes long.
+#[cfg(feature = "blake2")]
+fn compute_blake2b(path: &path::Path, size: usize, buffer_size: usize) -> io::Result<Vec<u8>> {
+    use blake2::digest::VariableOutput;
+
+    let mut hasher = blake2::VarBlake2b::new(size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid BLAKE2B digest size"))?;
+    read_into(path, &mut hasher, buffer_size)?;
+    let mut digest = Vec::new();
+    hasher.variable_result(|result| digest.extend_from_slice(result));
+    Ok(digest)
+}
+
+/// The hash of a file, with the same algorithm (and size) as `like`.
+fn compute_hash(path: &path::Path, like: &vardbapi::FileHash, options: &CheckOptions) -> Result<vardbapi::FileHash, Error> {
+    let unreadable = |e| Error::FileUnreadable { path: path.into(), source: e };
+    let buffer_size = options.hash_buffer_size();
+    match like {
+        vardbapi::FileHash::MD5(_) => compute_md5(path, buffer_size).map(vardbapi::FileHash::MD5).map_err(unreadable),
+        vardbapi::FileHash::SHA256(_) => compute_sha256(path, buffer_size).map(vardbapi::FileHash::SHA256).map_err(unreadable),
+        #[cfg(feature = "blake2")]
+        vardbapi::FileHash::Blake2b(digest) => compute_blake2b(path, digest.len(), buffer_size).map(vardbapi::FileHash::Blake2b).map_err(unreadable),
+        #[cfg(not(feature = "blake2"))]
+        vardbapi::FileHash::Blake2b(_) => Err(Error::UnsupportedHash { path: path.into() }),
+        vardbapi::FileHash::Tagged { algorithm, .. } => match options.hashers.get(algorithm) {
+            Some(hasher) => hasher.hash(path)
+                .map(|digest| vardbapi::FileHash::Tagged { algorithm: algorithm.clone(), digest })
+                .map_err(unreadable),
+            None => Err(Error::UnsupportedHash { path: path.into() }),
+        },
+    }
+}
+
+fn changed_hash(path: &path::Path, hash: &vardbapi::FileHash, options: &CheckOptions) -> Result<bool, Error> {
+    Ok(&compute_hash(path, hash, options)? != hash)
+}
+
+/// The hash of empty content, with the same algorithm (and size) as `like`; `None` if unsupported.
+fn empty_hash(like: &vardbapi::FileHash) -> Option<vardbapi::FileHash> {
+    match like {
+        vardbapi::FileHash::MD5(_) => Some(vardbapi::FileHash::MD5(Md5::new().result().into())),
+        vardbapi::FileHash::SHA256(_) => Some(vardbapi::FileHash::SHA256(sha2::Sha256::new().result().into())),
+        #[cfg(feature = "blake2")]
+        vardbapi::FileHash::Blake2b(digest) => {
+            use blake2::digest::VariableOutput;
+
+            let mut empty = Vec::new();
+            blake2::VarBlake2b::new(digest.len()).ok()?.variable_result(|result| empty.extend_from_slice(result));
+            Some(vardbapi::FileHash::Blake2b(empty))
+        },
+        #[cfg(not(feature = "blake2"))]
+        vardbapi::FileHash::Blake2b(_) => None,
+        // Registered hashers only hash files: the empty digest isn't known.
+        vardbapi::FileHash::Tagged { .. } => None,
+    }
+}
+
+/// Whether a file of `size` bytes doesn't match `hash`, if its size alone tells.
+///
+/// An empty file only matches the hash of empty content, which no other file matches:
+/// either way, there's no need to read the file.
+fn changed_size(size: u64, hash: &vardbapi::FileHash) -> Option<bool> {
+    let empty = empty_hash(hash)?;
+    if size == 0 {
+        Some(hash != &empty)
+    } else if hash == &empty {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+
+
+/// The extended attributes of a file.
+#[cfg(unix)]
+fn read_xattrs(path: &path::Path) -> Result<vardbapi::Xattrs, Error> {
+    let unreadable = |e| Error::FileUnreadable { path: path.into(), source: e };
+    let mut xattrs = vardbapi::Xattrs::new();
+    for name in xattr::list(path).map_err(unreadable)? {
+        if let Some(value) = xattr::get(path, &name).map_err(unreadable)? {
+            xattrs.insert(name, value);
         }
     }
+    Ok(xattrs)
 }
 
+#[cfg(not(unix))]
+fn read_xattrs(_path: &path::Path) -> Result<vardbapi::Xattrs, Error> {
+    Ok(vardbapi::Xattrs::new())
+}
 
+/// List the names of extended attributes differing from the `recorded` set.
+fn xattr_drift(path: &path::Path, recorded: &vardbapi::Xattrs) -> Result<Vec<ffi::OsString>, Error> {
+    let actual = read_xattrs(path)?;
 
-fn check_file(entry: &fs::DirEntry, expected: Option<&vardbapi::FileMetadata>) -> Result<ResultItem, Error> {
-    let metadata = entry.metadata().map_err(|e| Error::FileUnreadable { path: entry.path(), source: e })?;
-    let ftype = FileType::from(metadata.file_type());
+    let mut drifted: Vec<ffi::OsString> = recorded.iter()
+        .filter(|(name, value)| actual.get(*name) != Some(value))
+        .map(|(name, _)| name.clone())
+        .collect();
+    drifted.extend(actual.keys().filter(|name| !recorded.contains_key(*name)).cloned());
+    drifted.sort();
+    Ok(drifted)
+}
+
+/// Check a file found on disk at `disk_path`, reported as `path`.
+fn check_file(path: path::PathBuf, disk_path: &path::Path, metadata: &fs::Metadata, expected: Option<&vardbapi::FileMetadata>, options: &CheckOptions) -> Result<ResultItem, Error> {
+    classify(path, disk_path, metadata, expected, options, &mut |hash| match (changed_size(metadata.len(), hash), &options.hash_cache) {
+        (Some(changed), _) => Ok(changed),
+        (None, Some(cache)) => cache.changed(disk_path, metadata, hash, options),
+        (None, None) => changed_hash(disk_path, hash, options),
+    })
+}
+
+/// Classify a file whose content hash is already known, e.g. from a content-addressed store.
+///
+/// The file is never read to compute its hash; symlink targets are still read from disk.
+pub fn classify_with_hash(path: &path::Path, expected: &vardbapi::FileMetadata, on_disk_meta: &fs::Metadata, actual_hash: &vardbapi::FileHash) -> Result<ResultItem, Error> {
+    classify(path.into(), path, on_disk_meta, Some(expected), &CheckOptions::default(), &mut |hash| Ok(hash != actual_hash))
+}
+
+/// Maximum number of symlinks followed when resolving a chain, as for path lookups in Linux.
+pub const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// Whether the symlink at `disk_path` eventually leads to an existing file other than a symlink.
+fn resolves(disk_path: &path::Path, options: &CheckOptions) -> Result<bool, Error> {
+    let mut current = disk_path.to_path_buf();
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        let dest = current.read_link().map_err(|e| Error::FileUnreadable { path: current.clone(), source: e })?;
+        current = if dest.is_absolute() {
+            options.disk_path(&dest)
+        } else {
+            current.parent().unwrap_or_else(|| path::Path::new("/")).join(dest)
+        };
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {},
+            Ok(_) => return Ok(true),
+            Err(ref e) if matches!(e.raw_os_error(), Some(libc::ENOENT) | Some(libc::ENOTDIR) | Some(libc::ELOOP)) => return Ok(false),
+            Err(e) => return Err(Error::FileUnreadable { path: current, source: e }),
+        }
+    }
+    Ok(false)
+}
+
+/// Classify a file at `disk_path`, reported as `path`.
+///
+/// `hash_changed` tells whether the file's content differs from an expected hash;
+/// it is only called when the mtime isn't enough to conclude.
+fn classify(
+    path: path::PathBuf,
+    disk_path: &path::Path,
+    metadata: &fs::Metadata,
+    expected: Option<&vardbapi::FileMetadata>,
+    options: &CheckOptions,
+    hash_changed: &mut dyn FnMut(&vardbapi::FileHash) -> Result<bool, Error>,
+) -> Result<ResultItem, Error> {
+    let ftype = FileType::try_from(metadata.file_type())
+        .map_err(|_| Error::UnknownFileType { path: disk_path.into() })?;
     let entry_mtime = metadata
-        .modified().map_err(|e| Error::FileUnreadable { path: entry.path(), source: e })?
+        .modified().map_err(|e| Error::FileUnreadable { path: disk_path.into(), source: e })?
         .duration_since(time::UNIX_EPOCH).expect("Bad mtime").as_secs();
 
-    match expected {
-        Some(vardbapi::FileMetadata::Regular { mtime, hash }) => {
+    let mut hash_mismatch = None;
+    let status = match expected {
+        Some(vardbapi::FileMetadata::Regular { mtime, hash, .. }) => {
             if ftype != FileType::REG {
-                Ok(ResultItem { status: FileStatus::Changed, ftype: ftype, path: entry.path() })
-            } else if &entry_mtime != mtime {
-                if changed_hash(&entry.path(), &hash)? {
-                    Ok(ResultItem { status: FileStatus::Edited, ftype: ftype, path: entry.path() })
-                } else {
-                    Ok(ResultItem { status: FileStatus::Touched, ftype: ftype, path: entry.path() })
-                }
+                FileStatus::Changed
+            } else if options.executables_only && metadata.mode() & 0o111 == 0 {
+                FileStatus::Skipped
+            } else if mtime.is_some_and(|mtime| options.mtime_matches(mtime, entry_mtime)) {
+                FileStatus::Aligned
+            } else if options.skip_placeholder_hashes && hash.is_placeholder() {
+                FileStatus::Skipped
             } else {
-                Ok(ResultItem { status: FileStatus::Aligned, ftype: ftype, path: entry.path() })
+                let changed = hash_changed(hash);
+                hash_mismatch = changed.as_ref().ok().copied();
+                match changed {
+                    Ok(true) => FileStatus::Edited,
+                    // Nothing to compare the mtime with: only the content tells.
+                    Ok(false) if mtime.is_none() => FileStatus::Aligned,
+                    Ok(false) => FileStatus::Touched,
+                    Err(Error::FileUnreadable { .. }) if options.continue_on_read_error => FileStatus::Unreadable,
+                    Err(e) => return Err(e),
+                }
             }
         },
         Some(vardbapi::FileMetadata::Symlink { mtime, dest }) => {
             if ftype != FileType::LNK {
-                return Ok(ResultItem { status: FileStatus::Changed, ftype: ftype, path: entry.path() })
-            }
-            let entry_dest = entry.path().read_link()
-                .map_err(|e| Error::FileUnreadable { path: entry.path(), source: e })?;
-            if &entry_mtime != mtime || &entry_dest != dest {
-                Ok(ResultItem { status: FileStatus::Edited, ftype: ftype, path: entry.path() })
+                FileStatus::Changed
             } else {
-                Ok(ResultItem { status: FileStatus::Aligned, ftype: ftype, path: entry.path() })
+                let entry_dest = disk_path.read_link()
+                    .map_err(|e| Error::FileUnreadable { path: disk_path.into(), source: e })?;
+                if &entry_dest != dest {
+                    FileStatus::Edited
+                } else if options.resolve_symlinks && !resolves(disk_path, options)? {
+                    FileStatus::Broken
+                } else if !options.mtime_matches(*mtime, entry_mtime) {
+                    FileStatus::Touched
+                } else {
+                    FileStatus::Aligned
+                }
             }
         },
         Some(vardbapi::FileMetadata::Directory) => {
             if ftype == FileType::DIR {
-                Ok(ResultItem { status: FileStatus::Aligned, ftype: ftype, path: entry.path() })
+                FileStatus::Aligned
             } else {
-                Ok(ResultItem { status: FileStatus::Changed, ftype: ftype, path: entry.path() })
+                FileStatus::Changed
             }
         },
         Some(vardbapi::FileMetadata::Device) => {
             if ftype == FileType::BLK || ftype == FileType::CHR {
-                Ok(ResultItem { status: FileStatus::Aligned, ftype: ftype, path: entry.path() })
+                FileStatus::Aligned
             } else {
-                Ok(ResultItem { status: FileStatus::Changed, ftype: ftype, path: entry.path() })
+                FileStatus::Changed
             }
         },
         Some(vardbapi::FileMetadata::Fifo) => {
             if ftype == FileType::FIFO {
-                Ok(ResultItem { status: FileStatus::Aligned, ftype: ftype, path: entry.path() })
+                FileStatus::Aligned
             } else {
-                Ok(ResultItem { status: FileStatus::Changed, ftype: ftype, path: entry.path() })
+                FileStatus::Changed
+            }
+        },
+        None => FileStatus::Orphan,
+    };
+
+    let mut details = Vec::new();
+    if let Some(vardbapi::FileMetadata::Regular { xattrs: Some(recorded), .. }) = expected {
+        if options.check_xattrs && ftype == FileType::REG {
+            let drifted = xattr_drift(disk_path, recorded)?;
+            if !drifted.is_empty() {
+                details.push(Detail::XattrDrift { names: drifted });
+            }
+        }
+    }
+
+    if options.flag_world_writable_orphans && status == FileStatus::Orphan && ftype == FileType::REG
+            && metadata.mode() & 0o002 != 0 {
+        details.push(Detail::WorldWritable);
+    }
+
+    if expected.is_some() && options.future_mtime_after.is_some_and(|now| entry_mtime > now) {
+        details.push(Detail::FutureMTime);
+    }
+
+    let recorded_hash = recorded_hash(expected);
+    let (uid, gid) = if options.capture_ownership {
+        (Some(metadata.uid()), Some(metadata.gid()))
+    } else {
+        (None, None)
+    };
+    let mode = if options.capture_mode { Some(metadata.mode()) } else { None };
+    let protected = matches!(status, FileStatus::Edited | FileStatus::Touched)
+        && options.config_protect.iter().any(|dir| path.starts_with(dir));
+    let size = if ftype == FileType::DIR { None } else { Some(metadata.len()) };
+    let expected_mtime = recorded_mtime(expected);
+    let mtime_drift = match expected_mtime {
+        Some(recorded) if matches!(status, FileStatus::Touched | FileStatus::Edited) => match entry_mtime.cmp(&recorded) {
+            cmp::Ordering::Greater => Some(MtimeDrift::Newer),
+            cmp::Ordering::Less => Some(MtimeDrift::Older),
+            cmp::Ordering::Equal => None,
+        },
+        _ => None,
+    };
+    Ok(ResultItem {
+        path, ftype, status, details, mtime: Some(entry_mtime), owner: None, recorded_hash, uid, gid, mode, protected,
+        expected_mtime, hash_mismatch, expected_ftype: expected.map(expected_ftype), size, mtime_drift,
+    })
+}
+
+fn recorded_mtime(expected: Option<&vardbapi::FileMetadata>) -> Option<u64> {
+    match expected {
+        Some(vardbapi::FileMetadata::Regular { mtime, .. }) => *mtime,
+        Some(vardbapi::FileMetadata::Symlink { mtime, .. }) => Some(*mtime),
+        _ => None,
+    }
+}
+
+fn recorded_hash(expected: Option<&vardbapi::FileMetadata>) -> Option<vardbapi::FileHash> {
+    match expected {
+        Some(vardbapi::FileMetadata::Regular { hash, .. }) => Some(hash.clone()),
+        _ => None,
+    }
+}
+
+fn is_regular(expected: &vardbapi::FileMetadata) -> bool {
+    matches!(expected, vardbapi::FileMetadata::Regular { .. })
+}
+
+/// The file type matching an expectation.
+fn expected_ftype(expected: &vardbapi::FileMetadata) -> FileType {
+    match expected {
+        vardbapi::FileMetadata::Regular { .. } => FileType::REG,
+        vardbapi::FileMetadata::Symlink { .. } => FileType::LNK,
+        vardbapi::FileMetadata::Directory => FileType::DIR,
+        // CONTENTS doesn't tell block and character devices apart.
+        vardbapi::FileMetadata::Device => FileType::CHR,
+        vardbapi::FileMetadata::Fifo => FileType::FIFO,
+    }
+}
+
+/// The result for a recorded file missing from disk.
+fn absent(path: &path::Path, expected: &vardbapi::FileMetadata) -> ResultItem {
+    ResultItem {
+        path: path.into(),
+        ftype: expected_ftype(expected),
+        status: FileStatus::Absent,
+        details: Vec::new(),
+        mtime: None,
+        owner: None,
+        recorded_hash: recorded_hash(Some(expected)),
+        uid: None,
+        gid: None,
+        mode: None,
+        protected: false,
+        expected_mtime: None,
+        hash_mismatch: None,
+        expected_ftype: Some(expected_ftype(expected)),
+        size: None,
+        mtime_drift: None,
+    }
+}
+
+/// Compare the file at `path` against any expected metadata, reporting it as `Absent` if missing from disk.
+///
+/// This is the check run on each recorded path, for expectations from another manifest format
+/// than the VarDB: the file is looked up below `options.offset_root`, and the result has no `owner`.
+pub fn compare(path: &path::Path, expected: &vardbapi::FileMetadata, options: &CheckOptions) -> Result<ResultItem, Error> {
+    let disk_path = options.disk_path(path);
+    match fs::symlink_metadata(&disk_path) {
+        Ok(metadata) => check_file(path.into(), &disk_path, &metadata, Some(expected), options),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(absent(path, expected)),
+        Err(e) => Err(Error::FileUnreadable { path: disk_path, source: e }),
+    }
+}
+
+/// Check a file against every package recording it, e.g. from `vardbapi::get_vdb_all_owners`.
+///
+/// The file is `Aligned` if it matches any of the `records`; otherwise, the least
+/// serious mismatch is reported. The result's `owner` is the matching record's atom.
+/// Without any record, the file is checked as an orphan.
+pub fn check_path_all_owners(path: &path::Path, records: &[vardbapi::VarDBRecord], options: &CheckOptions) -> Result<ResultItem, Error> {
+    if records.is_empty() {
+        let disk_path = options.disk_path(path);
+        let metadata = fs::symlink_metadata(&disk_path).map_err(|e| Error::FileUnreadable { path: disk_path.clone(), source: e })?;
+        return check_file(path.into(), &disk_path, &metadata, None, options);
+    }
+    let mut best: Option<ResultItem> = None;
+    for record in records {
+        let mut result = compare(path, &record.metadata, options)?;
+        result.owner = Some(record.atom.clone());
+        if result.status == FileStatus::Aligned {
+            return Ok(result);
+        }
+        if best.as_ref().is_none_or(|best| mismatch_rank(&result.status) < mismatch_rank(&best.status)) {
+            best = Some(result);
+        }
+    }
+    Ok(best.expect("At least one record"))
+}
+
+/// How serious a status is, from the least to the most serious.
+fn mismatch_rank(status: &FileStatus) -> usize {
+    match status {
+        FileStatus::Aligned => 0,
+        FileStatus::Touched => 1,
+        FileStatus::Skipped => 2,
+        FileStatus::Unreadable => 3,
+        FileStatus::Edited => 4,
+        FileStatus::Broken => 5,
+        FileStatus::Changed => 6,
+        FileStatus::Absent => 7,
+        FileStatus::Orphan => 8,
+    }
+}
+
+/// Apply the action chosen by an orphan handler to the file at `disk_path`.
+fn handle_orphan(path: &path::Path, disk_path: &path::Path, action: OrphanAction, options: &CheckOptions) -> Result<Option<Detail>, Error> {
+    if action != OrphanAction::Keep && !options.allow_orphan_removal {
+        return Err(Error::OrphanRemovalDenied { path: disk_path.into() });
+    }
+    let failed = |e| Error::OrphanRemovalFailed { path: disk_path.into(), source: e };
+    match action {
+        OrphanAction::Keep => Ok(None),
+        OrphanAction::Quarantine => {
+            let quarantine_dir = options.quarantine_dir.as_ref()
+                .ok_or_else(|| Error::QuarantineDirUnset { path: disk_path.into() })?;
+            let to = quarantine_dir.join(path.strip_prefix("/").unwrap_or(path));
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent).map_err(failed)?;
             }
+            fs::rename(disk_path, &to).map_err(failed)?;
+            Ok(Some(Detail::Quarantined { to }))
+        },
+        OrphanAction::Delete => {
+            fs::remove_file(disk_path).map_err(failed)?;
+            Ok(Some(Detail::Deleted))
         },
-        None => Ok(ResultItem { status: FileStatus::Orphan, ftype: ftype, path: entry.path() }),
     }
 }
 
+/// A source of expectations about files, by path.
+///
+/// This decouples the checks from where expectations come from;
+/// the VarDB is one such source.
+pub trait ManifestProvider {
+    /// The recorded metadata for `path`, or `None` if the path isn't owned.
+    fn expected(&self, path: &path::Path) -> Option<&vardbapi::FileMetadata>;
+
+    /// The package owning `path`, as `<category>/<name>-<version>`, if known.
+    fn owner(&self, _path: &path::Path) -> Option<&str> {
+        None
+    }
+
+    /// All recorded paths, used to find recorded files missing from disk.
+    ///
+    /// Providers which can't list their paths never report `Absent` files from a walk.
+    fn recorded_paths(&self) -> Box<dyn Iterator<Item=&path::Path> + '_> {
+        Box::new(std::iter::empty())
+    }
+}
+
+impl ManifestProvider for vardbapi::VarDB {
+    fn expected(&self, path: &path::Path) -> Option<&vardbapi::FileMetadata> {
+        self.get(path).map(|record| &record.metadata)
+    }
+
+    fn owner(&self, path: &path::Path) -> Option<&str> {
+        self.get(path).map(|record| record.atom.as_str())
+    }
+
+    fn recorded_paths(&self) -> Box<dyn Iterator<Item=&path::Path> + '_> {
+        Box::new(self.keys().map(|path| path.as_path()))
+    }
+}
+
+impl<T: ManifestProvider + ?Sized> ManifestProvider for &T {
+    fn expected(&self, path: &path::Path) -> Option<&vardbapi::FileMetadata> {
+        (**self).expected(path)
+    }
+
+    fn owner(&self, path: &path::Path) -> Option<&str> {
+        (**self).owner(path)
+    }
+
+    fn recorded_paths(&self) -> Box<dyn Iterator<Item=&path::Path> + '_> {
+        (**self).recorded_paths()
+    }
+}
+
+type OrphanHandler<'a> = dyn FnMut(&path::Path) -> OrphanAction + 'a;
+type ProgressHandler<'a> = dyn FnMut(&path::Path) + 'a;
+
+/// Whether a folder lives on a filesystem the walk should skip.
+fn skipped_fs(path: &path::Path, metadata: &fs::Metadata, options: &CheckOptions, fs_types: &mut fstype::Cache) -> Result<bool, Error> {
+    if options.skipped_fs_types.is_empty() {
+        return Ok(false);
+    }
+    let fs_type = fs_types.get(path, metadata.dev()).map_err(|e| Error::DirUnreadable { path: path.into(), source: e })?;
+    Ok(options.skipped_fs_types.contains(&fs_type))
+}
+
+/// A file met during a walk, not checked yet.
+struct Found {
+    /// The path as recorded in the VarDB.
+    path: path::PathBuf,
+    disk_path: path::PathBuf,
+    metadata: fs::Metadata,
+}
 
-fn check_dir(base: &path::Path, store: &vardbapi::VarDB, mut output: &mut ResultSet) -> Result<(), Error> {
-    let entries = fs::read_dir(base).map_err(|e| Error::DirUnreadable { path: base.into(), source: e })?;
-    for entry in entries {
-        let entry = entry.map_err(|e| Error::DirUnreadable { path: base.into(), source: e })?;
+/// Check a file met during a walk.
+fn check_found(store: &impl ManifestProvider, found: &Found, options: &CheckOptions) -> Result<ResultItem, Error> {
+    let mut result = check_file(found.path.clone(), &found.disk_path, &found.metadata, store.expected(&found.path), options)?;
+    result.owner = store.owner(&found.path).map(String::from);
+    Ok(result)
+}
+
+/// A lazy walk through the checked folders, yielding each result as soon as it is known.
+///
+/// Once all folders are walked, the recorded paths missing from disk are reported as `Absent`.
+/// Errors are yielded as they happen, and the walk goes on after them.
+struct Scan<'a, S> {
+    store: S,
+    options: &'a CheckOptions,
+    /// The base folders not walked yet, last first.
+    bases: SearchPaths,
+    /// All base folders, to find the recorded paths below them.
+    recorded_bases: SearchPaths,
+    /// The folders being read, innermost last; `None` until opened.
+    dirs: Vec<(path::PathBuf, Option<fs::ReadDir>)>,
+    fs_types: fstype::Cache,
+    /// The folders walked so far, as `(device, inode)`, against loops through bind mounts.
+    visited: collections::HashSet<(u64, u64)>,
+    /// Recorded paths met during the walk.
+    seen: collections::HashSet<path::PathBuf>,
+    /// Recorded paths not met during the walk, once it is over.
+    unseen: Option<std::vec::IntoIter<path::PathBuf>>,
+    /// Called with the (recorded) path of each folder entry met.
+    on_progress: Option<&'a mut ProgressHandler<'a>>,
+}
+
+/// Normalize search paths, dropping those within another one: they'd be walked twice.
+///
+/// Paths are only normalized lexically, without resolving symlinks: a symlinked folder,
+/// e.g. `/lib` on a merged-usr system, is walked under its own recorded path.
+/// The remaining paths keep their order.
+fn distinct_bases(bases: SearchPaths) -> SearchPaths {
+    let normalized: SearchPaths = bases.iter().map(|base| base.components().collect()).collect();
+    let covered = |index: usize, base: &path::Path| normalized.iter().enumerate().any(|(other_index, other)| {
+        // Of identical paths, the first one is kept.
+        base.starts_with(other) && (base != other || other_index < index)
+    });
+    normalized.iter().enumerate()
+        .filter(|(index, base)| !covered(*index, base))
+        .map(|(_, base)| base.clone())
+        .collect()
+}
+
+/// Whether a recorded path would be met walking `recorded_bases`, as for `CheckOptions::max_depth`.
+fn within_walk_depth(path: &path::Path, recorded_bases: &[path::PathBuf], options: &CheckOptions) -> bool {
+    options.max_depth.is_none() || recorded_bases.iter()
+        .filter_map(|base| path.strip_prefix(base).ok())
+        .any(|relative| relative.components().count().checked_sub(1).is_none_or(|depth| options.within_depth(depth)))
+}
+
+impl<'a, S: ManifestProvider> Scan<'a, S> {
+    fn new(store: S, bases: SearchPaths, options: &'a CheckOptions) -> Self {
+        let bases = distinct_bases(bases);
+        Scan {
+            store,
+            options,
+            recorded_bases: bases.iter().map(|base| options.recorded_path(base)).collect(),
+            bases: bases.into_iter().rev().collect(),
+            dirs: Vec::new(),
+            fs_types: fstype::Cache::default(),
+            visited: collections::HashSet::new(),
+            seen: collections::HashSet::new(),
+            unseen: None,
+            on_progress: None,
+        }
+    }
+
+    /// The next file to check from the walk, or `None` once all folders are walked.
+    fn next_found(&mut self) -> Option<Result<Found, Error>> {
+        loop {
+            let (dir, entries) = match self.dirs.last_mut() {
+                Some(current) => current,
+                None => {
+                    let base = self.bases.pop()?;
+                    self.dirs.push((base, None));
+                    continue;
+                },
+            };
+            let entries = match entries {
+                Some(entries) => entries,
+                None => {
+                    match fs::read_dir(&*dir) {
+                        Ok(opened) => *entries = Some(opened),
+                        Err(e) => {
+                            let path = dir.clone();
+                            self.dirs.pop();
+                            return Some(Err(Error::DirUnreadable { path, source: e }));
+                        },
+                    }
+                    continue;
+                },
+            };
+            let entry = match entries.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => return Some(Err(Error::DirUnreadable { path: dir.clone(), source: e })),
+                None => {
+                    self.dirs.pop();
+                    continue;
+                },
+            };
+            match self.found(&entry) {
+                Ok(Some(found)) => return Some(Ok(found)),
+                Ok(None) => {},
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Look at a folder entry, returning it if it's something to check.
+    ///
+    /// Folders are queued for walking; those recorded in the VarDB are checked as well.
+    fn found(&mut self, entry: &fs::DirEntry) -> Result<Option<Found>, Error> {
+        let options = self.options;
+        // Like `fs::symlink_metadata`, this doesn't follow symlinks: a link to a folder isn't walked.
         let metadata = entry.metadata().map_err(|e| Error::DirUnreadable { path: entry.path(), source: e })?;
+        // The folder holding this entry is the innermost one being read.
+        let depth = self.dirs.len() - 1;
+        let path = options.recorded_path(&entry.path());
+        if options.ignores(&path) {
+            return Ok(None);
+        }
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(&path);
+        }
+        let expected = self.store.expected(&path);
+        if expected.is_some() {
+            self.seen.insert(path.clone());
+        }
         if metadata.is_dir() {
-            check_dir(&entry.path(), store, &mut output)?;
-        } else {
-            let result = check_file(&entry, store.get(&entry.path()))?;
-            output.insert(entry.path(), result);
+            // Off Unix, there's no inode to tell folders apart.
+            let first_visit = !cfg!(unix) || self.visited.insert((metadata.dev(), metadata.ino()));
+            if first_visit && options.within_depth(depth + 1) && !skipped_fs(&entry.path(), &metadata, options, &mut self.fs_types)? {
+                self.dirs.push((entry.path(), None));
+            }
+            if expected.is_none() {
+                return Ok(None);
+            }
+        }
+        if options.regular_files_only && !(metadata.is_file() && expected.is_none_or(is_regular)) {
+            return Ok(None);
+        }
+        Ok(Some(Found { path, disk_path: entry.path(), metadata }))
+    }
+
+    fn check(&self, found: &Found) -> Result<ResultItem, Error> {
+        check_found(&self.store, found, self.options)
+    }
+
+    fn within_depth(&self, path: &path::Path) -> bool {
+        within_walk_depth(path, &self.recorded_bases, self.options)
+    }
+
+    /// The next recorded path missing from disk, once the walk is over.
+    ///
+    /// Only paths not met during the walk need checking, e.g. files below a symlinked folder.
+    fn next_absent(&mut self) -> Option<Result<ResultItem, Error>> {
+        if self.unseen.is_none() {
+            let unseen: SearchPaths = self.store.recorded_paths()
+                .filter(|path| !self.seen.contains(*path) && self.recorded_bases.iter().any(|base| path.starts_with(base)))
+                .filter(|path| !self.options.ignores(path) && self.within_depth(path))
+                .map(path::PathBuf::from)
+                .collect();
+            self.unseen = Some(unseen.into_iter());
+        }
+        let options = self.options;
+        for path in self.unseen.as_mut()?.by_ref() {
+            let expected = match self.store.expected(&path) {
+                Some(expected) if !options.regular_files_only || is_regular(expected) => expected,
+                _ => continue,
+            };
+            let disk_path = options.disk_path(&path);
+            match fs::symlink_metadata(&disk_path) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    let mut result = absent(&path, expected);
+                    result.owner = self.store.owner(&path).map(String::from);
+                    return Some(Ok(result));
+                },
+                Err(e) => return Some(Err(Error::FileUnreadable { path: disk_path, source: e })),
+            }
+        }
+        None
+    }
+}
+
+impl<S: ManifestProvider> Iterator for Scan<'_, S> {
+    type Item = Result<ResultItem, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let result = match self.next_found() {
+                Some(found) => found.and_then(|found| self.check(&found)),
+                None => self.next_absent()?,
+            };
+            if result.as_ref().map_or(true, |result| self.options.wants(&result.status)) {
+                return Some(result);
+            }
+        }
+    }
+}
+
+/// State of a walk through the checked folders, collecting the results.
+struct Walk<'a> {
+    scan: Scan<'a, &'a dyn ManifestProvider>,
+    on_orphan: &'a mut OrphanHandler<'a>,
+    /// Non-fatal errors, in lenient mode.
+    errors: Vec<Error>,
+    results: ResultSet,
+}
+
+impl<'a> Walk<'a> {
+    fn new(store: &'a dyn ManifestProvider, bases: SearchPaths, options: &'a CheckOptions, on_orphan: &'a mut OrphanHandler<'a>) -> Self {
+        Walk { scan: Scan::new(store, bases, options), on_orphan, errors: Vec::new(), results: ResultSet::new() }
+    }
+
+    /// Record an unreadable, unknown or unverifiable file, or an unreadable folder, in lenient mode; other errors are fatal.
+    fn tolerate(&mut self, result: Result<(), Error>) -> Result<(), Error> {
+        match result {
+            Err(e @ Error::DirUnreadable { .. })
+            | Err(e @ Error::FileUnreadable { .. })
+            | Err(e @ Error::UnknownFileType { .. })
+            | Err(e @ Error::UnsupportedHash { .. }) if self.scan.options.lenient => {
+                self.errors.push(e);
+                Ok(())
+            },
+            result => result,
+        }
+    }
+
+    /// Walk all folders, then look for the missing recorded paths.
+    fn run(&mut self) -> Result<(), Error> {
+        while let Some(found) = self.scan.next_found() {
+            let result = found.and_then(|found| self.check_found(&found));
+            self.tolerate(result)?;
+        }
+        self.add_absent()
+    }
+
+    fn add_absent(&mut self) -> Result<(), Error> {
+        while let Some(result) = self.scan.next_absent() {
+            let result = result.map(|result| self.insert(result));
+            self.tolerate(result)?;
+        }
+        Ok(())
+    }
+
+    /// Keep a result, if its status is wanted.
+    fn insert(&mut self, result: ResultItem) {
+        if self.scan.options.wants(&result.status) {
+            self.results.insert(result.path.clone(), result);
+        }
+    }
+
+    fn check_found(&mut self, found: &Found) -> Result<(), Error> {
+        let mut result = self.scan.check(found)?;
+        if result.status == FileStatus::Orphan {
+            let action = (self.on_orphan)(&found.path);
+            result.details.extend(handle_orphan(&found.path, &found.disk_path, action, self.scan.options)?);
         }
+        self.insert(result);
+        Ok(())
     }
-    Ok(())
 }
 
 
-pub fn statuses(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path) -> Result<ResultSet, Error> {
+/// Check the files below `paths` against the VarDB at `vdb_root`.
+///
+/// Any collection of paths works, e.g. `[path]` to check a single folder;
+/// a recorded path below any of them is checked.
+pub fn statuses(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path, options: &CheckOptions) -> Result<ResultSet, Error> {
+    statuses_with_stats(paths, vdb_root, options).map(|(results, _stats)| results)
+}
+
+/// Same as `statuses`, as a list sorted by path.
+///
+/// Paths are compared component by component: a folder comes right before its contents.
+pub fn statuses_sorted(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path, options: &CheckOptions) -> Result<Vec<ResultItem>, Error> {
+    let mut results: Vec<ResultItem> = statuses(paths, vdb_root, options)?.into_values().collect();
+    results.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(results)
+}
+
+/// Same as `statuses`, also returning which VarDB entries were outside of the `paths`.
+///
+/// Useful to understand why a recorded file wasn't checked.
+pub fn statuses_with_stats(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path, options: &CheckOptions) -> Result<(ResultSet, vardbapi::FilterStats), Error> {
+    let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    let (expectations, stats) = load_expectations(&paths_list, vdb_root, options)?;
+    statuses_against(paths_list, &expectations, options).map(|results| (results, stats))
+}
+
+/// Same as `statuses`, calling `on_orphan` for each orphan file as soon as it is found.
+///
+/// The returned `OrphanAction` is executed right away, and recorded in the result's details;
+/// removing files requires `CheckOptions::allow_orphan_removal`.
+pub fn statuses_with_orphan_handler(
+    paths: impl IntoIterator<Item=path::PathBuf>,
+    vdb_root: &path::Path,
+    options: &CheckOptions,
+    mut on_orphan: impl FnMut(&path::Path) -> OrphanAction,
+) -> Result<ResultSet, Error> {
+    let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    let (expectations, _stats) = load_expectations(&paths_list, vdb_root, options)?;
+    let mut walk = Walk::new(&expectations, paths_list, options, &mut on_orphan);
+    walk.run()?;
+    Ok(walk.results)
+}
+
+/// Same as `statuses`, calling `on_progress` with each file or folder met during the walk.
+///
+/// Every entry is reported, including those unowned folders and filtered out files
+/// which never make it to the results; this tells a long scan isn't stuck.
+pub fn statuses_with_progress(
+    paths: impl IntoIterator<Item=path::PathBuf>,
+    vdb_root: &path::Path,
+    options: &CheckOptions,
+    mut on_progress: impl FnMut(&path::Path),
+) -> Result<ResultSet, Error> {
+    let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    let (expectations, _stats) = load_expectations(&paths_list, vdb_root, options)?;
+    let mut keep = |_: &path::Path| OrphanAction::Keep;
+    let mut walk = Walk::new(&expectations, paths_list, options, &mut keep);
+    walk.scan.on_progress = Some(&mut on_progress);
+    walk.run()?;
+    Ok(walk.results)
+}
+
+/// Same as `statuses`, yielding each result as soon as it is known.
+///
+/// Nothing is kept in memory but the VarDB and the recorded paths met so far.
+/// Errors are yielded as they happen, and don't stop the walk.
+pub fn status_iter<'a>(
+    paths: impl IntoIterator<Item=path::PathBuf>,
+    vdb_root: &path::Path,
+    options: &'a CheckOptions,
+) -> Result<impl Iterator<Item=Result<ResultItem, Error>> + 'a, Error> {
     let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    let (expectations, _stats) = load_expectations(&paths_list, vdb_root, options)?;
+    Ok(Scan::new(expectations, paths_list, options))
+}
+
+/// Load the VarDB entries within the (on-disk) search paths.
+fn load_expectations(paths: &SearchPaths, vdb_root: &path::Path, options: &CheckOptions) -> Result<(vardbapi::VarDB, vardbapi::FilterStats), Error> {
+    let contents_bases = paths.iter().filter_map(|base| options.unprefixed(&options.recorded_path(base))).collect();
+    vardbapi::get_vdb_with_stats(vdb_root, &contents_bases)
+        .map(|(vdb, stats)| (adapt_vdb(vdb, options), stats))
+        .map_err(|e| Error::VarDBError { source: e })
+}
+
+/// Adapt a VarDB to the checked system, see `with_eprefix` and `resplit_symlinks`.
+fn adapt_vdb(vdb: vardbapi::VarDB, options: &CheckOptions) -> vardbapi::VarDB {
+    resplit_symlinks(with_eprefix(vdb, options), options)
+}
+
+/// Rewrite the paths of a VarDB below `CheckOptions::eprefix`, if set.
+fn with_eprefix(vdb: vardbapi::VarDB, options: &CheckOptions) -> vardbapi::VarDB {
+    if options.eprefix.is_none() {
+        return vdb;
+    }
+    vdb.into_iter().map(|(path, record)| (options.prefixed(&path), record)).collect()
+}
+
+const SYMLINK_SEPARATOR: &str = " -> ";
+
+/// Move symlinks whose path contains ` -> ` to their actual path.
+///
+/// CONTENTS lines are split at the first ` -> `; when that doesn't match the disk, but a later
+/// separator splits the line into a symlink, mapped to disk, to the rest, the latter wins.
+fn resplit_symlinks(vdb: vardbapi::VarDB, options: &CheckOptions) -> vardbapi::VarDB {
+    let ambiguous = |record: &vardbapi::VarDBRecord| match record.metadata {
+        vardbapi::FileMetadata::Symlink { ref dest, .. } => dest.to_str().is_some_and(|dest| dest.contains(SYMLINK_SEPARATOR)),
+        _ => false,
+    };
+    if !vdb.values().any(ambiguous) {
+        return vdb;
+    }
+    vdb.into_iter().map(|(path, mut record)| {
+        if let vardbapi::FileMetadata::Symlink { ref mut dest, .. } = record.metadata {
+            if let Some((actual_path, actual_dest)) = resplit_symlink(&path, dest, options) {
+                *dest = actual_dest;
+                return (actual_path, record);
+            }
+        }
+        (path, record)
+    }).collect()
+}
 
-    let expectations = vardbapi::get_vdb(vdb_root, &paths_list)
+/// The split of `<path> -> <dest>` matching an on-disk symlink, unless the recorded one does.
+fn resplit_symlink(path: &path::Path, dest: &path::Path, options: &CheckOptions) -> Option<(path::PathBuf, path::PathBuf)> {
+    let points_to = |link: &path::Path, target: &str| fs::read_link(options.disk_path(link)).is_ok_and(|actual| actual == path::Path::new(target));
+    let (path, dest) = (path.to_str()?, dest.to_str()?);
+    if points_to(path.as_ref(), dest) {
+        return None;
+    }
+    dest.match_indices(SYMLINK_SEPARATOR).find_map(|(index, _)| {
+        let link = path::PathBuf::from(format!("{}{}{}", path, SYMLINK_SEPARATOR, &dest[..index]));
+        let target = &dest[index + SYMLINK_SEPARATOR.len()..];
+        if points_to(&link, target) { Some((link, target.into())) } else { None }
+    })
+}
+
+/// A VarDB parsed once, to check any number of path sets against it.
+///
+/// Unlike `statuses`, which only loads the entries within its paths,
+/// this keeps the whole VarDB in memory; long-running callers avoid
+/// re-parsing it for each check.
+#[derive(Debug, Default)]
+pub struct VarDb {
+    entries: vardbapi::VarDB,
+}
+
+impl VarDb {
+    /// Parse the whole VarDB found at `vdb_root`.
+    pub fn load(vdb_root: &path::Path) -> Result<VarDb, Error> {
+        let entries = vardbapi::get_vdb(vdb_root, &vec![path::PathBuf::from("/")])
+            .map_err(|e| Error::VarDBError { source: e })?;
+        Ok(VarDb { entries })
+    }
+
+    /// Load a manifest written by `save_manifest`, e.g. on a machine without the VarDB.
+    pub fn load_manifest(path: &path::Path) -> Result<VarDb, Error> {
+        vardbapi::load_snapshot(path)
+            .map(VarDb::from)
+            .map_err(|e| Error::VarDBError { source: e })
+    }
+
+    /// Save all entries as a manifest file, in the `vardbapi::write_snapshot` format.
+    ///
+    /// Checking against the loaded manifest gives the same results as against this VarDB.
+    pub fn save_manifest(&self, path: &path::Path) -> Result<(), Error> {
+        let failed = |e| Error::ManifestUnwritable { path: path.into(), source: e };
+        let mut out = io::BufWriter::new(fs::File::create(path).map_err(failed)?);
+        vardbapi::write_snapshot(&self.entries, &mut out)
+            .and_then(|()| io::Write::flush(&mut out))
+            .map_err(failed)
+    }
+
+    /// Same as `statuses`, against the already parsed VarDB.
+    ///
+    /// As the VarDB is adapted to the options, e.g. `CheckOptions::eprefix`, it is copied for each check.
+    pub fn statuses_with(&self, paths: impl IntoIterator<Item=path::PathBuf>, options: &CheckOptions) -> Result<ResultSet, Error> {
+        statuses_against(paths, &adapt_vdb(self.entries.clone(), options), options)
+    }
+
+    /// Number of recorded paths.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl From<vardbapi::VarDB> for VarDb {
+    fn from(entries: vardbapi::VarDB) -> Self {
+        VarDb { entries }
+    }
+}
+
+impl ManifestProvider for VarDb {
+    fn expected(&self, path: &path::Path) -> Option<&vardbapi::FileMetadata> {
+        self.entries.expected(path)
+    }
+
+    fn owner(&self, path: &path::Path) -> Option<&str> {
+        self.entries.owner(path)
+    }
+
+    fn recorded_paths(&self) -> Box<dyn Iterator<Item=&path::Path> + '_> {
+        self.entries.recorded_paths()
+    }
+}
+
+/// Check the given paths against already loaded expectations.
+///
+/// This allows checking the disk against a VarDB from another source, e.g.
+/// a snapshot loaded with `vardbapi::load_snapshot`, or against any other
+/// `ManifestProvider`.
+pub fn statuses_against(paths: impl IntoIterator<Item=path::PathBuf>, expectations: &impl ManifestProvider, options: &CheckOptions) -> Result<ResultSet, Error> {
+    let mut keep = |_: &path::Path| OrphanAction::Keep;
+    let mut walk = Walk::new(expectations, paths.into_iter().collect(), options, &mut keep);
+    walk.run()?;
+    Ok(walk.results)
+}
+
+/// Check the files of all packages within a VarDB category, e.g. `dev-python`.
+///
+/// Only the recorded paths are looked at: no directory is walked, and files
+/// missing from disk are reported as `Absent`.
+pub fn statuses_for_category(vdb_root: &path::Path, category: &str, options: &CheckOptions) -> Result<ResultSet, Error> {
+    let expectations = vardbapi::get_category_vdb(vdb_root, category)
+        .map_err(|e| Error::VarDBError { source: e })?;
+    statuses_of_recorded(&adapt_vdb(expectations, options), options)
+}
+
+/// Check the files of a single package, e.g. `dev-lang/rust-1.41.1`.
+///
+/// Without a version, e.g. `dev-lang/rust`, the files of all installed versions are checked.
+/// As for `statuses_for_category`, no directory is walked.
+pub fn statuses_for_atom(vdb_root: &path::Path, atom: &str, options: &CheckOptions) -> Result<ResultSet, Error> {
+    let expectations = vardbapi::get_atom_vdb(vdb_root, atom)
         .map_err(|e| Error::VarDBError { source: e })?;
+    statuses_of_recorded(&adapt_vdb(expectations, options), options)
+}
+
+/// Check each recorded path, without walking anything.
+fn statuses_of_recorded(expectations: &vardbapi::VarDB, options: &CheckOptions) -> Result<ResultSet, Error> {
     let mut results = ResultSet::new();
 
-    for base in paths_list.iter() {
-        check_dir(base, &expectations, &mut results)?;
+    for (path, record) in expectations.iter() {
+        if options.regular_files_only && !is_regular(&record.metadata) || options.ignores(path) {
+            continue;
+        }
+        let mut result = compare(path, &record.metadata, options)?;
+        result.owner = Some(record.atom.clone());
+        if options.wants(&result.status) {
+            results.insert(path.clone(), result);
+        }
     }
     Ok(results)
 }
 
+/// Paths found only on disk, or only in the VarDB.
+#[derive(Eq, PartialEq, Debug, Default)]
+pub struct Reconciliation {
+    /// Files on disk which no package owns, sorted.
+    pub orphans: Vec<path::PathBuf>,
+    /// Recorded paths missing from disk, sorted.
+    pub absent: Vec<path::PathBuf>,
+    /// Recorded paths found on disk with another file type, sorted.
+    pub mismatched: Vec<path::PathBuf>,
+}
+
+/// A set of owned paths, for structural checks.
+trait OwnedPaths {
+    fn is_owned(&self, path: &path::Path) -> bool;
+
+    /// The owned paths below `dir`, including `dir` itself if owned, in no particular order.
+    fn owned_under(&self, dir: &path::Path) -> Vec<path::PathBuf>;
+
+    /// The recorded metadata of an owned path, if known, to compare its file type.
+    fn recorded(&self, _path: &path::Path) -> Option<&vardbapi::FileMetadata> {
+        None
+    }
+}
+
+impl OwnedPaths for vardbapi::VarDB {
+    fn is_owned(&self, path: &path::Path) -> bool {
+        self.contains_key(path)
+    }
+
+    fn owned_under(&self, dir: &path::Path) -> Vec<path::PathBuf> {
+        self.keys().filter(|path| path.starts_with(dir)).cloned().collect()
+    }
+
+    fn recorded(&self, path: &path::Path) -> Option<&vardbapi::FileMetadata> {
+        self.get(path).map(|record| &record.metadata)
+    }
+}
+
+impl OwnedPaths for collections::HashSet<path::PathBuf> {
+    fn is_owned(&self, path: &path::Path) -> bool {
+        self.contains(path)
+    }
+
+    fn owned_under(&self, dir: &path::Path) -> Vec<path::PathBuf> {
+        self.iter().filter(|path| path.starts_with(dir)).cloned().collect()
+    }
+}
+
+impl OwnedPaths for vardbapi::PathTrie {
+    fn is_owned(&self, path: &path::Path) -> bool {
+        self.contains(path)
+    }
+
+    fn owned_under(&self, dir: &path::Path) -> Vec<path::PathBuf> {
+        self.paths_under(dir)
+    }
+}
+
+/// A VarDB, indexed by a `PathTrie` to find the owned paths below the checked ones.
+struct IndexedVarDB<'a> {
+    records: &'a vardbapi::VarDB,
+    trie: vardbapi::PathTrie,
+}
+
+impl<'a> From<&'a vardbapi::VarDB> for IndexedVarDB<'a> {
+    fn from(records: &'a vardbapi::VarDB) -> Self {
+        IndexedVarDB { records, trie: vardbapi::PathTrie::from(records) }
+    }
+}
+
+impl OwnedPaths for IndexedVarDB<'_> {
+    fn is_owned(&self, path: &path::Path) -> bool {
+        self.records.is_owned(path)
+    }
+
+    fn owned_under(&self, dir: &path::Path) -> Vec<path::PathBuf> {
+        self.trie.owned_under(dir)
+    }
+
+    fn recorded(&self, path: &path::Path) -> Option<&vardbapi::FileMetadata> {
+        self.records.recorded(path)
+    }
+}
+
+/// State of a structural walk through the checked folders, see `reconcile`.
+///
+/// Folders are filtered as in `Scan`, from `CheckOptions` and against loops.
+struct Audit<'a> {
+    store: &'a dyn OwnedPaths,
+    options: &'a CheckOptions,
+    fs_types: fstype::Cache,
+    /// The folders walked so far, as `(device, inode)`, against loops through bind mounts.
+    visited: collections::HashSet<(u64, u64)>,
+    /// Recorded paths met during the walk.
+    seen: collections::HashSet<path::PathBuf>,
+    /// Unowned non-folders met during the walk.
+    orphans: Vec<path::PathBuf>,
+    /// Owned paths met during the walk, whose file type isn't the recorded one.
+    mismatched: Vec<path::PathBuf>,
+}
+
+impl<'a> Audit<'a> {
+    fn new(store: &'a dyn OwnedPaths, options: &'a CheckOptions) -> Self {
+        Audit {
+            store,
+            options,
+            fs_types: fstype::Cache::default(),
+            visited: collections::HashSet::new(),
+            seen: collections::HashSet::new(),
+            orphans: Vec::new(),
+            mismatched: Vec::new(),
+        }
+    }
+
+    /// Walk a folder, whose entries are at `depth` below the checked path.
+    fn walk(&mut self, dir: &path::Path, depth: usize) -> Result<(), Error> {
+        let options = self.options;
+        let entries = fs::read_dir(dir).map_err(|e| Error::DirUnreadable { path: dir.into(), source: e })?;
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::DirUnreadable { path: dir.into(), source: e })?;
+            // Like `fs::symlink_metadata`, this doesn't follow symlinks: a link to a folder isn't walked.
+            let metadata = entry.metadata().map_err(|e| Error::DirUnreadable { path: entry.path(), source: e })?;
+            let path = options.recorded_path(&entry.path());
+            if options.ignores(&path) {
+                continue;
+            }
+            let owned = self.store.is_owned(&path);
+            if metadata.is_dir() {
+                // Off Unix, there's no inode to tell folders apart.
+                let first_visit = !cfg!(unix) || self.visited.insert((metadata.dev(), metadata.ino()));
+                if first_visit && options.within_depth(depth + 1) && !skipped_fs(&entry.path(), &metadata, options, &mut self.fs_types)? {
+                    self.walk(&entry.path(), depth + 1)?;
+                }
+            } else if !owned && (metadata.is_file() || !options.regular_files_only) {
+                self.orphans.push(path.clone());
+            }
+            if owned {
+                if self.mismatches(&path, &metadata) {
+                    self.mismatched.push(path.clone());
+                }
+                self.seen.insert(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an owned path has another file type than recorded, if known.
+    ///
+    /// As in `Scan`, only regular files are compared with `CheckOptions::regular_files_only`.
+    fn mismatches(&self, path: &path::Path, metadata: &fs::Metadata) -> bool {
+        match self.store.recorded(path) {
+            Some(expected) if !self.options.regular_files_only || metadata.is_file() && is_regular(expected) => {
+                !FileType::try_from(metadata.file_type()).is_ok_and(|ftype| same_kind(expected, ftype))
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Whether an on-disk file type matches an expectation.
+fn same_kind(expected: &vardbapi::FileMetadata, ftype: FileType) -> bool {
+    match expected {
+        vardbapi::FileMetadata::Device => ftype == FileType::BLK || ftype == FileType::CHR,
+        _ => ftype == expected_ftype(expected),
+    }
+}
+
+fn reconcile_owned(paths: &SearchPaths, owned: &dyn OwnedPaths, options: &CheckOptions) -> Result<Reconciliation, Error> {
+    let bases = distinct_bases(paths.clone());
+    let recorded_bases: SearchPaths = bases.iter().map(|base| options.recorded_path(base)).collect();
+    let mut audit = Audit::new(owned, options);
+    for base in bases {
+        audit.seen.insert(options.recorded_path(&base));
+        audit.walk(&base, 0)?;
+    }
+    let below_bases: collections::BTreeSet<path::PathBuf> = recorded_bases.iter().flat_map(|base| owned.owned_under(base)).collect();
+    let unseen = below_bases.iter()
+        .filter(|path| !audit.seen.contains(*path))
+        .filter(|path| !options.ignores(path) && within_walk_depth(path, &recorded_bases, options))
+        .filter(|path| !options.regular_files_only || owned.recorded(path).is_none_or(is_regular));
+    let mut absent = Vec::new();
+    let mut mismatched = Vec::new();
+    for path in unseen {
+        let disk_path = options.disk_path(path);
+        match fs::symlink_metadata(&disk_path) {
+            Ok(metadata) if audit.mismatches(path, &metadata) => mismatched.push(path.clone()),
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => absent.push(path.clone()),
+            Err(e) => return Err(Error::FileUnreadable { path: disk_path, source: e }),
+        }
+    }
+    audit.mismatched.extend(mismatched);
+    let mut reconciliation = Reconciliation { orphans: audit.orphans, absent, mismatched: audit.mismatched };
+    reconciliation.orphans.sort();
+    reconciliation.absent.sort();
+    reconciliation.mismatched.sort();
+    Ok(reconciliation)
+}
+
+/// Compare which paths exist on disk and in the VarDB, without looking at their content.
+///
+/// This is a fast structural audit: no file is hashed, only file types are compared.
+/// Recorded paths not met during the walk, e.g. below a symlinked folder, are
+/// only reported as absent if missing on disk.
+pub fn reconcile(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path, options: &CheckOptions) -> Result<Reconciliation, Error> {
+    let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    let (expectations, _stats) = load_expectations(&paths_list, vdb_root, options)?;
+    reconcile_owned(&paths_list, &IndexedVarDB::from(&expectations), options)
+}
+
+/// Files below `paths` which no package owns, sorted.
+///
+/// Same as the orphans from `reconcile`, without looking for absent files;
+/// nothing is hashed or classified, and folders are never reported.
+pub fn orphans(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path, options: &CheckOptions) -> Result<Vec<path::PathBuf>, Error> {
+    let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    let (expectations, _stats) = load_expectations(&paths_list, vdb_root, options)?;
+    let mut audit = Audit::new(&expectations, options);
+    for base in distinct_bases(paths_list) {
+        audit.walk(&base, 0)?;
+    }
+    audit.orphans.sort();
+    Ok(audit.orphans)
+}
+
+/// Same as `reconcile`, against a prebuilt set of owned (recorded) paths instead of the VarDB.
+///
+/// This skips parsing the VarDB, e.g. to repeat checks until the VarDB changes;
+/// as the set holds no file type, none is ever reported as mismatched.
+pub fn reconcile_against(paths: impl IntoIterator<Item=path::PathBuf>, owned: &collections::HashSet<path::PathBuf>, options: &CheckOptions) -> Result<Reconciliation, Error> {
+    let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    reconcile_owned(&paths_list, owned, options)
+}
+
+
+/// A single SHA-256 fingerprint of a whole run, to compare trees across machines.
+///
+/// Results are hashed sorted by path, each as its path, recorded hash (if any) and status;
+/// two runs have the same fingerprint iff all those match.
+pub fn tree_fingerprint(results: &ResultSet) -> [u8; 32] {
+    let mut paths: Vec<&path::PathBuf> = results.keys().collect();
+    paths.sort();
+    let mut hasher = sha2::Sha256::new();
+    for path in paths {
+        let result = &results[path];
+        let recorded_hash = result.recorded_hash.as_ref().map(|hash| hash.to_string()).unwrap_or_default();
+        // NUL can't appear in a path, so fields can't be confused with each other.
+        hasher.input(path.as_os_str().as_bytes());
+        hasher.input(b"\0");
+        hasher.input(recorded_hash.as_bytes());
+        hasher.input(b"\0");
+        hasher.input(format!("{:?}", result.status).as_bytes());
+        hasher.input(b"\0");
+    }
+    hasher.result().into()
+}
+
+/// Order results by path, as `statuses_sorted` does.
+pub fn order_by_path(results: &ResultSet) -> Vec<&ResultItem> {
+    let mut ordered: Vec<&ResultItem> = results.values().collect();
+    ordered.sort_by_key(|result| &result.path);
+    ordered
+}
+
+/// Order results by the build time of their owning package, newest first.
+///
+/// `build_times` maps atoms to their build time, as returned by `vardbapi::build_times`;
+/// results with an unknown build time come next, then orphans. Ties are ordered by path.
+pub fn order_by_build_time<'a>(results: &'a ResultSet, build_times: &collections::HashMap<String, u64>) -> Vec<&'a ResultItem> {
+    let mut ordered: Vec<&ResultItem> = results.values().collect();
+    ordered.sort_by_key(|result| {
+        let rank = match result.owner.as_ref().map(|atom| build_times.get(atom)) {
+            Some(Some(time)) => (0, cmp::Reverse(*time)),
+            Some(None) => (1, cmp::Reverse(0)),
+            None => (2, cmp::Reverse(0)),
+        };
+        (rank, &result.path)
+    });
+    ordered
+}
+
+/// Number of results found for each status.
+#[derive(Eq, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Summary {
+    pub aligned: usize,
+    pub touched: usize,
+    pub edited: usize,
+    pub changed: usize,
+    pub absent: usize,
+    pub orphan: usize,
+    pub skipped: usize,
+    pub broken: usize,
+    pub unreadable: usize,
+    /// Number of files modified after the newest package install, if compared.
+    ///
+    /// See `summarize_against_vdb`.
+    pub newer_than_vdb: Option<usize>,
+    /// Total size of the orphans, in bytes: what deleting them would reclaim.
+    pub orphan_bytes: u64,
+}
+
+impl Summary {
+    fn count(&mut self, status: &FileStatus) {
+        match status {
+            FileStatus::Aligned => self.aligned += 1,
+            FileStatus::Touched => self.touched += 1,
+            FileStatus::Edited => self.edited += 1,
+            FileStatus::Changed => self.changed += 1,
+            FileStatus::Absent => self.absent += 1,
+            FileStatus::Orphan => self.orphan += 1,
+            FileStatus::Skipped => self.skipped += 1,
+            FileStatus::Broken => self.broken += 1,
+            FileStatus::Unreadable => self.unreadable += 1,
+        }
+    }
+
+    /// Total number of results.
+    pub fn total(&self) -> usize {
+        self.aligned + self.touched + self.edited + self.changed + self.absent + self.orphan + self.skipped + self.broken + self.unreadable
+    }
+
+    /// Number of serious findings: files whose content or type changed, which are missing
+    /// or unreadable, or symlinks leading nowhere.
+    ///
+    /// Touched files, orphans and skipped files aren't counted.
+    pub fn serious(&self) -> usize {
+        self.edited + self.changed + self.absent + self.broken + self.unreadable
+    }
+
+    /// Whether at least `threshold` files are newer than the VarDB.
+    ///
+    /// This hints at a stale VarDB, e.g. when packages were installed outside of portage.
+    pub fn vdb_may_be_stale(&self, threshold: usize) -> bool {
+        self.newer_than_vdb.is_some_and(|newer| newer >= threshold)
+    }
+}
+
+/// A one-line report, e.g. `4 files: 2 aligned, 0 touched, 1 edited, 0 changed, 0 absent, 1 orphan`.
+///
+/// Skipped, broken and unreadable files are only mentioned when there are some.
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{} files: {} aligned, {} touched, {} edited, {} changed, {} absent, {} orphan",
+            self.total(), self.aligned, self.touched, self.edited, self.changed, self.absent, self.orphan,
+        )?;
+        for (count, name) in [(self.skipped, "skipped"), (self.broken, "broken"), (self.unreadable, "unreadable")].iter().copied() {
+            if count > 0 {
+                write!(f, ", {} {}", count, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Count the results of a run by status.
+pub fn summarize(results: &ResultSet) -> Summary {
+    let mut summary = Summary::default();
+    for result in results.values() {
+        summary.count(&result.status);
+        if result.status == FileStatus::Orphan {
+            summary.orphan_bytes += result.size.unwrap_or_default();
+        }
+    }
+    summary
+}
+
+/// Consistency of a single package, see `per_package_report`.
+#[derive(Eq, PartialEq, Debug)]
+pub struct PackageReport {
+    /// The package, as `<category>/<name>-<version>`.
+    pub atom: String,
+    /// Number of entries recorded for the package.
+    pub recorded: usize,
+    /// Results of the recorded entries which were checked, by status.
+    pub summary: Summary,
+}
+
+/// Count the results of a run by owning package, sorted by atom.
+///
+/// All packages of `vdb` are listed; recorded entries missing from `results`,
+/// e.g. outside of the checked paths, are only counted in `recorded`.
+pub fn per_package_report(results: &ResultSet, vdb: &vardbapi::VarDB) -> Vec<PackageReport> {
+    let mut packages: collections::BTreeMap<&str, PackageReport> = collections::BTreeMap::new();
+    for (path, record) in vdb.iter() {
+        let report = packages.entry(&record.atom).or_insert_with(|| PackageReport {
+            atom: record.atom.clone(),
+            recorded: 0,
+            summary: Summary::default(),
+        });
+        report.recorded += 1;
+        if let Some(result) = results.get(path) {
+            report.summary.count(&result.status);
+        }
+    }
+    packages.into_values().collect()
+}
+
+/// The packages owning any result which isn't `Aligned`, e.g. to re-emerge them.
+///
+/// Results without a known owner, such as orphans, are left out.
+pub fn affected_packages(results: &ResultSet) -> collections::BTreeSet<String> {
+    results.values()
+        .filter(|result| result.status != FileStatus::Aligned)
+        .filter_map(|result| result.owner.clone())
+        .collect()
+}
+
+/// A path whose status differs between two runs, see `diff`.
+#[derive(Eq, PartialEq, Debug)]
+pub struct StatusChange {
+    pub path: path::PathBuf,
+    pub before: FileStatus,
+    pub after: FileStatus,
+}
+
+/// How the results changed between two runs, each list sorted by path.
+#[derive(Eq, PartialEq, Debug, Default)]
+pub struct ResultDiff {
+    /// Paths only found in the new results.
+    pub added: Vec<path::PathBuf>,
+    /// Paths only found in the old results.
+    pub removed: Vec<path::PathBuf>,
+    /// Paths found in both, with another status.
+    pub changed: Vec<StatusChange>,
+}
+
+impl ResultDiff {
+    /// Whether both runs had the same paths, with the same statuses.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compare the results of two runs, e.g. from one night to the next.
+///
+/// Only statuses are compared: a file edited again is still `Edited`, and doesn't show up.
+pub fn diff(old: &ResultSet, new: &ResultSet) -> ResultDiff {
+    let mut result = ResultDiff::default();
+    for (path, after) in new.iter() {
+        match old.get(path) {
+            None => result.added.push(path.clone()),
+            Some(before) if before.status != after.status => result.changed.push(StatusChange {
+                path: path.clone(),
+                before: before.status,
+                after: after.status,
+            }),
+            Some(_) => {},
+        }
+    }
+    result.removed = old.keys().filter(|path| !new.contains_key(*path)).cloned().collect();
+    result.added.sort();
+    result.removed.sort();
+    result.changed.sort_by(|a, b| a.path.cmp(&b.path));
+    result
+}
+
+/// Count the results of a run by status, and those modified after `vdb_mtime`.
+///
+/// `vdb_mtime` is the time of the latest change to the VarDB, as returned by
+/// `vardbapi::newest_atom_mtime`.
+pub fn summarize_against_vdb(results: &ResultSet, vdb_mtime: u64) -> Summary {
+    let mut summary = summarize(results);
+    summary.newer_than_vdb = Some(results.values()
+        .filter(|result| result.mtime.is_some_and(|mtime| mtime > vdb_mtime))
+        .count());
+    summary
+}
+
+/// A whole scan run: its results, and how it went.
+///
+/// With the `serde` feature, results are serialized as by `to_json`, errors as their
+/// messages and times as POSIX timestamps.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ScanReport {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::sorted_results"))]
+    pub results: ResultSet,
+    pub summary: Summary,
+    /// Non-fatal errors met during the scan, see `CheckOptions::lenient`.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::messages"))]
+    pub errors: Vec<Error>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::timestamp"))]
+    pub started: time::SystemTime,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::timestamp"))]
+    pub finished: time::SystemTime,
+    /// The checked paths.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "json::lossy_paths"))]
+    pub paths: SearchPaths,
+    /// The options used.
+    ///
+    /// Not serialized: they hold callbacks, e.g. `hashers`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub options: CheckOptions,
+}
+
+/// Same as `statuses`, bundling the results with a description of the run.
+pub fn statuses_report(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path, options: &CheckOptions) -> Result<ScanReport, Error> {
+    let started = time::SystemTime::now();
+    let paths_list: Vec<path::PathBuf> = paths.into_iter().collect();
+    let (expectations, _stats) = load_expectations(&paths_list, vdb_root, options)?;
+    let mut keep = |_: &path::Path| OrphanAction::Keep;
+    let mut walk = Walk::new(&expectations, paths_list.clone(), options, &mut keep);
+    walk.run()?;
+    Ok(ScanReport {
+        summary: summarize(&walk.results),
+        results: walk.results,
+        errors: walk.errors,
+        started,
+        finished: time::SystemTime::now(),
+        paths: paths_list,
+        options: options.clone(),
+    })
+}
+
 #[derive(Debug)]
 pub enum Error {
     VarDBError {
@@ -187,6 +1961,49 @@ pub enum Error {
         path: path::PathBuf,
         source: io::Error,
     },
+    /// A file type estatus doesn't know about
+    UnknownFileType {
+        path: path::PathBuf,
+    },
+    /// A file recorded with a hash this build can't compute, e.g. BLAKE2B without the `blake2` feature,
+    /// or a tagged hash without a registered hasher
+    UnsupportedHash {
+        path: path::PathBuf,
+    },
+    /// Unable to read or write a `HashCache` file
+    HashCacheFailed {
+        path: path::PathBuf,
+        source: io::Error,
+    },
+    /// Unable to write a manifest file, see `VarDb::save_manifest`
+    ManifestUnwritable {
+        path: path::PathBuf,
+        source: io::Error,
+    },
+    /// An orphan handler asked for a removal which isn't allowed by the options
+    OrphanRemovalDenied {
+        path: path::PathBuf,
+    },
+    /// An orphan handler asked for a quarantine, but no `CheckOptions::quarantine_dir` is set
+    QuarantineDirUnset {
+        path: path::PathBuf,
+    },
+    /// Quarantining or deleting an orphan file failed
+    OrphanRemovalFailed {
+        path: path::PathBuf,
+        source: io::Error,
+    },
+    /// Unable to watch a folder for changes
+    #[cfg(feature = "inotify")]
+    WatchFailed {
+        path: path::PathBuf,
+        source: io::Error,
+    },
+    /// Unable to set up inotify, or to read its change events
+    #[cfg(feature = "inotify")]
+    WatchInterrupted {
+        source: io::Error,
+    },
 }
 
 impl fmt::Display for Error {
@@ -201,6 +2018,35 @@ impl fmt::Display for Error {
             Error::FileUnreadable { ref path, ref source } => {
                 write!(f, "{}: could not read file: {}", path.display(), source)
             },
+            Error::UnknownFileType { ref path } => {
+                write!(f, "{}: unknown file type", path.display())
+            },
+            Error::UnsupportedHash { ref path } => {
+                write!(f, "{}: recorded hash algorithm isn't supported by this build", path.display())
+            },
+            Error::HashCacheFailed { ref path, ref source } => {
+                write!(f, "{}: could not read or write hash cache: {}", path.display(), source)
+            },
+            Error::ManifestUnwritable { ref path, ref source } => {
+                write!(f, "{}: could not write manifest: {}", path.display(), source)
+            },
+            Error::OrphanRemovalDenied { ref path } => {
+                write!(f, "{}: orphan removal isn't allowed", path.display())
+            },
+            Error::QuarantineDirUnset { ref path } => {
+                write!(f, "{}: could not quarantine orphan file: no quarantine folder was set", path.display())
+            },
+            Error::OrphanRemovalFailed { ref path, ref source } => {
+                write!(f, "{}: could not remove orphan file: {}", path.display(), source)
+            },
+            #[cfg(feature = "inotify")]
+            Error::WatchFailed { ref path, ref source } => {
+                write!(f, "{}: could not watch for changes: {}", path.display(), source)
+            },
+            #[cfg(feature = "inotify")]
+            Error::WatchInterrupted { ref source } => {
+                write!(f, "could not read change events: {}", source)
+            },
         }
     }
 }
@@ -211,6 +2057,17 @@ impl error::Error for Error {
             Error::VarDBError { ref source, ..} => Some(source),
             Error::DirUnreadable { ref source, ..} => Some(source),
             Error::FileUnreadable { ref source, ..} => Some(source),
+            Error::UnknownFileType { .. } => None,
+            Error::UnsupportedHash { .. } => None,
+            Error::HashCacheFailed { ref source, ..} => Some(source),
+            Error::ManifestUnwritable { ref source, ..} => Some(source),
+            Error::OrphanRemovalDenied { .. } => None,
+            Error::QuarantineDirUnset { .. } => None,
+            Error::OrphanRemovalFailed { ref source, ..} => Some(source),
+            #[cfg(feature = "inotify")]
+            Error::WatchFailed { ref source, ..} => Some(source),
+            #[cfg(feature = "inotify")]
+            Error::WatchInterrupted { ref source } => Some(source),
         }
     }
 }
@@ -1,17 +1,601 @@
+use std::collections;
 use std::env;
+use std::ffi;
 use std::path;
+use std::sync;
+use std::time;
 
-use estatus;
+/// Number of files newer than the VarDB above which it is reported as potentially stale.
+const STALE_VDB_THRESHOLD: usize = 20;
+
+/// Exit status when more serious findings than accepted were found, see `exit_code`.
+const EXIT_FINDINGS: i32 = 1;
+/// Exit status when the run couldn't complete, e.g. with invalid arguments or an unreadable VarDB.
+const EXIT_ERROR: i32 = 2;
+
+/// Output formats of the CLI.
+#[derive(Debug, Eq, PartialEq)]
+enum Format {
+    /// One `<status>: <path>` line per file.
+    Text,
+    /// Aggregated counters only, as `key value` lines for metrics scraping.
+    Metrics,
+    /// An `emerge` command re-installing the packages owning non-aligned files.
+    Emerge,
+    /// A single hash of all results, see `estatus::tree_fingerprint`.
+    Fingerprint,
+    /// All results as a JSON array, see `estatus::to_json`.
+    #[cfg(feature = "serde")]
+    Json,
+}
+
+/// Names of all formats, as accepted by `--format`.
+#[cfg(not(feature = "serde"))]
+const FORMAT_NAMES: &[&str] = &["text", "metrics", "emerge", "fingerprint"];
+#[cfg(feature = "serde")]
+const FORMAT_NAMES: &[&str] = &["text", "metrics", "emerge", "fingerprint", "json"];
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "metrics" => Ok(Format::Metrics),
+            "emerge" => Ok(Format::Emerge),
+            "fingerprint" => Ok(Format::Fingerprint),
+            #[cfg(feature = "serde")]
+            "json" => Ok(Format::Json),
+            _ => Err(format!("invalid format \"{}\", expected one of: {}", s, FORMAT_NAMES.join(", "))),
+        }
+    }
+}
+
+/// Parsed command line.
+#[derive(Debug)]
+struct Args {
+    paths: Vec<path::PathBuf>,
+    format: Format,
+    category: Option<String>,
+    vdb_url: Option<String>,
+    /// The VarDB folder from `--vdb-root`; see `vdb_root` for its default.
+    vdb_root: Option<path::PathBuf>,
+    /// The `--hash-cache` file, loaded before the run and saved after it.
+    hash_cache: Option<path::PathBuf>,
+    debug_filter: bool,
+    check_vdb_freshness: bool,
+    dump_vdb: bool,
+    /// The unprivileged user to switch to, from `--reduced-privileges`.
+    reduced_privileges: Option<String>,
+    /// Number of serious findings accepted before failing, from `--fail-over`.
+    fail_over: usize,
+    by_build_time: bool,
+    options: estatus::CheckOptions,
+}
+
+/// Parse the command line arguments, excluding the program name.
+///
+/// Only long options are supported, as either `--option=value` or `--option value`.
+fn parse_args(args: impl IntoIterator<Item=String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        paths: Vec::new(),
+        format: Format::Text,
+        category: None,
+        vdb_url: None,
+        vdb_root: None,
+        hash_cache: None,
+        debug_filter: false,
+        check_vdb_freshness: false,
+        dump_vdb: false,
+        reduced_privileges: None,
+        fail_over: 0,
+        by_build_time: false,
+        options: estatus::CheckOptions::default(),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            parsed.paths.push(path::PathBuf::from(arg));
+            continue;
+        }
+        let (name, inline_value) = match arg.find('=') {
+            Some(index) => (arg[..index].to_string(), Some(arg[index + 1..].to_string())),
+            None => (arg.clone(), None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next())
+            .ok_or_else(|| format!("missing value for {}", name));
+        match name.as_str() {
+            "--format" => parsed.format = value()?.parse()?,
+            "--category" => parsed.category = Some(value()?),
+            "--vdb-url" => parsed.vdb_url = Some(value()?),
+            "--vdb-root" => parsed.vdb_root = Some(path::PathBuf::from(value()?)),
+            "--hash-cache" => parsed.hash_cache = Some(path::PathBuf::from(value()?)),
+            "--flag-world-writable" => parsed.options.flag_world_writable_orphans = true,
+            "--flag-future-mtime" => parsed.options.future_mtime_after = Some(
+                time::SystemTime::now().duration_since(time::UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs()
+            ),
+            "--map" => parsed.options.path_map.push(parse_mapping(&value()?)?),
+            "--offset-root" => parsed.options.offset_root = path::PathBuf::from(value()?),
+            "--eprefix" => parsed.options.eprefix = Some(path::PathBuf::from(value()?)),
+            "--debug-filter" => parsed.debug_filter = true,
+            "--check-vdb-freshness" => parsed.check_vdb_freshness = true,
+            "--dump-vdb" => parsed.dump_vdb = true,
+            "--by-build-time" => parsed.by_build_time = true,
+            "--fail-over" => {
+                let raw = value()?;
+                parsed.fail_over = raw.parse().map_err(|_| format!("invalid threshold \"{}\" for --fail-over", raw))?;
+            },
+            "--mtime-tolerance" => {
+                let raw = value()?;
+                parsed.options.mtime_tolerance_secs = raw.parse().map_err(|_| format!("invalid tolerance \"{}\" for --mtime-tolerance", raw))?;
+            },
+            "--max-depth" => {
+                let raw = value()?;
+                parsed.options.max_depth = Some(raw.parse().map_err(|_| format!("invalid depth \"{}\" for --max-depth", raw))?);
+            },
+            "--hash-buffer-size" => {
+                let raw = value()?;
+                let size = raw.parse().ok().filter(|size| *size > 0)
+                    .ok_or_else(|| format!("invalid size \"{}\" for --hash-buffer-size", raw))?;
+                parsed.options.hash_buffer_size = Some(size);
+            },
+            "--only" | "--status" => parsed.options.wanted_statuses = Some(
+                value()?.split(',').map(parse_status).collect::<Result<_, _>>()?
+            ),
+            "--quiet" | "--changed-only" => parsed.options.wanted_statuses = Some(
+                STATUS_NAMES.iter().filter(|name| **name != "aligned").map(|name| parse_status(name)).collect::<Result<_, _>>()?
+            ),
+            // The user is optional, so only given inline.
+            "--reduced-privileges" => parsed.reduced_privileges = Some(inline_value.clone().unwrap_or_else(|| UNPRIVILEGED_USER.to_string())),
+            "--skip-placeholder-hashes" => parsed.options.skip_placeholder_hashes = true,
+            "--executables-only" => parsed.options.executables_only = true,
+            "--regular-only" => parsed.options.regular_files_only = true,
+            "--resolve-symlinks" => parsed.options.resolve_symlinks = true,
+            "--continue-on-read-error" => parsed.options.continue_on_read_error = true,
+            "--config-protect" => parsed.options.config_protect.extend(
+                value()?.split_whitespace().map(path::PathBuf::from)
+            ),
+            "--ignore" => {
+                let raw = value()?;
+                let pattern = glob::Pattern::new(&raw).map_err(|e| format!("invalid pattern \"{}\" for --ignore: {}", raw, e))?;
+                parsed.options.ignore.push(pattern);
+            },
+            "--skip-fs-type" => {
+                let name = value()?;
+                let magic = estatus::fstype::magic(&name).ok_or_else(|| format!("unknown filesystem type \"{}\"", name))?;
+                parsed.options.skipped_fs_types.push(magic);
+            },
+            _ => return Err(format!("unknown option {}", name)),
+        }
+    }
+    if parsed.category.is_some() && !parsed.paths.is_empty() {
+        return Err("--category can't be combined with paths".to_string());
+    }
+    if parsed.category.is_some() && parsed.vdb_url.is_some() {
+        return Err("--category can't be combined with --vdb-url".to_string());
+    }
+    if parsed.dump_vdb && (!parsed.paths.is_empty() || parsed.category.is_some() || parsed.vdb_url.is_some()) {
+        return Err("--dump-vdb can't be combined with paths, --category or --vdb-url".to_string());
+    }
+    if parsed.vdb_root.is_some() && parsed.vdb_url.is_some() {
+        return Err("--vdb-root can't be combined with --vdb-url".to_string());
+    }
+    if parsed.by_build_time && parsed.vdb_url.is_some() {
+        return Err("--by-build-time can't be combined with --vdb-url".to_string());
+    }
+    if parsed.check_vdb_freshness && parsed.vdb_url.is_some() {
+        return Err("--check-vdb-freshness can't be combined with --vdb-url".to_string());
+    }
+    Ok(parsed)
+}
+
+/// Names of all statuses, as accepted by `--only`.
+const STATUS_NAMES: &[&str] = &["aligned", "touched", "edited", "changed", "absent", "orphan", "skipped", "broken", "unreadable"];
+
+/// Parse a status name, as in the text output but lowercase.
+fn parse_status(name: &str) -> Result<estatus::FileStatus, String> {
+    use estatus::FileStatus;
+    match name {
+        "aligned" => Ok(FileStatus::Aligned),
+        "touched" => Ok(FileStatus::Touched),
+        "edited" => Ok(FileStatus::Edited),
+        "changed" => Ok(FileStatus::Changed),
+        "absent" => Ok(FileStatus::Absent),
+        "orphan" => Ok(FileStatus::Orphan),
+        "skipped" => Ok(FileStatus::Skipped),
+        "broken" => Ok(FileStatus::Broken),
+        "unreadable" => Ok(FileStatus::Unreadable),
+        _ => Err(format!("invalid status \"{}\", expected one of: {}", name, STATUS_NAMES.join(", "))),
+    }
+}
+
+/// The VarDB folder to read: `--vdb-root` if set, else found below `$EROOT` or `$ROOT` as Portage does.
+fn vdb_root(flag: Option<path::PathBuf>, eroot: Option<ffi::OsString>, root: Option<ffi::OsString>) -> path::PathBuf {
+    const VDB_PATH: &str = "var/db/pkg";
+    if let Some(vdb_root) = flag {
+        return vdb_root;
+    }
+    match eroot.filter(|value| !value.is_empty()).or_else(|| root.filter(|value| !value.is_empty())) {
+        Some(root) => path::Path::new(&root).join(VDB_PATH),
+        None => path::Path::new("/").join(VDB_PATH),
+    }
+}
+
+#[cfg(feature = "http")]
+fn load_vdb_url(url: &str) -> Result<estatus::vardbapi::VarDB, estatus::Error> {
+    estatus::vardbapi::load_vdb_url(url).map_err(|e| estatus::Error::VarDBError { source: e })
+}
+
+#[cfg(not(feature = "http"))]
+fn load_vdb_url(url: &str) -> Result<estatus::vardbapi::VarDB, estatus::Error> {
+    fail(format!("{}: estatus was built without HTTP support (feature \"http\")", url))
+}
+
+/// The user `--reduced-privileges` switches to by default.
+const UNPRIVILEGED_USER: &str = "nobody";
+
+/// Run as `user`, with CAP_DAC_READ_SEARCH only: enough to read and traverse any file, but not to write.
+///
+/// A process already running as another user than root keeps its ids. The bounding set is cleared
+/// as well, when allowed, so that no other capability can be regained, e.g. through a setuid binary.
+#[cfg(feature = "caps")]
+fn reduce_privileges(user: &str) -> Result<(), String> {
+    use caps::{CapSet, Capability};
+
+    let cap = Capability::CAP_DAC_READ_SEARCH;
+    let cannot = |e: caps::errors::CapsError| format!("could not reduce privileges to {}: {}", cap, e);
+    if !caps::has_cap(None, CapSet::Permitted, cap).map_err(cannot)? {
+        return Err(format!("could not reduce privileges: {} isn't permitted", cap));
+    }
+    if caps::has_cap(None, CapSet::Effective, Capability::CAP_SETPCAP).map_err(cannot)? {
+        for other in caps::read(None, CapSet::Bounding).map_err(cannot)? {
+            if other != cap {
+                caps::drop(None, CapSet::Bounding, other).map_err(cannot)?;
+            }
+        }
+    }
+    // SAFETY: these calls have no memory safety requirements.
+    if unsafe { libc::getuid() == 0 || libc::geteuid() == 0 } {
+        switch_user(user)?;
+    }
+    let kept = std::iter::once(cap).collect();
+    // The effective set must stay a subset of the permitted one.
+    caps::set(None, CapSet::Effective, &kept).map_err(cannot)?;
+    caps::set(None, CapSet::Permitted, &kept).map_err(cannot)?;
+    caps::clear(None, CapSet::Inheritable).map_err(cannot)?;
+    caps::clear(None, CapSet::Ambient).map_err(cannot)?;
+    Ok(())
+}
+
+/// Drop the root ids and supplementary groups for those of `user`, keeping the permitted capabilities.
+#[cfg(feature = "caps")]
+fn switch_user(user: &str) -> Result<(), String> {
+    let cannot = |what: &str| format!("could not switch to user {}: {}: {}", user, what, std::io::Error::last_os_error());
+    let name = ffi::CString::new(user).map_err(|_| format!("invalid user name \"{}\"", user))?;
+    // SAFETY: `name` is a valid C string; the returned entry is copied before any other call.
+    let (uid, gid) = match unsafe { libc::getpwnam(name.as_ptr()).as_ref() } {
+        Some(entry) => (entry.pw_uid, entry.pw_gid),
+        None => return Err(format!("could not switch to user {}: no such user", user)),
+    };
+    if uid == 0 {
+        return Err(format!("could not switch to user {}: it is root", user));
+    }
+    caps::securebits::set_keepcaps(true).map_err(|e| format!("could not switch to user {}: {}", user, e))?;
+    // SAFETY: these calls have no memory safety requirements; an empty group list may be null.
+    unsafe {
+        if libc::setgroups(0, std::ptr::null()) != 0 {
+            return Err(cannot("setgroups"));
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(cannot("setgid"));
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(cannot("setuid"));
+        }
+    }
+    caps::securebits::set_keepcaps(false).map_err(|e| format!("could not switch to user {}: {}", user, e))
+}
+
+#[cfg(not(feature = "caps"))]
+fn reduce_privileges(_user: &str) -> Result<(), String> {
+    Err("estatus was built without capabilities support (feature \"caps\")".to_string())
+}
+
+/// Parse a `<recorded prefix>=<disk prefix>` path mapping.
+fn parse_mapping(raw: &str) -> Result<(path::PathBuf, path::PathBuf), String> {
+    match raw.find('=') {
+        Some(index) => Ok((raw[..index].into(), raw[index + 1..].into())),
+        None => Err(format!("invalid mapping \"{}\", expected <recorded prefix>=<disk prefix>", raw)),
+    }
+}
+
+fn print_filter_stats(stats: &estatus::vardbapi::FilterStats) {
+    eprintln!("debug: {} VarDB entries within the search paths, {} outside", stats.kept, stats.filtered);
+    for path in stats.sample.iter() {
+        eprintln!("debug: skipped {}", path.display());
+    }
+    if stats.filtered > stats.sample.len() {
+        eprintln!("debug: ... and {} more", stats.filtered - stats.sample.len());
+    }
+}
+
+/// Print all VarDB entries, one `<atom> <CONTENTS line>` per line, sorted by path.
+fn dump_vdb(vdb_root: &path::Path) -> Result<(), estatus::vardbapi::Error> {
+    let mut entries = estatus::vardbapi::parse_vdb(vdb_root)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by(|a, b| (&a.path, &a.atom).cmp(&(&b.path, &b.atom)));
+    for entry in entries {
+        println!("{} {}", entry.atom, entry);
+    }
+    Ok(())
+}
+
+fn print_metrics(summary: &estatus::Summary, duration: time::Duration) {
+    println!("estatus_aligned {}", summary.aligned);
+    println!("estatus_touched {}", summary.touched);
+    println!("estatus_edited {}", summary.edited);
+    println!("estatus_changed {}", summary.changed);
+    println!("estatus_absent {}", summary.absent);
+    println!("estatus_orphan {}", summary.orphan);
+    println!("estatus_orphan_bytes {}", summary.orphan_bytes);
+    println!("estatus_skipped {}", summary.skipped);
+    println!("estatus_broken {}", summary.broken);
+    println!("estatus_unreadable {}", summary.unreadable);
+    println!("estatus_files_total {}", summary.total());
+    if let Some(newer) = summary.newer_than_vdb {
+        println!("estatus_newer_than_vdb {}", newer);
+    }
+    println!("estatus_scan_duration_seconds {:.3}", duration.as_secs_f64());
+}
+
+/// Print a single `emerge` command covering all packages with non-aligned files.
+///
+/// Skipped files are left out, as re-emerging wouldn't make them verifiable.
+fn print_repair_plan(results: &estatus::ResultSet) {
+    let atoms: collections::BTreeSet<&str> = results.values()
+        .filter(|result| result.status != estatus::FileStatus::Aligned && result.status != estatus::FileStatus::Skipped)
+        .filter_map(|result| result.owner.as_deref())
+        .collect();
+    if atoms.is_empty() {
+        return;
+    }
+    let targets: Vec<String> = atoms.iter().map(|atom| format!("={}", atom)).collect();
+    println!("emerge --oneshot {}", targets.join(" "));
+}
+
+/// The exit status of a completed run: `EXIT_FINDINGS` with more than `threshold` serious findings, else 0.
+///
+/// Touched files, orphans and skipped files are never serious, see `Summary::serious`.
+fn exit_code(summary: &estatus::Summary, threshold: usize) -> i32 {
+    if summary.serious() > threshold {
+        EXIT_FINDINGS
+    } else {
+        0
+    }
+}
+
+/// Report an error preventing the run from completing, and exit.
+fn fail(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(EXIT_ERROR)
+}
 
 fn main() {
-    let paths: Vec<path::PathBuf> = env::args().skip(1).map(|arg| path::PathBuf::from(arg)).collect();
-    let results = estatus::statuses(paths, &path::PathBuf::from("/var/db/pkg"));
-    if let Err(e) = results {
-        eprintln!("{}", e);
+    let mut args = parse_args(env::args().skip(1)).unwrap_or_else(|e| fail(e));
+    if let Some(ref user) = args.reduced_privileges {
+        reduce_privileges(user).unwrap_or_else(|e| fail(e));
+    }
+    let start = time::Instant::now();
+    let vdb_root = vdb_root(args.vdb_root, env::var_os("EROOT"), env::var_os("ROOT"));
+    if args.dump_vdb {
+        dump_vdb(&vdb_root).unwrap_or_else(|e| fail(e));
         return;
     }
-    let results = results.unwrap();
-    for result in results.values() {
-        println!("{:?}: {}", result.status, result.path.display());
+    if let Some(ref cache_path) = args.hash_cache {
+        let cache = estatus::HashCache::load(cache_path).unwrap_or_else(|e| fail(e));
+        args.options.hash_cache = Some(sync::Arc::new(cache));
+    }
+    let (paths, options, debug_filter) = (args.paths, &args.options, args.debug_filter);
+    let results = if let Some(ref category) = args.category {
+        estatus::statuses_for_category(&vdb_root, category, options)
+    } else if let Some(ref url) = args.vdb_url {
+        load_vdb_url(url).and_then(|vdb| estatus::VarDb::from(vdb).statuses_with(paths, options))
+    } else {
+        estatus::statuses_with_stats(paths, &vdb_root, options).map(|(results, stats)| {
+            if debug_filter {
+                print_filter_stats(&stats);
+            }
+            results
+        })
+    };
+    let results = results.unwrap_or_else(|e| fail(e));
+    if let (Some(cache_path), Some(cache)) = (&args.hash_cache, &options.hash_cache) {
+        if let Err(e) = cache.save(cache_path) {
+            eprintln!("{}", e);
+        }
+    }
+
+    let summary = if args.check_vdb_freshness {
+        match estatus::vardbapi::newest_atom_mtime(&vdb_root) {
+            Ok(Some(vdb_mtime)) => estatus::summarize_against_vdb(&results, vdb_mtime),
+            Ok(None) => estatus::summarize(&results),
+            Err(e) => fail(e),
+        }
+    } else {
+        estatus::summarize(&results)
+    };
+    if summary.vdb_may_be_stale(STALE_VDB_THRESHOLD) {
+        eprintln!(
+            "warning: {} files were modified after the last package change; {} may not reflect this system",
+            summary.newer_than_vdb.unwrap_or_default(), vdb_root.display(),
+        );
+    }
+
+    match args.format {
+        Format::Text => {
+            let ordered = if args.by_build_time {
+                match estatus::vardbapi::build_times(&vdb_root) {
+                    Ok(build_times) => estatus::order_by_build_time(&results, &build_times),
+                    Err(e) => fail(e),
+                }
+            } else {
+                estatus::order_by_path(&results)
+            };
+            for result in ordered {
+                let mut suffix = result.owner.as_ref().map(|atom| format!(" ({})", atom)).unwrap_or_default();
+                if result.protected {
+                    suffix.push_str(" [protected]");
+                }
+                if let (estatus::FileStatus::Changed, Some(expected)) = (result.status, &result.expected_ftype) {
+                    suffix.push_str(&format!(" [expected {:?}, found {:?}]", expected, result.ftype));
+                }
+                if result.details.is_empty() {
+                    println!("{:?}: {}{}", result.status, result.path.display(), suffix);
+                } else {
+                    println!("{:?}: {}{} {:?}", result.status, result.path.display(), suffix, result.details);
+                }
+            }
+        },
+        Format::Metrics => print_metrics(&summary, start.elapsed()),
+        Format::Emerge => print_repair_plan(&results),
+        Format::Fingerprint => {
+            let fingerprint: Vec<String> = estatus::tree_fingerprint(&results).iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("{}", fingerprint.concat());
+        },
+        #[cfg(feature = "serde")]
+        Format::Json => println!("{}", estatus::to_json(&results)),
+    }
+
+    std::process::exit(exit_code(&summary, args.fail_over));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(raw: &[&str]) -> Result<Args, String> {
+        parse_args(raw.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_args_vdb_root() {
+        let parsed = args(&["/usr", "--vdb-root", "/mnt/gentoo/var/db/pkg", "/etc"]).unwrap();
+        assert_eq!(Some(path::PathBuf::from("/mnt/gentoo/var/db/pkg")), parsed.vdb_root);
+        assert_eq!(vec![path::PathBuf::from("/usr"), path::PathBuf::from("/etc")], parsed.paths);
+
+        let parsed = args(&["--vdb-root=/backup/pkg", "--flag-world-writable"]).unwrap();
+        assert_eq!(Some(path::PathBuf::from("/backup/pkg")), parsed.vdb_root);
+        assert!(parsed.options.flag_world_writable_orphans);
+
+        assert_eq!(None, args(&["/usr"]).unwrap().vdb_root);
+        assert!(args(&["--vdb-root"]).is_err());
+        assert!(args(&["--vdb-root", "/pkg", "--vdb-url", "https://example.org/base.txt"]).is_err());
+    }
+
+    #[test]
+    fn parse_args_only() {
+        let parsed = args(&["--only=edited,absent", "/usr"]).unwrap();
+        let wanted = [estatus::FileStatus::Edited, estatus::FileStatus::Absent].iter().copied().collect();
+        assert_eq!(Some(wanted), parsed.options.wanted_statuses);
+        assert!(args(&["--only", "edited,altered"]).is_err());
+        assert_eq!(args(&["--only=orphan"]).unwrap().options.wanted_statuses, args(&["--status", "orphan"]).unwrap().options.wanted_statuses);
+    }
+
+    #[test]
+    fn parse_args_quiet() {
+        use estatus::FileStatus;
+        let wanted = args(&["--quiet", "/usr"]).unwrap().options.wanted_statuses.unwrap();
+        assert_eq!(STATUS_NAMES.len() - 1, wanted.len());
+        assert!(!wanted.contains(&FileStatus::Aligned));
+        assert!(wanted.contains(&FileStatus::Touched) && wanted.contains(&FileStatus::Orphan));
+        assert_eq!(Some(wanted), args(&["--changed-only"]).unwrap().options.wanted_statuses);
+        assert_eq!(None, args(&["/usr"]).unwrap().options.wanted_statuses);
+    }
+
+    #[test]
+    fn parse_args_reduced_privileges() {
+        assert_eq!(None, args(&["/usr"]).unwrap().reduced_privileges);
+        let parsed = args(&["--reduced-privileges", "/usr"]).unwrap();
+        assert_eq!(Some(UNPRIVILEGED_USER.to_string()), parsed.reduced_privileges);
+        assert_eq!(vec![path::PathBuf::from("/usr")], parsed.paths);
+        assert_eq!(Some("portage".to_string()), args(&["--reduced-privileges=portage"]).unwrap().reduced_privileges);
+    }
+
+    /// Set for `reduced_privileges_child`, to the file it must fail to write.
+    #[cfg(feature = "caps")]
+    const PRIVILEGES_TEST_FILE: &str = "ESTATUS_TEST_PRIVILEGES_FILE";
+
+    /// Privileges can't be restored afterwards, so they are reduced in a child process running `reduced_privileges_child`.
+    #[cfg(feature = "caps")]
+    #[test]
+    fn reduced_privileges_deny_writes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // SAFETY: this call has no memory safety requirements.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("secret");
+        std::fs::write(&file, "Hello, world!").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let status = std::process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", "tests::reduced_privileges_child", "--ignored", "--test-threads=1"])
+            .env(PRIVILEGES_TEST_FILE, &file)
+            .status().unwrap();
+        assert!(status.success());
+        assert_eq!("Hello, world!", std::fs::read_to_string(&file).unwrap());
+    }
+
+    #[cfg(feature = "caps")]
+    #[test]
+    #[ignore = "run by reduced_privileges_deny_writes"]
+    fn reduced_privileges_child() {
+        let file = match env::var_os(PRIVILEGES_TEST_FILE) {
+            Some(file) => path::PathBuf::from(file),
+            None => return,
+        };
+        reduce_privileges(UNPRIVILEGED_USER).unwrap();
+        // SAFETY: this call has no memory safety requirements.
+        assert_ne!(0, unsafe { libc::geteuid() });
+        // Readable through CAP_DAC_READ_SEARCH, but not writable although owned by root.
+        assert_eq!("Hello, world!", std::fs::read_to_string(&file).unwrap());
+        let error = std::fs::OpenOptions::new().write(true).open(&file).unwrap_err();
+        assert_eq!(std::io::ErrorKind::PermissionDenied, error.kind());
+    }
+
+    #[test]
+    fn parse_args_max_depth() {
+        assert_eq!(Some(0), args(&["--max-depth=0"]).unwrap().options.max_depth);
+        assert_eq!(Some(3), args(&["--max-depth", "3", "/usr"]).unwrap().options.max_depth);
+        assert_eq!(None, args(&["/usr"]).unwrap().options.max_depth);
+        assert!(args(&["--max-depth=-1"]).is_err());
+    }
+
+    #[test]
+    fn parse_format() {
+        assert!(FORMAT_NAMES.iter().all(|name| name.parse::<Format>().is_ok()));
+        assert!("xml".parse::<Format>().unwrap_err().ends_with(&FORMAT_NAMES.join(", ")));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let summary = |edited, touched| estatus::Summary { aligned: 10, edited, touched, orphan: 3, ..Default::default() };
+        assert_eq!(0, exit_code(&summary(0, 2), 0));
+        assert_eq!(EXIT_FINDINGS, exit_code(&summary(1, 0), 0));
+        assert_eq!(0, exit_code(&summary(2, 0), 2));
+        assert_eq!(EXIT_FINDINGS, exit_code(&estatus::Summary { absent: 3, ..Default::default() }, 2));
+        assert_eq!(2, args(&["--fail-over=2"]).unwrap().fail_over);
+        assert_eq!(0, args(&[]).unwrap().fail_over);
+    }
+
+    #[test]
+    fn vdb_root_fallbacks() {
+        let var = |value: &str| Some(ffi::OsString::from(value));
+        assert_eq!(path::PathBuf::from("/var/db/pkg"), vdb_root(None, None, None));
+        assert_eq!(path::PathBuf::from("/mnt/gentoo/var/db/pkg"), vdb_root(None, None, var("/mnt/gentoo")));
+        assert_eq!(path::PathBuf::from("/prefix/var/db/pkg"), vdb_root(None, var("/prefix"), var("/mnt/gentoo")));
+        assert_eq!(path::PathBuf::from("/mnt/gentoo/var/db/pkg"), vdb_root(None, var(""), var("/mnt/gentoo")));
+        assert_eq!(path::PathBuf::from("/pkg"), vdb_root(Some("/pkg".into()), var("/prefix"), var("/mnt/gentoo")));
     }
 }
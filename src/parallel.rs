@@ -0,0 +1,35 @@
+//! Checks spread over a thread pool, for large trees on fast disks.
+
+use std::path;
+
+use rayon::prelude::*;
+
+use crate::{check_found, load_expectations, CheckOptions, Error, OrphanAction, ResultItem, ResultSet, SearchPaths, Walk};
+
+/// Same as `statuses`, hashing files on rayon's global thread pool.
+///
+/// Folders are still walked serially, then the files found are checked in parallel.
+/// Errors are thus reported in a different order: an unreadable folder takes precedence
+/// over an unreadable file anywhere, and all files are checked before failing.
+pub fn statuses_parallel(paths: impl IntoIterator<Item=path::PathBuf>, vdb_root: &path::Path, options: &CheckOptions) -> Result<ResultSet, Error> {
+    let paths_list: SearchPaths = paths.into_iter().collect();
+    let (expectations, _stats) = load_expectations(&paths_list, vdb_root, options)?;
+    let mut keep = |_: &path::Path| OrphanAction::Keep;
+    let mut walk = Walk::new(&expectations, paths_list, options, &mut keep);
+
+    let mut found = Vec::new();
+    while let Some(result) = walk.scan.next_found() {
+        let result = result.map(|file| found.push(file));
+        walk.tolerate(result)?;
+    }
+    let checked: Vec<Result<ResultItem, Error>> = found.par_iter()
+        .map(|file| check_found(&expectations, file, options))
+        .collect();
+    // Results keep the walk order: the first failing file is the one `statuses` would fail on.
+    for result in checked {
+        let result = result.map(|result| walk.insert(result));
+        walk.tolerate(result)?;
+    }
+    walk.add_absent()?;
+    Ok(walk.results)
+}
@@ -0,0 +1,95 @@
+//! Unix-specific extensions, with fallbacks for other platforms.
+//!
+//! estatus checks Unix systems. Elsewhere, these fallbacks only let the crate build,
+//! e.g. to parse a copied VarDB or inspect a manifest: file checks are approximate.
+
+#[cfg(unix)]
+pub(crate) use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+pub(crate) use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+#[cfg(not(unix))]
+use std::{ffi, fs, time};
+
+/// An `OsStr` from raw bytes, as read from a file; `None` if not valid on this platform.
+#[cfg(unix)]
+pub(crate) fn os_str_from_bytes(bytes: &[u8]) -> Option<&std::ffi::OsStr> {
+    Some(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_str_from_bytes(bytes: &[u8]) -> Option<&ffi::OsStr> {
+    std::str::from_utf8(bytes).ok().map(ffi::OsStr::new)
+}
+
+#[cfg(not(unix))]
+pub(crate) trait OsStrExt {
+    fn as_bytes(&self) -> &[u8];
+}
+
+#[cfg(not(unix))]
+impl OsStrExt for ffi::OsStr {
+    fn as_bytes(&self) -> &[u8] {
+        self.as_encoded_bytes()
+    }
+}
+
+/// Devices, FIFOs and sockets don't exist off Unix.
+#[cfg(not(unix))]
+pub(crate) trait FileTypeExt {
+    fn is_block_device(&self) -> bool { false }
+    fn is_char_device(&self) -> bool { false }
+    fn is_fifo(&self) -> bool { false }
+    fn is_socket(&self) -> bool { false }
+}
+
+#[cfg(not(unix))]
+impl FileTypeExt for fs::FileType {}
+
+/// The subset of `std::os::unix::fs::MetadataExt` used by estatus.
+///
+/// There are no owners or inodes to report: those are all 0.
+#[cfg(not(unix))]
+pub(crate) trait MetadataExt {
+    fn mode(&self) -> u32;
+    fn uid(&self) -> u32 { 0 }
+    fn gid(&self) -> u32 { 0 }
+    fn dev(&self) -> u64 { 0 }
+    fn ino(&self) -> u64 { 0 }
+    fn mtime(&self) -> i64;
+    fn mtime_nsec(&self) -> i64;
+    fn size(&self) -> u64;
+}
+
+#[cfg(not(unix))]
+impl MetadataExt for fs::Metadata {
+    /// A mode matching the file type, read-only if so.
+    fn mode(&self) -> u32 {
+        let file_type = self.file_type();
+        if file_type.is_symlink() {
+            0o120_777
+        } else if file_type.is_dir() {
+            0o040_755
+        } else if self.permissions().readonly() {
+            0o100_444
+        } else {
+            0o100_644
+        }
+    }
+
+    fn mtime(&self) -> i64 {
+        self.modified().ok()
+            .and_then(|mtime| mtime.duration_since(time::UNIX_EPOCH).ok())
+            .map_or(0, |mtime| mtime.as_secs() as i64)
+    }
+
+    fn mtime_nsec(&self) -> i64 {
+        self.modified().ok()
+            .and_then(|mtime| mtime.duration_since(time::UNIX_EPOCH).ok())
+            .map_or(0, |mtime| i64::from(mtime.subsec_nanos()))
+    }
+
+    fn size(&self) -> u64 {
+        self.len()
+    }
+}
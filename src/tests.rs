@@ -0,0 +1,2023 @@
+use super::*;
+
+use std::ffi::OsString;
+use std::os::unix::fs::PermissionsExt;
+
+/// Create an atom folder holding the given CONTENTS lines.
+fn write_atom(vdb_root: &path::Path, atom: &str, lines: &[String]) {
+    let atom_dir = vdb_root.join(atom);
+    fs::create_dir_all(&atom_dir).unwrap();
+    fs::write(atom_dir.join("CONTENTS"), lines.join("\n") + "\n").unwrap();
+}
+
+/// Build the CONTENTS line of an `obj` entry matching the on-disk content and mtime.
+fn obj_line(path: &path::Path) -> String {
+    let hash: String = compute_md5(path, DEFAULT_HASH_BUFFER_SIZE).unwrap().iter().map(|byte| format!("{:02x}", byte)).collect();
+    let mtime = fs::metadata(path).unwrap()
+        .modified().unwrap()
+        .duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    format!("obj {} {} {}", path.display(), hash, mtime)
+}
+
+/// Build the expected metadata for a regular file, matching its on-disk content and mtime.
+fn regular_metadata(path: &path::Path, xattrs: Option<vardbapi::Xattrs>) -> vardbapi::FileMetadata {
+    let mtime = fs::metadata(path).unwrap()
+        .modified().unwrap()
+        .duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    vardbapi::FileMetadata::Regular {
+        mtime: Some(mtime),
+        hash: vardbapi::FileHash::MD5(compute_md5(path, DEFAULT_HASH_BUFFER_SIZE).unwrap()),
+        xattrs,
+    }
+}
+
+#[test]
+fn check_file_xattrs_aligned() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    xattr::set(&path, "user.estatus", b"1").unwrap();
+
+    let recorded: vardbapi::Xattrs = vec![(OsString::from("user.estatus"), b"1".to_vec())].into_iter().collect();
+    let expected = regular_metadata(&path, Some(recorded));
+    let options = CheckOptions { check_xattrs: true, ..Default::default() };
+    let result = compare(&path, &expected, &options).unwrap();
+    assert_eq!(FileStatus::Aligned, result.status);
+    assert_eq!(Vec::<Detail>::new(), result.details);
+}
+
+#[test]
+fn check_file_xattrs_drift() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    xattr::set(&path, "user.changed", b"2").unwrap();
+    xattr::set(&path, "user.added", b"3").unwrap();
+
+    let recorded: vardbapi::Xattrs = vec![
+        (OsString::from("user.changed"), b"1".to_vec()),
+        (OsString::from("user.removed"), b"1".to_vec()),
+    ].into_iter().collect();
+    let expected = regular_metadata(&path, Some(recorded));
+    let options = CheckOptions { check_xattrs: true, ..Default::default() };
+    let result = compare(&path, &expected, &options).unwrap();
+    assert_eq!(FileStatus::Aligned, result.status);
+    assert_eq!(
+        vec![Detail::XattrDrift { names: vec!["user.added".into(), "user.changed".into(), "user.removed".into()] }],
+        result.details,
+    );
+}
+
+#[test]
+fn check_file_xattrs_not_recorded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    xattr::set(&path, "user.estatus", b"1").unwrap();
+
+    // No recorded xattrs: the check is disabled
+    let expected = regular_metadata(&path, None);
+    let options = CheckOptions { check_xattrs: true, ..Default::default() };
+    let result = compare(&path, &expected, &options).unwrap();
+    assert_eq!(Vec::<Detail>::new(), result.details);
+}
+
+/// An inode of a type unknown to the standard library.
+struct Door;
+
+impl FileKind for Door {
+    fn is_block_device(&self) -> bool { false }
+    fn is_char_device(&self) -> bool { false }
+    fn is_fifo(&self) -> bool { false }
+    fn is_socket(&self) -> bool { false }
+    fn is_dir(&self) -> bool { false }
+    fn is_symlink(&self) -> bool { false }
+    fn is_file(&self) -> bool { false }
+}
+
+#[test]
+fn file_type_unknown() {
+    assert_eq!(None, file_type_of(&Door));
+
+    let dir = tempfile::tempdir().unwrap();
+    assert_eq!(Ok(FileType::DIR), FileType::try_from(fs::symlink_metadata(dir.path()).unwrap().file_type()));
+    let error = Error::UnknownFileType { path: dir.path().join("door") };
+    assert_eq!(format!("{}: unknown file type", dir.path().join("door").display()), error.to_string());
+}
+
+/// A result for a regular file, with no other details.
+fn result(path: &str, status: FileStatus) -> ResultItem {
+    ResultItem {
+        path: path.into(),
+        ftype: FileType::REG,
+        status,
+        details: Vec::new(),
+        mtime: Some(100),
+        owner: None,
+        recorded_hash: None,
+        uid: None,
+        gid: None,
+        mode: None,
+        protected: false,
+        expected_mtime: None,
+        hash_mismatch: None,
+        expected_ftype: None,
+        size: None,
+        mtime_drift: None,
+    }
+}
+
+#[test]
+fn summarize_counts() {
+    let mut results = ResultSet::new();
+    for (name, status) in [
+        ("/a", FileStatus::Aligned),
+        ("/b", FileStatus::Aligned),
+        ("/c", FileStatus::Edited),
+        ("/d", FileStatus::Orphan),
+    ] {
+        results.insert(name.into(), result(name, status));
+    }
+    let summary = summarize(&results);
+    assert_eq!(Summary { aligned: 2, edited: 1, orphan: 1, ..Default::default() }, summary);
+    assert_eq!(4, summary.total());
+    assert_eq!(1, summary.serious());
+}
+
+#[test]
+fn summary_display() {
+    let mut results = ResultSet::new();
+    for (name, status) in [
+        ("/a", FileStatus::Aligned),
+        ("/b", FileStatus::Aligned),
+        ("/c", FileStatus::Touched),
+        ("/d", FileStatus::Edited),
+        ("/e", FileStatus::Changed),
+        ("/f", FileStatus::Absent),
+        ("/g", FileStatus::Absent),
+        ("/h", FileStatus::Orphan),
+        ("/i", FileStatus::Broken),
+    ] {
+        results.insert(name.into(), result(name, status));
+    }
+    let summary = summarize(&results);
+    assert_eq!(Summary { aligned: 2, touched: 1, edited: 1, changed: 1, absent: 2, orphan: 1, broken: 1, ..Default::default() }, summary);
+    assert_eq!("9 files: 2 aligned, 1 touched, 1 edited, 1 changed, 2 absent, 1 orphan, 1 broken", summary.to_string());
+    assert_eq!("0 files: 0 aligned, 0 touched, 0 edited, 0 changed, 0 absent, 0 orphan", Summary::default().to_string());
+}
+
+#[test]
+fn order_by_build_time_newest_first() {
+    let mut results = ResultSet::new();
+    for (name, owner) in [
+        ("/a", None),
+        ("/b", Some("app-misc/old-1.0")),
+        ("/c", Some("app-misc/new-1.0")),
+        ("/d", Some("app-misc/unknown-1.0")),
+        ("/e", Some("app-misc/new-1.0")),
+    ] {
+        let owner = owner.map(String::from);
+        results.insert(name.into(), ResultItem { owner, ..result(name, FileStatus::Edited) });
+    }
+    let build_times = vec![("app-misc/old-1.0".to_string(), 1000), ("app-misc/new-1.0".to_string(), 2000)].into_iter().collect();
+
+    let ordered: Vec<&path::Path> = order_by_build_time(&results, &build_times).iter().map(|result| result.path.as_path()).collect();
+    assert_eq!(vec![path::Path::new("/c"), "/e".as_ref(), "/b".as_ref(), "/d".as_ref(), "/a".as_ref()], ordered);
+}
+
+#[test]
+fn tree_fingerprint_stable() {
+    let results = |status: FileStatus| {
+        let mut results = ResultSet::new();
+        for (name, status) in [("/a", FileStatus::Aligned), ("/b", status)] {
+            let recorded_hash = Some(vardbapi::FileHash::MD5([1; 16]));
+            results.insert(name.into(), ResultItem { recorded_hash, ..result(name, status) });
+        }
+        results
+    };
+    assert_eq!(tree_fingerprint(&results(FileStatus::Aligned)), tree_fingerprint(&results(FileStatus::Aligned)));
+    assert_ne!(tree_fingerprint(&results(FileStatus::Aligned)), tree_fingerprint(&results(FileStatus::Edited)));
+    assert_ne!(tree_fingerprint(&results(FileStatus::Aligned)), tree_fingerprint(&ResultSet::new()));
+}
+
+#[test]
+fn affected_packages_dedup() {
+    let mut results = ResultSet::new();
+    for (name, status, owner) in [
+        ("/a", FileStatus::Aligned, Some("app-misc/foo-1.0")),
+        ("/b", FileStatus::Aligned, Some("app-misc/bar-1.0")),
+        ("/c", FileStatus::Edited, Some("app-misc/bar-1.0")),
+        ("/d", FileStatus::Absent, Some("app-misc/bar-1.0")),
+        ("/e", FileStatus::Orphan, None),
+    ] {
+        let owner = owner.map(String::from);
+        results.insert(name.into(), ResultItem { owner, ..result(name, status) });
+    }
+    let expected: collections::BTreeSet<String> = vec!["app-misc/bar-1.0".to_string()].into_iter().collect();
+    assert_eq!(expected, affected_packages(&results));
+    assert!(affected_packages(&ResultSet::new()).is_empty());
+}
+
+#[test]
+fn diff_transitions() {
+    let results = |statuses: &[(&str, FileStatus)]| {
+        let mut results = ResultSet::new();
+        for (name, status) in statuses.iter().copied() {
+            results.insert(name.into(), result(name, status));
+        }
+        results
+    };
+    let old = results(&[
+        ("/same", FileStatus::Aligned),
+        ("/edited", FileStatus::Aligned),
+        ("/fixed", FileStatus::Edited),
+        ("/removed", FileStatus::Orphan),
+        ("/still", FileStatus::Edited),
+    ]);
+    let new = results(&[
+        ("/same", FileStatus::Aligned),
+        ("/edited", FileStatus::Edited),
+        ("/fixed", FileStatus::Aligned),
+        ("/added", FileStatus::Orphan),
+        ("/still", FileStatus::Edited),
+    ]);
+
+    let changes = diff(&old, &new);
+    assert_eq!(vec![path::PathBuf::from("/added")], changes.added);
+    assert_eq!(vec![path::PathBuf::from("/removed")], changes.removed);
+    assert_eq!(vec![
+        StatusChange { path: "/edited".into(), before: FileStatus::Aligned, after: FileStatus::Edited },
+        StatusChange { path: "/fixed".into(), before: FileStatus::Edited, after: FileStatus::Aligned },
+    ], changes.changed);
+    assert!(!changes.is_empty());
+
+    assert!(diff(&old, &old).is_empty());
+    assert_eq!(diff(&old, &new).added, diff(&new, &old).removed);
+}
+
+#[test]
+fn per_package_report_counts() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let present = root.join("present");
+    fs::write(&present, "Hello, world!").unwrap();
+    let missing = root.join("missing");
+    let other = root.join("other");
+    fs::write(&other, "Hello, world!").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        obj_line(&present),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", missing.display()),
+    ]);
+    write_atom(&vdb_root, "app-misc/bar-1.0", &[obj_line(&other), "dir /elsewhere".to_string()]);
+
+    let vdb = vardbapi::get_vdb(&vdb_root, &vec!["/".into()]).unwrap();
+    let mut results = statuses_for_category(&vdb_root, "app-misc", &CheckOptions::default()).unwrap();
+    // As if outside of the checked paths
+    results.remove(path::Path::new("/elsewhere"));
+
+    assert_eq!(vec![
+        PackageReport { atom: "app-misc/bar-1.0".into(), recorded: 2, summary: Summary { aligned: 1, ..Default::default() } },
+        PackageReport { atom: "app-misc/foo-1.0".into(), recorded: 2, summary: Summary { aligned: 1, absent: 1, ..Default::default() } },
+    ], per_package_report(&results, &vdb));
+}
+
+#[test]
+fn summarize_against_vdb_counts_newer_files() {
+    let mut results = ResultSet::new();
+    for (name, status, mtime) in [
+        ("/a", FileStatus::Aligned, Some(100)),
+        ("/b", FileStatus::Orphan, Some(300)),
+        ("/c", FileStatus::Edited, Some(250)),
+        ("/d", FileStatus::Absent, None),
+    ] {
+        results.insert(name.into(), ResultItem { mtime, ..result(name, status) });
+    }
+    let summary = summarize_against_vdb(&results, 200);
+    assert_eq!(Some(2), summary.newer_than_vdb);
+    assert!(summary.vdb_may_be_stale(2));
+    assert!(!summary.vdb_may_be_stale(3));
+    assert!(!summarize(&results).vdb_may_be_stale(0));
+}
+
+/// A VarDB with two versions of `dev-lang/foo`, and an unrelated `dev-lang/foo-bar`.
+fn atoms_fixture(dir: &path::Path) -> (path::PathBuf, path::PathBuf, path::PathBuf) {
+    let vdb_root = dir.join("vdb");
+    let old = dir.join("old");
+    let new = dir.join("new");
+    fs::write(&new, "Hello, world!").unwrap();
+    write_atom(&vdb_root, "dev-lang/foo-1.0", &[
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", old.display()),
+    ]);
+    write_atom(&vdb_root, "dev-lang/foo-2.0", &[obj_line(&new)]);
+    write_atom(&vdb_root, "dev-lang/foo-bar-1.0", &[
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", dir.join("bar").display()),
+    ]);
+    (vdb_root, old, new)
+}
+
+#[test]
+fn statuses_for_atom_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let (vdb_root, _old, new) = atoms_fixture(dir.path());
+
+    let results = statuses_for_atom(&vdb_root, "dev-lang/foo-2.0", &CheckOptions::default()).unwrap();
+    assert_eq!(1, results.len());
+    assert_eq!(FileStatus::Aligned, results[&new].status);
+    assert_eq!(Some("dev-lang/foo-2.0"), results[&new].owner.as_deref());
+
+    let result = statuses_for_atom(&vdb_root, "dev-lang/foo-3.0", &CheckOptions::default());
+    assert!(matches!(result, Err(Error::VarDBError { source: vardbapi::Error::AtomNotFound { .. } })));
+}
+
+#[test]
+fn statuses_for_atom_any_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let (vdb_root, old, new) = atoms_fixture(dir.path());
+
+    let results = statuses_for_atom(&vdb_root, "dev-lang/foo", &CheckOptions::default()).unwrap();
+    assert_eq!(2, results.len());
+    assert_eq!(FileStatus::Absent, results[&old].status);
+    assert_eq!(Some("dev-lang/foo-1.0"), results[&old].owner.as_deref());
+    assert_eq!(FileStatus::Aligned, results[&new].status);
+
+    let result = statuses_for_atom(&vdb_root, "dev-lang/fo", &CheckOptions::default());
+    assert!(matches!(result, Err(Error::VarDBError { source: vardbapi::Error::AtomNotFound { .. } })));
+}
+
+#[test]
+fn statuses_for_category_checks_recorded_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let present = root.join("present");
+    fs::write(&present, "Hello, world!").unwrap();
+    let missing = root.join("missing");
+    let other = root.join("other");
+    fs::write(&other, "Hello, world!").unwrap();
+
+    write_atom(&vdb_root, "dev-python/foo-1.0", &[
+        format!("dir {}", root.display()),
+        obj_line(&present),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", missing.display()),
+    ]);
+    write_atom(&vdb_root, "dev-lang/bar-1.0", &[obj_line(&other)]);
+
+    let results = statuses_for_category(&vdb_root, "dev-python", &CheckOptions::default()).unwrap();
+    assert_eq!(3, results.len());
+    assert_eq!(FileStatus::Aligned, results[&root].status);
+    assert_eq!(FileType::DIR, results[&root].ftype);
+    assert_eq!(FileStatus::Aligned, results[&present].status);
+    assert_eq!(FileStatus::Absent, results[&missing].status);
+    assert_eq!(FileType::REG, results[&missing].ftype);
+    assert_eq!(Some("dev-python/foo-1.0"), results[&missing].owner.as_deref());
+}
+
+#[test]
+fn statuses_reports_owner() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let owned = root.join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let orphan = root.join("orphan");
+    fs::write(&orphan, "Hello, world!").unwrap();
+    write_atom(&vdb_root, "dev-lang/rust-1.41.1", &[obj_line(&owned)]);
+
+    let results = statuses(vec![root], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(Some("dev-lang/rust-1.41.1"), results[&owned].owner.as_deref());
+    assert_eq!(None, results[&orphan].owner);
+}
+
+#[test]
+fn statuses_world_writable_orphans() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    fs::create_dir(&vdb_root).unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let writable = root.join("writable");
+    fs::write(&writable, "Hello, world!").unwrap();
+    fs::set_permissions(&writable, fs::Permissions::from_mode(0o666)).unwrap();
+    let private = root.join("private");
+    fs::write(&private, "Hello, world!").unwrap();
+    fs::set_permissions(&private, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let options = CheckOptions { flag_world_writable_orphans: true, ..Default::default() };
+    let results = statuses(vec![root], &vdb_root, &options).unwrap();
+    assert_eq!(FileStatus::Orphan, results[&writable].status);
+    assert_eq!(vec![Detail::WorldWritable], results[&writable].details);
+    assert_eq!(FileStatus::Orphan, results[&private].status);
+    assert_eq!(Vec::<Detail>::new(), results[&private].details);
+}
+
+#[test]
+fn check_path_future_mtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    fs::File::options().write(true).open(&path).unwrap()
+        .set_modified(time::UNIX_EPOCH + time::Duration::from_secs(2000)).unwrap();
+    let expected = regular_metadata(&path, None);
+
+    let options = CheckOptions { future_mtime_after: Some(1000), ..Default::default() };
+    assert_eq!(vec![Detail::FutureMTime], compare(&path, &expected, &options).unwrap().details);
+    let options = CheckOptions { future_mtime_after: Some(3000), ..Default::default() };
+    assert_eq!(Vec::<Detail>::new(), compare(&path, &expected, &options).unwrap().details);
+}
+
+#[test]
+fn check_path_placeholder_hash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let expected = vardbapi::FileMetadata::Regular {
+        mtime: Some(0),
+        hash: vardbapi::FileHash::MD5([0; 16]),
+        xattrs: None,
+    };
+
+    assert_eq!(FileStatus::Edited, compare(&path, &expected, &CheckOptions::default()).unwrap().status);
+    let options = CheckOptions { skip_placeholder_hashes: true, ..Default::default() };
+    assert_eq!(FileStatus::Skipped, compare(&path, &expected, &options).unwrap().status);
+}
+
+#[test]
+fn check_path_sha256() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let expected = |hash| vardbapi::FileMetadata::Regular { mtime: Some(0), hash, xattrs: None };
+
+    let matching = expected(vardbapi::FileHash::SHA256(compute_sha256(&path, DEFAULT_HASH_BUFFER_SIZE).unwrap()));
+    assert_eq!(FileStatus::Touched, compare(&path, &matching, &CheckOptions::default()).unwrap().status);
+    let mismatching = expected(vardbapi::FileHash::SHA256([1; 32]));
+    assert_eq!(FileStatus::Edited, compare(&path, &mismatching, &CheckOptions::default()).unwrap().status);
+}
+
+#[test]
+fn check_path_blake2b() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let expected = |hash| vardbapi::FileMetadata::Regular { mtime: Some(0), hash, xattrs: None };
+    // echo -n 'Hello, world!' | b2sum -l 256
+    let digest = vec![
+        181, 218, 68, 28, 254, 114, 174, 4, 46, 244, 210, 177, 119, 66, 144, 127,
+        103, 93, 228, 218, 87, 70, 45, 76, 54, 9, 194, 226, 237, 117, 89, 112,
+    ];
+
+    let matching = compare(&path, &expected(vardbapi::FileHash::Blake2b(digest)), &CheckOptions::default());
+    let mismatching = compare(&path, &expected(vardbapi::FileHash::Blake2b(vec![1; 64])), &CheckOptions::default());
+    if cfg!(feature = "blake2") {
+        assert_eq!(FileStatus::Touched, matching.unwrap().status);
+        assert_eq!(FileStatus::Edited, mismatching.unwrap().status);
+    } else {
+        assert!(matches!(matching, Err(Error::UnsupportedHash { .. })));
+    }
+}
+
+#[test]
+fn compute_md5_buffer_sizes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("large");
+    let content: Vec<u8> = (0..3 * 1024 * 1024 + 123).map(|i| (i % 251) as u8).collect();
+    fs::write(&path, &content).unwrap();
+    let expected = compute_md5_of(&content);
+
+    for buffer_size in [4097, DEFAULT_HASH_BUFFER_SIZE, 8 * 1024 * 1024] {
+        assert_eq!(expected, compute_md5(&path, buffer_size).unwrap(), "buffer of {} bytes", buffer_size);
+    }
+    let recorded = vardbapi::FileMetadata::Regular { mtime: Some(0), hash: vardbapi::FileHash::MD5(expected), xattrs: None };
+    let options = CheckOptions { hash_buffer_size: Some(4097), ..CheckOptions::default() };
+    assert_eq!(FileStatus::Touched, compare(&path, &recorded, &options).unwrap().status);
+}
+
+/// Create a FIFO at `path`, as `mkfifo` does.
+fn mkfifo(path: &path::Path) {
+    let raw = ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+    // SAFETY: `raw` is a valid C string.
+    assert_eq!(0, unsafe { libc::mkfifo(raw.as_ptr(), 0o644) });
+}
+
+#[test]
+fn check_path_fifo() {
+    let dir = tempfile::tempdir().unwrap();
+    let fifo = dir.path().join("fifo");
+    mkfifo(&fifo);
+    let regular = dir.path().join("regular");
+    fs::write(&regular, "Hello, world!").unwrap();
+
+    let result = compare(&fifo, &vardbapi::FileMetadata::Fifo, &CheckOptions::default()).unwrap();
+    assert_eq!((FileType::FIFO, FileStatus::Aligned), (result.ftype, result.status));
+    let result = compare(&regular, &vardbapi::FileMetadata::Fifo, &CheckOptions::default()).unwrap();
+    assert_eq!((FileType::REG, FileStatus::Changed), (result.ftype, result.status));
+    let result = compare(&dir.path().join("missing"), &vardbapi::FileMetadata::Fifo, &CheckOptions::default()).unwrap();
+    assert_eq!((FileType::FIFO, FileStatus::Absent), (result.ftype, result.status));
+}
+
+#[test]
+fn check_path_device() {
+    let dir = tempfile::tempdir().unwrap();
+    let fifo = dir.path().join("fifo");
+    mkfifo(&fifo);
+
+    // Major and minor numbers aren't recorded: any character or block device matches.
+    let result = compare(path::Path::new("/dev/null"), &vardbapi::FileMetadata::Device, &CheckOptions::default()).unwrap();
+    assert_eq!((FileType::CHR, FileStatus::Aligned), (result.ftype, result.status));
+    let result = compare(&fifo, &vardbapi::FileMetadata::Device, &CheckOptions::default()).unwrap();
+    assert_eq!((FileType::FIFO, FileStatus::Changed), (result.ftype, result.status));
+}
+
+#[test]
+fn check_path_executables_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("script");
+    fs::write(&script, "#!/bin/sh").unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let data = dir.path().join("data");
+    fs::write(&data, "Hello, world!").unwrap();
+    fs::set_permissions(&data, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let options = CheckOptions { executables_only: true, ..Default::default() };
+    assert_eq!(FileStatus::Aligned, compare(&script, &regular_metadata(&script, None), &options).unwrap().status);
+    assert_eq!(FileStatus::Skipped, compare(&data, &regular_metadata(&data, None), &options).unwrap().status);
+}
+
+#[test]
+fn statuses_sockets() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    // Portage never records sockets: they can only be orphans, or replace another file type.
+    let orphan = root.join("orphan.sock");
+    let _orphan = std::os::unix::net::UnixListener::bind(&orphan).unwrap();
+    let replaced = root.join("replaced.sock");
+    let _replaced = std::os::unix::net::UnixListener::bind(&replaced).unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", replaced.display())]);
+
+    let results = statuses(vec![root], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(FileType::SOCK, results[&orphan].ftype);
+    assert_eq!(FileStatus::Orphan, results[&orphan].status);
+    assert_eq!(FileType::SOCK, results[&replaced].ftype);
+    assert_eq!(FileStatus::Changed, results[&replaced].status);
+}
+
+#[test]
+fn statuses_symlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("target")).unwrap();
+    fs::write(root.join("target/file"), "Hello, world!").unwrap();
+    let link_mtime = |path: &path::Path| fs::symlink_metadata(path).unwrap()
+        .modified().unwrap()
+        .duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    let correct = root.join("correct");
+    std::os::unix::fs::symlink("target", &correct).unwrap();
+    let touched = root.join("touched");
+    std::os::unix::fs::symlink("target", &touched).unwrap();
+    let repointed = root.join("repointed");
+    std::os::unix::fs::symlink("elsewhere", &repointed).unwrap();
+    let replaced = root.join("replaced");
+    fs::write(&replaced, "Hello, world!").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("sym {} -> target {}", correct.display(), link_mtime(&correct)),
+        format!("sym {} -> target {}", touched.display(), link_mtime(&touched) - 1),
+        format!("sym {} -> target {}", repointed.display(), link_mtime(&repointed)),
+        format!("sym {} -> target 1586621688", replaced.display()),
+    ]);
+
+    let results = statuses(vec![root], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(FileStatus::Aligned, results[&correct].status);
+    assert_eq!(FileStatus::Touched, results[&touched].status);
+    assert_eq!(FileStatus::Edited, results[&repointed].status);
+    assert_eq!(FileStatus::Changed, results[&replaced].status);
+    // Links to a folder aren't walked.
+    assert!(!results.contains_key(&correct.join("file")));
+}
+
+#[test]
+fn statuses_symlinks_with_separator() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+    let mtime = |path: &path::Path| fs::symlink_metadata(path).unwrap()
+        .modified().unwrap()
+        .duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    // Both lines read `<root>/<name> -> <dest> -> <rest>`, but only one link name holds ` -> `.
+    let in_dest = root.join("rustc");
+    std::os::unix::fs::symlink("rustc -> 1.41.1", &in_dest).unwrap();
+    let in_path = root.join("cargo -> stable");
+    std::os::unix::fs::symlink("cargo-1.41.1", &in_path).unwrap();
+    write_atom(&vdb_root, "dev-lang/rust-1.41.1", &[
+        format!("sym {} -> rustc -> 1.41.1 {}", in_dest.display(), mtime(&in_dest)),
+        format!("sym {} -> cargo-1.41.1 {}", in_path.display(), mtime(&in_path)),
+    ]);
+
+    let results = statuses(vec![root.clone()], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(FileStatus::Aligned, results[&in_dest].status);
+    assert_eq!(FileStatus::Aligned, results[&in_path].status);
+    assert!(!results.contains_key(&root.join("cargo")));
+
+    // Same results against an already loaded VarDB.
+    let vdb = VarDb::load(&vdb_root).unwrap();
+    assert_eq!(results, vdb.statuses_with(vec![root], &CheckOptions::default()).unwrap());
+}
+
+#[test]
+fn status_iter_streams_results() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let owned = root.join("sub/owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let orphan = root.join("orphan");
+    fs::write(&orphan, "Hello, world!").unwrap();
+    let link = root.join("link");
+    std::os::unix::fs::symlink("sub", &link).unwrap();
+    let missing = root.join("missing");
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        obj_line(&owned),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", missing.display()),
+    ]);
+
+    let options = CheckOptions::default();
+    let items: Vec<_> = status_iter(vec![dir.path().join("unreadable"), root.clone()], &vdb_root, &options).unwrap().collect();
+    assert!(matches!(items[0], Err(Error::DirUnreadable { .. })));
+    let streamed: ResultSet = items.into_iter().skip(1)
+        .map(|item| item.map(|item| (item.path.clone(), item)))
+        .collect::<Result<_, _>>()
+        .unwrap();
+    // The symlinked folder isn't walked.
+    assert_eq!(4, streamed.len());
+    assert_eq!(FileType::LNK, streamed[&link].ftype);
+    assert_eq!(FileStatus::Absent, streamed[&missing].status);
+    assert_eq!(statuses(vec![root], &vdb_root, &options).unwrap(), streamed);
+}
+
+#[test]
+fn statuses_resolve_symlinks() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    fs::write(root.join("target"), "Hello, world!").unwrap();
+    let symlink = |dest: &str, name: &str| {
+        let link = root.join(name);
+        std::os::unix::fs::symlink(dest, &link).unwrap();
+        link
+    };
+    // valid -> middle -> target
+    symlink("target", "middle");
+    let valid = symlink("middle", "valid");
+    let dangling = symlink("nowhere", "dangling");
+    // deep -> deep0 -> ... -> deep40 -> target: more links than followed.
+    symlink("target", &format!("deep{}", MAX_SYMLINK_DEPTH));
+    for i in (0..MAX_SYMLINK_DEPTH).rev() {
+        symlink(&format!("deep{}", i + 1), &format!("deep{}", i));
+    }
+    let deep = symlink("deep0", "deep");
+    let mut lines = Vec::new();
+    for (link, dest) in [(&valid, "middle"), (&dangling, "nowhere"), (&deep, "deep0")] {
+        let mtime = fs::symlink_metadata(link).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+        lines.push(format!("sym {} -> {} {}", link.display(), dest, mtime));
+    }
+    write_atom(&vdb_root, "app-misc/foo-1.0", &lines);
+
+    let results = statuses(vec![root.clone()], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(FileStatus::Aligned, results[&dangling].status);
+    let options = CheckOptions { resolve_symlinks: true, ..Default::default() };
+    let results = statuses(vec![root], &vdb_root, &options).unwrap();
+    assert_eq!(FileStatus::Aligned, results[&valid].status);
+    assert_eq!(FileStatus::Broken, results[&dangling].status);
+    assert_eq!(FileStatus::Broken, results[&deep].status);
+    assert_eq!(2, summarize(&results).broken);
+}
+
+#[test]
+fn statuses_dangling_symlinks_walked() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    // Walking reads the links themselves: their missing targets don't stop the scan.
+    let owned = root.join("sub").join("owned");
+    std::os::unix::fs::symlink("nowhere", &owned).unwrap();
+    let orphan = root.join("sub").join("orphan");
+    std::os::unix::fs::symlink("/nonexistent/target", &orphan).unwrap();
+    let mtime = fs::symlink_metadata(&owned).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("dir {}", root.join("sub").display()),
+        format!("sym {} -> nowhere {}", owned.display(), mtime),
+    ]);
+
+    let options = CheckOptions { resolve_symlinks: true, ..Default::default() };
+    let results = statuses(vec![root], &vdb_root, &options).unwrap();
+    assert_eq!(FileStatus::Broken, results[&owned].status);
+    assert_eq!(FileStatus::Orphan, results[&orphan].status);
+    assert_eq!(FileType::LNK, results[&orphan].ftype);
+}
+
+#[test]
+fn statuses_symlink_loop() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    fs::create_dir(&vdb_root).unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let up = root.join("sub/up");
+    std::os::unix::fs::symlink("..", &up).unwrap();
+    let self_link = root.join("self");
+    std::os::unix::fs::symlink("self", &self_link).unwrap();
+
+    let results = statuses(vec![root], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(2, results.len());
+    assert_eq!(FileType::LNK, results[&up].ftype);
+    assert_eq!(FileType::LNK, results[&self_link].ftype);
+}
+
+#[test]
+fn statuses_recorded_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    let kept = root.join("kept");
+    fs::create_dir_all(&kept).unwrap();
+    let replaced = root.join("replaced");
+    fs::write(&replaced, "Hello, world!").unwrap();
+    let replacing = root.join("replacing");
+    fs::create_dir(&replacing).unwrap();
+    fs::create_dir(root.join("unowned")).unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("dir {}", kept.display()),
+        format!("dir {}", replaced.display()),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", replacing.display()),
+    ]);
+
+    let results = statuses(vec![root], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(3, results.len());
+    assert_eq!(FileStatus::Aligned, results[&kept].status);
+    assert_eq!(Some("app-misc/foo-1.0"), results[&kept].owner.as_deref());
+    assert_eq!(FileStatus::Changed, results[&replaced].status);
+    assert_eq!(FileType::DIR, results[&replacing].ftype);
+    assert_eq!(FileStatus::Changed, results[&replacing].status);
+}
+
+#[test]
+fn statuses_offset_root() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    let bin = root.join("usr/bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(bin.join("foo"), "Hello, world!").unwrap();
+    fs::write(bin.join("orphan"), "Hello, world!").unwrap();
+    let mtime = fs::metadata(bin.join("foo")).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        "dir /usr/bin".to_string(),
+        format!("obj /usr/bin/foo 6cd3556deb0da54bca060b4c39479839 {}", mtime),
+        "obj /usr/bin/missing 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+    ]);
+
+    let options = CheckOptions { offset_root: root.clone(), ..CheckOptions::default() };
+    let results = statuses(vec![root.join("usr")], &vdb_root, &options).unwrap();
+    assert_eq!(4, results.len());
+    assert_eq!(FileStatus::Aligned, results[path::Path::new("/usr/bin")].status);
+    assert_eq!(FileStatus::Aligned, results[path::Path::new("/usr/bin/foo")].status);
+    assert_eq!(FileStatus::Absent, results[path::Path::new("/usr/bin/missing")].status);
+    assert_eq!(FileStatus::Orphan, results[path::Path::new("/usr/bin/orphan")].status);
+}
+
+#[test]
+fn statuses_sorted_by_components() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("bin")).unwrap();
+    fs::create_dir_all(root.join("bin-extra")).unwrap();
+    for name in ["bin/b", "bin/a", "bin-extra/a", "a"] {
+        fs::write(root.join(name), "Hello, world!").unwrap();
+    }
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[format!("dir {}", root.join("bin").display())]);
+
+    // Unowned folders aren't reported; as strings, `bin-extra/a` would sort before `bin/a`.
+    let results = statuses_sorted(vec![root.clone()], &vdb_root, &CheckOptions::default()).unwrap();
+    let paths: Vec<&path::Path> = results.iter().map(|result| result.path.as_path()).collect();
+    assert_eq!(
+        vec![root.join("a"), root.join("bin"), root.join("bin/a"), root.join("bin/b"), root.join("bin-extra/a")],
+        paths,
+    );
+}
+
+#[test]
+fn statuses_with_progress_reports_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("share/doc")).unwrap();
+    fs::write(root.join("share/doc/README"), "Hello, world!").unwrap();
+    fs::write(root.join("aligned"), "Hello, world!").unwrap();
+    fs::write(root.join("orphan"), "Hello, world!").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[obj_line(&root.join("aligned"))]);
+
+    let mut entered = Vec::new();
+    let options = CheckOptions { wanted_statuses: Some([FileStatus::Orphan].iter().copied().collect()), ..CheckOptions::default() };
+    let results = statuses_with_progress(vec![root.clone()], &vdb_root, &options, |path| entered.push(path.to_path_buf())).unwrap();
+    assert_eq!(2, results.len());
+    entered.sort();
+    assert_eq!(
+        vec![root.join("aligned"), root.join("orphan"), root.join("share"), root.join("share/doc"), root.join("share/doc/README")],
+        entered,
+    );
+}
+
+#[test]
+fn statuses_eprefix() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    let bin = root.join("opt/gentoo/usr/bin");
+    fs::create_dir_all(&bin).unwrap();
+    fs::write(bin.join("foo"), "Hello, world!").unwrap();
+    let mtime = fs::metadata(bin.join("foo")).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("obj /usr/bin/foo 6cd3556deb0da54bca060b4c39479839 {}", mtime),
+        "obj /usr/bin/missing 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+        "obj /etc/outside 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+    ]);
+
+    // The prefix is part of reported paths, the mount point isn't.
+    let options = CheckOptions { eprefix: Some("/opt/gentoo".into()), offset_root: root.clone(), ..CheckOptions::default() };
+    let results = statuses(vec![root.join("opt/gentoo/usr")], &vdb_root, &options).unwrap();
+    assert_eq!(2, results.len());
+    assert_eq!(FileStatus::Aligned, results[path::Path::new("/opt/gentoo/usr/bin/foo")].status);
+    assert_eq!(FileStatus::Absent, results[path::Path::new("/opt/gentoo/usr/bin/missing")].status);
+
+    // A folder above the prefix holds all of it.
+    let results = statuses(vec![root.join("opt")], &vdb_root, &options).unwrap();
+    assert_eq!(3, results.len());
+    assert_eq!(FileStatus::Absent, results[path::Path::new("/opt/gentoo/etc/outside")].status);
+
+    let results = statuses_for_category(&vdb_root, "app-misc", &options).unwrap();
+    assert_eq!(FileStatus::Aligned, results[path::Path::new("/opt/gentoo/usr/bin/foo")].status);
+
+    // Same for a VarDB from elsewhere, e.g. downloaded with `--vdb-url`.
+    let downloaded = VarDb::from(vardbapi::get_vdb(&vdb_root, &vec!["/".into()]).unwrap());
+    let usr = vec![root.join("opt/gentoo/usr")];
+    assert_eq!(statuses(usr.clone(), &vdb_root, &options).unwrap(), downloaded.statuses_with(usr, &options).unwrap());
+}
+
+#[test]
+fn statuses_ignore_patterns() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("etc")).unwrap();
+    fs::create_dir_all(root.join("usr/lib/python")).unwrap();
+    fs::write(root.join("etc/hosts"), "127.0.0.1 localhost").unwrap();
+    fs::write(root.join("usr/lib/python/foo.pyc"), "").unwrap();
+    fs::write(root.join("usr/lib/python/foo.py"), "").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        "obj /etc/hosts 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+        "obj /etc/missing 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+        "obj /usr/lib/python/gone.pyc 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+    ]);
+    let bases = vec![root.join("etc"), root.join("usr")];
+
+    let options = CheckOptions { offset_root: root.clone(), ..CheckOptions::default() };
+    let results = statuses(bases.clone(), &vdb_root, &options).unwrap();
+    assert_eq!(5, results.len());
+
+    let options = CheckOptions {
+        offset_root: root.clone(),
+        ignore: vec![glob::Pattern::new("/etc/*").unwrap(), glob::Pattern::new("**/*.pyc").unwrap()],
+        ..CheckOptions::default()
+    };
+    let results = statuses(bases, &vdb_root, &options).unwrap();
+    assert_eq!(vec![path::Path::new("/usr/lib/python/foo.py")], results.keys().map(|path| path.as_path()).collect::<Vec<_>>());
+    assert_eq!(FileStatus::Orphan, results[path::Path::new("/usr/lib/python/foo.py")].status);
+}
+
+#[test]
+fn statuses_ignore_folder() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("usr/share/doc/foo")).unwrap();
+    fs::write(root.join("usr/share/doc/foo/README"), "").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        "obj /usr/share/doc/foo/NEWS 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+    ]);
+
+    let options = CheckOptions {
+        offset_root: root.clone(),
+        ignore: vec![glob::Pattern::new("/usr/share/doc").unwrap()],
+        ..CheckOptions::default()
+    };
+    let results = statuses(vec![root.join("usr")], &vdb_root, &options).unwrap();
+    assert!(results.is_empty());
+}
+
+#[test]
+fn statuses_config_protect() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("etc/foo")).unwrap();
+    fs::create_dir_all(root.join("usr/share/foo")).unwrap();
+    for file in &["etc/foo/foo.conf", "etc/foo/aligned.conf", "usr/share/foo/foo.conf"] {
+        fs::write(root.join(file), "Edited").unwrap();
+    }
+    let mtime = fs::metadata(root.join("etc/foo/aligned.conf")).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        "obj /etc/foo/foo.conf 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+        format!("obj /etc/foo/aligned.conf 6cd3556deb0da54bca060b4c39479839 {}", mtime),
+        "obj /usr/share/foo/foo.conf 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+    ]);
+
+    let options = CheckOptions { offset_root: root.clone(), config_protect: vec!["/etc".into()], ..CheckOptions::default() };
+    let results = statuses(vec![root.join("etc"), root.join("usr")], &vdb_root, &options).unwrap();
+    let protected = &results[path::Path::new("/etc/foo/foo.conf")];
+    assert_eq!((FileStatus::Edited, true), (protected.status, protected.protected));
+    let aligned = &results[path::Path::new("/etc/foo/aligned.conf")];
+    assert_eq!((FileStatus::Aligned, false), (aligned.status, aligned.protected));
+    let unprotected = &results[path::Path::new("/usr/share/foo/foo.conf")];
+    assert_eq!((FileStatus::Edited, false), (unprotected.status, unprotected.protected));
+}
+
+#[test]
+fn statuses_multiple_bases() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    for folder in &["etc", "usr/lib", "usr/share"] {
+        fs::create_dir_all(root.join(folder)).unwrap();
+    }
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        "obj /etc/foo.conf 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+        "obj /usr/lib/libfoo.so 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+        "obj /usr/share/foo 6cd3556deb0da54bca060b4c39479839 1586621688".to_string(),
+    ]);
+
+    let options = CheckOptions { offset_root: root.clone(), ..CheckOptions::default() };
+    let results = statuses(vec![root.join("etc"), root.join("usr/lib")], &vdb_root, &options).unwrap();
+    let mut paths: Vec<&path::Path> = results.keys().map(|path| path.as_path()).collect();
+    paths.sort();
+    assert_eq!(vec![path::Path::new("/etc/foo.conf"), path::Path::new("/usr/lib/libfoo.so")], paths);
+}
+
+#[test]
+fn statuses_overlapping_bases() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let usr = dir.path().join("usr");
+    fs::create_dir_all(usr.join("bin")).unwrap();
+    fs::write(usr.join("bin/foo"), "Hello, world!").unwrap();
+    fs::write(usr.join("bin/orphan"), "").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", usr.join("bin/foo").display()),
+    ]);
+
+    let mut met = Vec::new();
+    let bases = vec![usr.join("bin"), usr.clone(), usr.join("bin/."), usr.join("bin/")];
+    let results = statuses_with_progress(bases, &vdb_root, &CheckOptions::default(), |path| met.push(path.to_path_buf())).unwrap();
+    met.sort();
+    assert_eq!(vec![usr.join("bin"), usr.join("bin/foo"), usr.join("bin/orphan")], met);
+    assert_eq!(2, results.len());
+
+    let bases = vec![usr.join("bin"), usr.clone()];
+    assert_eq!(vec![usr.join("bin/orphan")], orphans(bases.clone(), &vdb_root, &CheckOptions::default()).unwrap());
+    assert_eq!(vec![usr.join("bin/orphan")], reconcile(bases, &vdb_root, &CheckOptions::default()).unwrap().orphans);
+}
+
+#[test]
+fn statuses_deleted_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let obj = root.join("obj");
+    let sym = root.join("sym");
+    let folder = root.join("folder");
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("dir {}", folder.display()),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", folder.join("nested").display()),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", obj.display()),
+        format!("sym {} -> obj 1586621688", sym.display()),
+    ]);
+
+    let results = statuses(vec![root], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(4, results.len());
+    for (path, ftype) in [(&obj, FileType::REG), (&sym, FileType::LNK), (&folder, FileType::DIR)] {
+        assert_eq!(ftype, results[path].ftype);
+        assert_eq!(FileStatus::Absent, results[path].status);
+        assert_eq!(Some("app-misc/foo-1.0"), results[path].owner.as_deref());
+    }
+    assert_eq!(FileStatus::Absent, results[&folder.join("nested")].status);
+}
+
+#[test]
+fn vardb_statuses_with_reused() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let bin = dir.path().join("bin");
+    let lib = dir.path().join("lib");
+    fs::create_dir(&bin).unwrap();
+    fs::create_dir(&lib).unwrap();
+    fs::write(bin.join("foo"), "foo").unwrap();
+    fs::write(lib.join("foo.so"), "foo").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        obj_line(&bin.join("foo")),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", lib.join("foo.so").display()),
+    ]);
+
+    let vdb = VarDb::load(&vdb_root).unwrap();
+    assert_eq!(2, vdb.len());
+
+    let results = vdb.statuses_with(vec![bin.clone()], &CheckOptions::default()).unwrap();
+    assert_eq!(1, results.len());
+    assert_eq!(FileStatus::Aligned, results[&bin.join("foo")].status);
+
+    let results = vdb.statuses_with(vec![lib.clone()], &CheckOptions::default()).unwrap();
+    assert_eq!(1, results.len());
+    assert_eq!(FileStatus::Edited, results[&lib.join("foo.so")].status);
+    assert_eq!(Some("app-misc/foo-1.0"), results[&lib.join("foo.so")].owner.as_deref());
+}
+
+#[test]
+fn vardb_manifest_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    fs::write(root.join("aligned"), "foo").unwrap();
+    fs::write(root.join("edited"), "foo").unwrap();
+    fs::write(root.join("orphan"), "foo").unwrap();
+    std::os::unix::fs::symlink("aligned", root.join("link")).unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("dir {}", root.display()),
+        obj_line(&root.join("aligned")),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", root.join("edited").display()),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", root.join("absent").display()),
+        format!("sym {} -> aligned 1586621688", root.join("link").display()),
+    ]);
+    write_atom(&vdb_root, "app-misc/bar-2.0", &[
+        format!("fif {}", root.join("pipe").display()),
+    ]);
+    let manifest = dir.path().join("manifest.txt");
+
+    let vdb = VarDb::load(&vdb_root).unwrap();
+    vdb.save_manifest(&manifest).unwrap();
+    let loaded = VarDb::load_manifest(&manifest).unwrap();
+    assert_eq!(vdb.len(), loaded.len());
+    let options = CheckOptions::default();
+    let expected = vdb.statuses_with(vec![root.clone()], &options).unwrap();
+    assert_eq!(6, expected.len());
+    assert_eq!(expected, loaded.statuses_with(vec![root], &options).unwrap());
+
+    assert!(matches!(vdb.save_manifest(&dir.path().join("missing/manifest.txt")), Err(Error::ManifestUnwritable { .. })));
+}
+
+#[test]
+fn check_path_all_owners_any_match() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let record = |atom: &str, content: &[u8]| vardbapi::VarDBRecord {
+        metadata: vardbapi::FileMetadata::Regular {
+            mtime: Some(0),
+            hash: vardbapi::FileHash::MD5(compute_md5_of(content)),
+            xattrs: None,
+        },
+        atom: atom.into(),
+    };
+    let aligned = vardbapi::VarDBRecord { metadata: regular_metadata(&path, None), atom: "app-misc/foo-2.0".into() };
+    let options = CheckOptions::default();
+
+    let result = check_path_all_owners(&path, &[record("app-misc/bar-1.0", b"Goodbye"), aligned], &options).unwrap();
+    assert_eq!((FileStatus::Aligned, Some("app-misc/foo-2.0")), (result.status, result.owner.as_deref()));
+
+    let records = [record("app-misc/bar-1.0", b"Goodbye"), record("app-misc/foo-1.0", b"Hello, world!")];
+    let result = check_path_all_owners(&path, &records, &options).unwrap();
+    assert_eq!((FileStatus::Touched, Some("app-misc/foo-1.0")), (result.status, result.owner.as_deref()));
+
+    assert_eq!(FileStatus::Orphan, check_path_all_owners(&path, &[], &options).unwrap().status);
+}
+
+#[test]
+fn check_options_path_map() {
+    let options = CheckOptions {
+        path_map: vec![("/usr/lib".into(), "/lib".into()), ("/usr".into(), "/opt/usr".into())],
+        ..Default::default()
+    };
+    assert_eq!(path::PathBuf::from("/lib/libfoo.so"), options.disk_path("/usr/lib/libfoo.so".as_ref()));
+    assert_eq!(path::PathBuf::from("/opt/usr/bin/foo"), options.disk_path("/usr/bin/foo".as_ref()));
+    assert_eq!(path::PathBuf::from("/opt/usr"), options.disk_path("/usr".as_ref()));
+    assert_eq!(path::PathBuf::from("/usrlocal/foo"), options.disk_path("/usrlocal/foo".as_ref()));
+    assert_eq!(path::PathBuf::from("/usr/bin/foo"), options.recorded_path("/opt/usr/bin/foo".as_ref()));
+    assert_eq!(path::PathBuf::from("/etc/hosts"), options.recorded_path("/etc/hosts".as_ref()));
+}
+
+#[test]
+fn statuses_wanted_statuses() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let aligned = root.join("aligned");
+    fs::write(&aligned, "Hello, world!").unwrap();
+    let touched = root.join("touched");
+    fs::write(&touched, "Hello, world!").unwrap();
+    let edited = root.join("edited");
+    fs::write(&edited, "Goodbye!").unwrap();
+    let absent = root.join("absent");
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        obj_line(&aligned),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", touched.display()),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", edited.display()),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", absent.display()),
+    ]);
+
+    let wanted = [FileStatus::Edited, FileStatus::Absent].iter().copied().collect();
+    let options = CheckOptions { wanted_statuses: Some(wanted), ..Default::default() };
+    let results = statuses(vec![root.clone()], &vdb_root, &options).unwrap();
+    let mut paths: Vec<_> = results.keys().collect();
+    paths.sort();
+    assert_eq!(vec![&absent, &edited], paths);
+    let streamed: Vec<_> = status_iter(vec![root], &vdb_root, &options).unwrap().map(|result| result.unwrap().status).collect();
+    assert_eq!(2, streamed.len());
+}
+
+#[test]
+fn statuses_path_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("bin")).unwrap();
+    let owned = rootfs.join("bin/owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    fs::write(rootfs.join("bin/orphan"), "Hello, world!").unwrap();
+
+    // Record the file as if it lived in /usr
+    let line = obj_line(&owned).replace(&rootfs.display().to_string(), "/usr");
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[line]);
+
+    let options = CheckOptions { path_map: vec![("/usr".into(), rootfs.clone())], ..Default::default() };
+    let results = statuses(vec![rootfs.join("bin")], &vdb_root, &options).unwrap();
+    assert_eq!(2, results.len());
+    assert_eq!(FileStatus::Aligned, results[path::Path::new("/usr/bin/owned")].status);
+    assert_eq!(FileStatus::Orphan, results[path::Path::new("/usr/bin/orphan")].status);
+}
+
+#[test]
+fn statuses_regular_files_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let owned = root.join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let link = root.join("link");
+    std::os::unix::fs::symlink("owned", &link).unwrap();
+    let replaced = root.join("replaced");
+    fs::write(&replaced, "Hello, world!").unwrap();
+
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        obj_line(&owned),
+        format!("sym {} -> owned 1586621688", link.display()),
+        format!("sym {} -> owned 1586621688", replaced.display()),
+    ]);
+
+    let options = CheckOptions { regular_files_only: true, ..Default::default() };
+    let results = statuses(vec![root], &vdb_root, &options).unwrap();
+    assert_eq!(1, results.len());
+    assert_eq!(FileStatus::Aligned, results[&owned].status);
+}
+
+#[test]
+fn statuses_skipped_fs_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    fs::create_dir(&vdb_root).unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("mnt")).unwrap();
+    let top = root.join("top");
+    fs::write(&top, "Hello, world!").unwrap();
+    fs::write(root.join("mnt").join("nested"), "Hello, world!").unwrap();
+
+    let fs_type = fstype::of(&root).unwrap();
+    let options = CheckOptions { skipped_fs_types: vec![fs_type], ..Default::default() };
+    let results = statuses(vec![root], &vdb_root, &options).unwrap();
+    assert_eq!(vec![&top], results.keys().collect::<Vec<_>>());
+}
+
+#[test]
+fn reconcile_presence() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let owned = root.join("sub").join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let orphan_b = root.join("sub").join("b");
+    fs::write(&orphan_b, "").unwrap();
+    let orphan_a = root.join("a");
+    fs::write(&orphan_a, "").unwrap();
+    let missing = root.join("missing");
+
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("dir {}", root.display()),
+        format!("dir {}", root.join("sub").display()),
+        // A wrong hash doesn't matter: contents aren't checked.
+        format!("obj {} 00000000000000000000000000000000 0", owned.display()),
+        format!("obj {} 00000000000000000000000000000000 0", missing.display()),
+    ]);
+
+    let reconciliation = reconcile(vec![root], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(Reconciliation { orphans: vec![orphan_a, orphan_b], absent: vec![missing], ..Default::default() }, reconciliation);
+}
+
+#[test]
+fn reconcile_file_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let file = root.join("file");
+    fs::write(&file, "").unwrap();
+    let now_dir = root.join("now_dir");
+    fs::create_dir(&now_dir).unwrap();
+    let now_link = root.join("sub").join("now_link");
+    std::os::unix::fs::symlink("file", &now_link).unwrap();
+    let ignored = root.join("ignored");
+    fs::write(&ignored, "").unwrap();
+    let fifo = root.join("fifo");
+    mkfifo(&fifo);
+
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("obj {} 00000000000000000000000000000000 0", file.display()),
+        format!("obj {} 00000000000000000000000000000000 0", now_dir.display()),
+        format!("obj {} 00000000000000000000000000000000 0", now_link.display()),
+        format!("dir {}", ignored.display()),
+        format!("obj {} 00000000000000000000000000000000 0", root.join("sub").join("missing").display()),
+        format!("dir {}", fifo.display()),
+    ]);
+    let reconcile_with = |options: CheckOptions| reconcile(vec![root.clone()], &vdb_root, &options).unwrap();
+
+    let ignore = vec![glob::Pattern::new("**/ignored").unwrap()];
+    let reconciliation = reconcile_with(CheckOptions { ignore: ignore.clone(), ..Default::default() });
+    assert_eq!(vec![fifo, now_dir.clone(), now_link], reconciliation.mismatched);
+    assert_eq!(vec![root.join("sub").join("missing")], reconciliation.absent);
+    assert!(reconciliation.orphans.is_empty());
+
+    let reconciliation = reconcile_with(CheckOptions { ignore, max_depth: Some(0), regular_files_only: true, ..Default::default() });
+    assert!(reconciliation.mismatched.is_empty());
+    assert!(reconciliation.absent.is_empty());
+}
+
+#[test]
+fn orphans_lists_unowned_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub").join("empty")).unwrap();
+    let owned = root.join("sub").join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let orphan_b = root.join("sub").join("b");
+    fs::write(&orphan_b, "").unwrap();
+    let orphan_a = root.join("a");
+    fs::write(&orphan_a, "").unwrap();
+
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("dir {}", root.join("sub").display()),
+        // A wrong hash doesn't matter: contents aren't checked.
+        format!("obj {} 00000000000000000000000000000000 0", owned.display()),
+        format!("obj {} 00000000000000000000000000000000 0", root.join("missing").display()),
+    ]);
+
+    assert_eq!(vec![orphan_a, orphan_b], orphans(vec![root], &vdb_root, &CheckOptions::default()).unwrap());
+}
+
+#[test]
+fn orphans_honor_options() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let top = root.join("top");
+    fs::write(&top, "").unwrap();
+    let deep = root.join("sub").join("deep");
+    fs::write(&deep, "").unwrap();
+    let ignored = root.join("ignored.pyc");
+    fs::write(&ignored, "").unwrap();
+    let fifo = root.join("fifo");
+    mkfifo(&fifo);
+    fs::create_dir(&vdb_root).unwrap();
+    let orphans_with = |options: CheckOptions| orphans(vec![root.clone()], &vdb_root, &options).unwrap();
+
+    assert_eq!(vec![fifo.clone(), ignored, deep.clone(), top.clone()], orphans_with(CheckOptions::default()));
+    let ignore = vec![glob::Pattern::new("**/*.pyc").unwrap()];
+    assert_eq!(vec![fifo.clone(), deep, top.clone()], orphans_with(CheckOptions { ignore: ignore.clone(), ..Default::default() }));
+    assert_eq!(vec![fifo, top.clone()], orphans_with(CheckOptions { ignore: ignore.clone(), max_depth: Some(0), ..Default::default() }));
+    assert_eq!(vec![top], orphans_with(CheckOptions { ignore, max_depth: Some(0), regular_files_only: true, ..Default::default() }));
+
+    // The checked folder itself is walked, but not `sub`, on the same file system.
+    let skipped_fs_types = vec![fstype::of(&root).unwrap()];
+    assert_eq!(3, orphans_with(CheckOptions { skipped_fs_types, ..Default::default() }).len());
+}
+
+#[test]
+fn summarize_orphan_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    let owned = root.join("owned");
+    fs::write(&owned, vec![0; 4096]).unwrap();
+    fs::write(root.join("sub").join("a"), vec![0; 1000]).unwrap();
+    fs::write(root.join("sub").join("b"), vec![0; 24]).unwrap();
+    fs::write(root.join("c"), "").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[format!("dir {}", root.join("sub").display()), obj_line(&owned)]);
+
+    let results = statuses(vec![root.clone()], &vdb_root, &CheckOptions::default()).unwrap();
+    assert_eq!(Some(1000), results[&root.join("sub").join("a")].size);
+    assert_eq!(Some(4096), results[&owned].size);
+    assert_eq!(None, results[&root.join("sub")].size);
+    let summary = summarize(&results);
+    assert_eq!((3, 1024), (summary.orphan, summary.orphan_bytes));
+}
+
+#[test]
+fn statuses_max_depth() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("a").join("b")).unwrap();
+    fs::write(root.join("top"), "").unwrap();
+    fs::write(root.join("a").join("middle"), "").unwrap();
+    let deep = root.join("a").join("b").join("deep");
+    fs::write(&deep, "Hello, world!").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[
+        format!("dir {}", root.join("a").display()),
+        format!("dir {}", root.join("a").join("b").display()),
+        obj_line(&deep),
+        format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", root.join("a").join("b").join("missing").display()),
+    ]);
+
+    let checked = |max_depth| {
+        let options = CheckOptions { max_depth, ..Default::default() };
+        let mut paths: Vec<path::PathBuf> = statuses(vec![root.clone()], &vdb_root, &options).unwrap().into_keys().collect();
+        paths.sort();
+        paths
+    };
+    assert_eq!(vec![root.join("a"), root.join("top")], checked(Some(0)));
+    assert_eq!(vec![root.join("a"), root.join("a").join("b"), root.join("a").join("middle"), root.join("top")], checked(Some(1)));
+    assert_eq!(6, checked(Some(2)).len());
+    assert_eq!(checked(Some(2)), checked(None));
+}
+
+#[test]
+fn statuses_report_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let owned = root.join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    fs::write(root.join("orphan"), "Hello, world!").unwrap();
+    write_atom(&vdb_root, "app-misc/foo-1.0", &[obj_line(&owned)]);
+
+    let options = CheckOptions { lenient: true, ..Default::default() };
+    let report = statuses_report(vec![root.clone()], &vdb_root, &options).unwrap();
+    assert_eq!(2, report.results.len());
+    assert_eq!(Summary { aligned: 1, orphan: 1, orphan_bytes: 13, ..Default::default() }, report.summary);
+    assert!(report.errors.is_empty());
+    assert!(report.started <= report.finished);
+    assert_eq!(vec![root], report.paths);
+    assert!(report.options.lenient);
+}
+
+#[test]
+fn walk_lenient_records_unreadable_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    let vdb = vardbapi::VarDB::new();
+    let mut keep = |_: &path::Path| OrphanAction::Keep;
+
+    let options = CheckOptions { lenient: true, ..Default::default() };
+    let mut walk = Walk::new(&vdb, vec![missing.clone()], &options, &mut keep);
+    walk.run().unwrap();
+    assert!(matches!(walk.errors[..], [Error::DirUnreadable { .. }]));
+
+    let options = CheckOptions::default();
+    let mut walk = Walk::new(&vdb, vec![missing], &options, &mut keep);
+    assert!(matches!(walk.run(), Err(Error::DirUnreadable { .. })));
+}
+
+#[test]
+fn reconcile_against_owned_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let owned = root.join("owned");
+    fs::write(&owned, "").unwrap();
+    let orphan = root.join("orphan");
+    fs::write(&orphan, "").unwrap();
+    let missing = root.join("missing");
+
+    let owned_paths = vec![owned, missing.clone(), "/elsewhere".into()].into_iter().collect();
+    let reconciliation = reconcile_against(vec![root], &owned_paths, &CheckOptions::default()).unwrap();
+    assert_eq!(Reconciliation { orphans: vec![orphan], absent: vec![missing], ..Default::default() }, reconciliation);
+}
+
+#[test]
+fn statuses_with_orphan_handler_actions() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    fs::create_dir(&vdb_root).unwrap();
+    let quarantine = dir.path().join("quarantine");
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    for name in ["keep", "quarantine", "delete"] {
+        fs::write(root.join(name), "Hello, world!").unwrap();
+    }
+
+    let options = CheckOptions {
+        allow_orphan_removal: true,
+        quarantine_dir: Some(quarantine.clone()),
+        ..Default::default()
+    };
+    let results = statuses_with_orphan_handler(vec![root.clone()], &vdb_root, &options, |path| {
+        match path.file_name().unwrap().to_str().unwrap() {
+            "quarantine" => OrphanAction::Quarantine,
+            "delete" => OrphanAction::Delete,
+            _ => OrphanAction::Keep,
+        }
+    }).unwrap();
+
+    let quarantined = quarantine.join(root.join("quarantine").strip_prefix("/").unwrap());
+    assert!(root.join("keep").exists());
+    assert!(!root.join("quarantine").exists());
+    assert!(quarantined.exists());
+    assert!(!root.join("delete").exists());
+    assert_eq!(Vec::<Detail>::new(), results[&root.join("keep")].details);
+    assert_eq!(vec![Detail::Quarantined { to: quarantined }], results[&root.join("quarantine")].details);
+    assert_eq!(vec![Detail::Deleted], results[&root.join("delete")].details);
+}
+
+#[test]
+fn statuses_with_orphan_handler_denied() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    fs::create_dir(&vdb_root).unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    fs::write(root.join("orphan"), "Hello, world!").unwrap();
+
+    let result = statuses_with_orphan_handler(vec![root.clone()], &vdb_root, &CheckOptions::default(), |_| OrphanAction::Delete);
+    assert!(matches!(result, Err(Error::OrphanRemovalDenied { .. })));
+    let result = statuses_with_orphan_handler(vec![root.clone()], &vdb_root, &CheckOptions::default(), |_| OrphanAction::Quarantine);
+    assert!(matches!(result, Err(Error::OrphanRemovalDenied { .. })));
+    assert!(root.join("orphan").exists());
+
+    // Allowed, but with nowhere to move the file.
+    let options = CheckOptions { allow_orphan_removal: true, ..Default::default() };
+    let result = statuses_with_orphan_handler(vec![root.clone()], &vdb_root, &options, |_| OrphanAction::Quarantine);
+    assert!(matches!(result, Err(Error::QuarantineDirUnset { .. })));
+    assert!(root.join("orphan").exists());
+}
+
+/// A manifest listing expected paths, without any VarDB.
+struct ListManifest(Vec<(path::PathBuf, vardbapi::FileMetadata)>);
+
+impl ManifestProvider for ListManifest {
+    fn expected(&self, path: &path::Path) -> Option<&vardbapi::FileMetadata> {
+        self.0.iter().find(|(recorded, _)| recorded == path).map(|(_, metadata)| metadata)
+    }
+}
+
+#[test]
+fn statuses_against_custom_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let owned = dir.path().join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let edited = dir.path().join("edited");
+    fs::write(&edited, "Hello, world!").unwrap();
+    let orphan = dir.path().join("orphan");
+    fs::write(&orphan, "Hello, world!").unwrap();
+
+    let manifest = ListManifest(vec![
+        (owned.clone(), regular_metadata(&owned, None)),
+        (edited.clone(), vardbapi::FileMetadata::Regular {
+            mtime: Some(0),
+            hash: vardbapi::FileHash::MD5([0; 16]),
+            xattrs: None,
+        }),
+    ]);
+    let results = statuses_against(vec![dir.path().into()], &manifest, &CheckOptions::default()).unwrap();
+    assert_eq!(FileStatus::Aligned, results[&owned].status);
+    assert_eq!(FileStatus::Edited, results[&edited].status);
+    assert_eq!(FileStatus::Orphan, results[&orphan].status);
+}
+
+#[test]
+fn classify_with_hash_no_io() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+    let expected = vardbapi::FileMetadata::Regular {
+        mtime: Some(0),
+        hash: vardbapi::FileHash::MD5(compute_md5(&path, DEFAULT_HASH_BUFFER_SIZE).unwrap()),
+        xattrs: None,
+    };
+    // The file is gone: only the provided hash may be used.
+    fs::remove_file(&path).unwrap();
+
+    let result = classify_with_hash(&path, &expected, &metadata, &vardbapi::FileHash::MD5(compute_md5_of(b"Hello, world!"))).unwrap();
+    assert_eq!(FileStatus::Touched, result.status);
+    let result = classify_with_hash(&path, &expected, &metadata, &vardbapi::FileHash::MD5([0; 16])).unwrap();
+    assert_eq!(FileStatus::Edited, result.status);
+}
+
+#[test]
+fn check_file_trusts_matching_mtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+    let mtime = metadata.modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    let expected = |mtime| vardbapi::FileMetadata::Regular { mtime: Some(mtime), hash: vardbapi::FileHash::MD5([1; 16]), xattrs: None };
+    // The file is gone: any attempt to hash it fails.
+    fs::remove_file(&path).unwrap();
+
+    let result = check_file(path.clone(), &path, &metadata, Some(&expected(mtime)), &CheckOptions::default()).unwrap();
+    assert_eq!(FileStatus::Aligned, result.status);
+    let result = check_file(path.clone(), &path, &metadata, Some(&expected(mtime + 1)), &CheckOptions::default());
+    assert!(matches!(result, Err(Error::FileUnreadable { .. })));
+}
+
+#[test]
+fn check_file_capture_ownership() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+
+    let result = check_file(path.clone(), &path, &metadata, None, &CheckOptions::default()).unwrap();
+    assert_eq!((None, None), (result.uid, result.gid));
+    let options = CheckOptions { capture_ownership: true, ..CheckOptions::default() };
+    let result = check_file(path.clone(), &path, &metadata, None, &options).unwrap();
+    assert_eq!((Some(metadata.uid()), Some(metadata.gid())), (result.uid, result.gid));
+}
+
+#[test]
+fn check_file_capture_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o4751)).unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+
+    let result = check_file(path.clone(), &path, &metadata, None, &CheckOptions::default()).unwrap();
+    assert_eq!(None, result.mode);
+    let options = CheckOptions { capture_mode: true, ..CheckOptions::default() };
+    let result = check_file(path.clone(), &path, &metadata, None, &options).unwrap();
+    assert_eq!(Some(0o104751), result.mode);
+}
+
+#[test]
+fn check_file_hash_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    let cache_path = dir.path().join("hashes");
+    fs::write(&path, "Hello, world!").unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+    // A recorded mtime which never matches, so that the file always needs hashing.
+    let expected = vardbapi::FileMetadata::Regular { mtime: Some(0), hash: vardbapi::FileHash::MD5(compute_md5(&path, DEFAULT_HASH_BUFFER_SIZE).unwrap()), xattrs: None };
+
+    let options = CheckOptions { hash_cache: Some(sync::Arc::new(HashCache::new())), ..CheckOptions::default() };
+    let result = check_file(path.clone(), &path, &metadata, Some(&expected), &options).unwrap();
+    assert_eq!(FileStatus::Touched, result.status);
+    options.hash_cache.as_ref().unwrap().save(&cache_path).unwrap();
+
+    // The file is gone: any attempt to hash it fails.
+    fs::remove_file(&path).unwrap();
+    let cache = HashCache::load(&cache_path).unwrap();
+    assert_eq!(1, cache.len());
+    let options = CheckOptions { hash_cache: Some(sync::Arc::new(cache)), ..CheckOptions::default() };
+    let result = check_file(path.clone(), &path, &metadata, Some(&expected), &options).unwrap();
+    assert_eq!(FileStatus::Touched, result.status);
+
+    // A file with another size doesn't match the cached entry.
+    fs::write(&path, "Hello!").unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+    let result = check_file(path.clone(), &path, &metadata, Some(&expected), &options).unwrap();
+    assert_eq!(FileStatus::Edited, result.status);
+}
+
+#[test]
+fn check_file_continue_on_read_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let metadata = fs::symlink_metadata(&path).unwrap();
+    let expected = vardbapi::FileMetadata::Regular { mtime: Some(0), hash: vardbapi::FileHash::MD5([1; 16]), xattrs: None };
+    // The file can't be opened anymore, as if its permissions or its disk failed.
+    fs::remove_file(&path).unwrap();
+
+    let result = check_file(path.clone(), &path, &metadata, Some(&expected), &CheckOptions::default());
+    assert!(matches!(result, Err(Error::FileUnreadable { .. })));
+    let options = CheckOptions { continue_on_read_error: true, ..CheckOptions::default() };
+    let result = check_file(path.clone(), &path, &metadata, Some(&expected), &options).unwrap();
+    assert_eq!(FileStatus::Unreadable, result.status);
+    assert_eq!(Some(vardbapi::FileHash::MD5([1; 16])), result.recorded_hash);
+}
+
+#[test]
+fn check_path_mtime_tolerance() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let mtime = fs::metadata(&path).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    // A wrong hash tells whether the file was hashed.
+    let expected = |mtime| vardbapi::FileMetadata::Regular { mtime: Some(mtime), hash: vardbapi::FileHash::MD5([1; 16]), xattrs: None };
+    let options = CheckOptions { mtime_tolerance_secs: 2, ..Default::default() };
+
+    assert_eq!(FileStatus::Aligned, compare(&path, &expected(mtime), &CheckOptions::default()).unwrap().status);
+    assert_eq!(FileStatus::Edited, compare(&path, &expected(mtime - 2), &CheckOptions::default()).unwrap().status);
+    assert_eq!(FileStatus::Aligned, compare(&path, &expected(mtime - 2), &options).unwrap().status);
+    assert_eq!(FileStatus::Aligned, compare(&path, &expected(mtime + 2), &options).unwrap().status);
+    assert_eq!(FileStatus::Edited, compare(&path, &expected(mtime - 3), &options).unwrap().status);
+}
+
+#[test]
+fn check_path_expected_state() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let mtime = fs::metadata(&path).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    let expected = |mtime, hash| vardbapi::FileMetadata::Regular { mtime: Some(mtime), hash, xattrs: None };
+    let right_hash = vardbapi::FileHash::MD5([108, 211, 85, 109, 235, 13, 165, 75, 202, 6, 11, 76, 57, 71, 152, 57]);
+    let wrong_hash = vardbapi::FileHash::MD5([1; 16]);
+    let options = CheckOptions::default();
+
+    let touched = compare(&path, &expected(mtime - 10, right_hash.clone()), &options).unwrap();
+    assert_eq!(FileStatus::Touched, touched.status);
+    assert_eq!((Some(mtime - 10), Some(false)), (touched.expected_mtime, touched.hash_mismatch));
+
+    let edited = compare(&path, &expected(mtime - 10, wrong_hash.clone()), &options).unwrap();
+    assert_eq!(FileStatus::Edited, edited.status);
+    assert_eq!((Some(mtime - 10), Some(true)), (edited.expected_mtime, edited.hash_mismatch));
+
+    // A matching mtime is trusted: the file isn't hashed.
+    let aligned = compare(&path, &expected(mtime, wrong_hash.clone()), &options).unwrap();
+    assert_eq!((Some(mtime), None), (aligned.expected_mtime, aligned.hash_mismatch));
+
+    let absent = compare(&dir.path().join("missing"), &expected(mtime, wrong_hash), &options).unwrap();
+    assert_eq!((None, None), (absent.expected_mtime, absent.hash_mismatch));
+    let orphan = check_file(path.clone(), &path, &fs::symlink_metadata(&path).unwrap(), None, &options).unwrap();
+    assert_eq!((None, None), (orphan.expected_mtime, orphan.hash_mismatch));
+}
+
+#[test]
+fn check_path_mtime_drift() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let mtime = fs::metadata(&path).unwrap().modified().unwrap().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+    let expected = |mtime, hash| vardbapi::FileMetadata::Regular { mtime: Some(mtime), hash, xattrs: None };
+    let right_hash = vardbapi::FileHash::MD5([108, 211, 85, 109, 235, 13, 165, 75, 202, 6, 11, 76, 57, 71, 152, 57]);
+    let options = CheckOptions::default();
+
+    let newer = compare(&path, &expected(mtime - 10, right_hash.clone()), &options).unwrap();
+    assert_eq!((FileStatus::Touched, Some(MtimeDrift::Newer)), (newer.status, newer.mtime_drift));
+    let older = compare(&path, &expected(mtime + 10, right_hash.clone()), &options).unwrap();
+    assert_eq!((FileStatus::Touched, Some(MtimeDrift::Older)), (older.status, older.mtime_drift));
+    let edited = compare(&path, &expected(mtime + 10, vardbapi::FileHash::MD5([1; 16])), &options).unwrap();
+    assert_eq!((FileStatus::Edited, Some(MtimeDrift::Older)), (edited.status, edited.mtime_drift));
+
+    // Within the tolerance, the file is aligned: there's no drift to report.
+    let options = CheckOptions { mtime_tolerance_secs: 10, ..Default::default() };
+    let aligned = compare(&path, &expected(mtime + 10, right_hash), &options).unwrap();
+    assert_eq!((FileStatus::Aligned, None), (aligned.status, aligned.mtime_drift));
+}
+
+#[test]
+fn check_file_empty_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let empty = dir.path().join("empty");
+    fs::write(&empty, "").unwrap();
+    let full = dir.path().join("full");
+    fs::write(&full, "Hello, world!").unwrap();
+    // echo -n '' | md5sum
+    let empty_md5 = vardbapi::FileHash::MD5([212, 29, 140, 217, 143, 0, 178, 4, 233, 128, 9, 152, 236, 248, 66, 126]);
+    let full_md5 = vardbapi::FileHash::MD5([108, 211, 85, 109, 235, 13, 165, 75, 202, 6, 11, 76, 57, 71, 152, 57]);
+    let expected = |hash| vardbapi::FileMetadata::Regular { mtime: Some(0), hash, xattrs: None };
+    let check = |path: &path::Path, hash| {
+        check_file(path.into(), path, &fs::symlink_metadata(path).unwrap(), Some(&expected(hash)), &CheckOptions::default()).unwrap().status
+    };
+
+    assert_eq!(FileStatus::Touched, check(&empty, empty_md5.clone()));
+    assert_eq!(FileStatus::Edited, check(&empty, full_md5.clone()));
+    assert_eq!(FileStatus::Edited, check(&full, empty_md5.clone()));
+    assert_eq!(FileStatus::Touched, check(&full, full_md5));
+
+    // Neither is read: both are still classified once they can't be opened anymore.
+    let gone = |path: &path::Path, hash| {
+        let metadata = fs::symlink_metadata(path).unwrap();
+        fs::remove_file(path).unwrap();
+        check_file(path.into(), path, &metadata, Some(&expected(hash)), &CheckOptions::default()).unwrap().status
+    };
+    assert_eq!(FileStatus::Touched, gone(&empty, empty_md5.clone()));
+    assert_eq!(FileStatus::Edited, gone(&full, empty_md5));
+}
+
+#[test]
+fn check_path_expected_ftype() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::create_dir(&path).unwrap();
+    let expected = vardbapi::FileMetadata::Regular { mtime: Some(0), hash: vardbapi::FileHash::MD5([1; 16]), xattrs: None };
+
+    let result = compare(&path, &expected, &CheckOptions::default()).unwrap();
+    assert_eq!(FileStatus::Changed, result.status);
+    assert_eq!((FileType::DIR, Some(FileType::REG)), (result.ftype, result.expected_ftype));
+
+    let result = compare(&path, &vardbapi::FileMetadata::Directory, &CheckOptions::default()).unwrap();
+    assert_eq!((FileStatus::Aligned, Some(FileType::DIR)), (result.status, result.expected_ftype));
+    let result = compare(&dir.path().join("missing"), &vardbapi::FileMetadata::Fifo, &CheckOptions::default()).unwrap();
+    assert_eq!((FileStatus::Absent, Some(FileType::FIFO)), (result.status, result.expected_ftype));
+    let result = check_file(path.clone(), &path, &fs::symlink_metadata(&path).unwrap(), None, &CheckOptions::default()).unwrap();
+    assert_eq!((FileStatus::Orphan, None), (result.status, result.expected_ftype));
+}
+
+#[test]
+fn check_path_without_recorded_mtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let expected = |content: &[u8]| vardbapi::FileMetadata::Regular {
+        mtime: None,
+        hash: vardbapi::FileHash::MD5(compute_md5_of(content)),
+        xattrs: None,
+    };
+
+    let options = CheckOptions::default();
+    assert_eq!(FileStatus::Aligned, compare(&path, &expected(b"Hello, world!"), &options).unwrap().status);
+    assert_eq!(FileStatus::Edited, compare(&path, &expected(b"Goodbye"), &options).unwrap().status);
+}
+
+fn compute_md5_of(content: &[u8]) -> MD5Hash {
+    Md5::digest(content).into()
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn statuses_parallel_matches_serial() {
+    let dir = tempfile::tempdir().unwrap();
+    let vdb_root = dir.path().join("vdb");
+    let root = dir.path().join("root");
+    let mut lines = Vec::new();
+    for i in 0..50 {
+        let sub = root.join(format!("sub{}", i % 5));
+        fs::create_dir_all(&sub).unwrap();
+        let path = sub.join(format!("file{}", i));
+        fs::write(&path, format!("content {}", i)).unwrap();
+        match i % 3 {
+            0 => lines.push(obj_line(&path)),
+            1 => lines.push(format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", path.display())),
+            _ => {},
+        }
+    }
+    lines.push(format!("obj {} 6cd3556deb0da54bca060b4c39479839 1586621688", root.join("missing").display()));
+    write_atom(&vdb_root, "app-misc/foo-1.0", &lines);
+
+    let options = CheckOptions::default();
+    let serial = statuses(vec![root.clone()], &vdb_root, &options).unwrap();
+    let parallel = statuses_parallel(vec![root], &vdb_root, &options).unwrap();
+    assert_eq!(51, serial.len());
+    assert_eq!(serial, parallel);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn to_json_results() {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut results = ResultSet::new();
+    results.insert("/usr/bin/rustc".into(), ResultItem {
+        path: "/usr/bin/rustc".into(),
+        ftype: FileType::REG,
+        status: FileStatus::Edited,
+        details: vec![Detail::XattrDrift { names: vec![OsString::from("user.foo")] }, Detail::FutureMTime],
+        mtime: Some(1586621688),
+        owner: Some("dev-lang/rust-1.41.1".into()),
+        recorded_hash: Some(vardbapi::FileHash::MD5([27, 204, 143, 239, 188, 25, 186, 63, 175, 81, 86, 75, 242, 160, 225, 128])),
+        uid: Some(0),
+        gid: Some(0),
+        mode: Some(0o100755),
+        protected: false,
+        expected_mtime: Some(1586621600),
+        hash_mismatch: Some(true),
+        expected_ftype: Some(FileType::REG),
+        size: Some(2048),
+        mtime_drift: Some(MtimeDrift::Newer),
+    });
+    results.insert(path::PathBuf::from(OsString::from_vec(b"/tmp/\xff".to_vec())), ResultItem {
+        path: path::PathBuf::from(OsString::from_vec(b"/tmp/\xff".to_vec())),
+        ftype: FileType::FIFO,
+        status: FileStatus::Orphan,
+        details: Vec::new(),
+        mtime: Some(1586621688),
+        owner: None,
+        recorded_hash: None,
+        uid: None,
+        gid: None,
+        mode: None,
+        protected: false,
+        expected_mtime: None,
+        hash_mismatch: None,
+        expected_ftype: None,
+        size: Some(0),
+        mtime_drift: None,
+    });
+
+    assert_eq!(
+        concat!(
+            "[{\"path\":\"/tmp/\u{fffd}\",",
+            r#""ftype":"fifo","status":"orphan","details":[],"mtime":1586621688,"owner":null,"recorded_hash":null,"uid":null,"gid":null,"mode":null,"protected":false,"expected_mtime":null,"hash_mismatch":null,"expected_ftype":null,"size":0,"mtime_drift":null},"#,
+            r#"{"path":"/usr/bin/rustc","ftype":"reg","status":"edited","#,
+            r#""details":[{"kind":"xattr_drift","names":["user.foo"]},{"kind":"future_mtime"}],"#,
+            r#""mtime":1586621688,"owner":"dev-lang/rust-1.41.1","recorded_hash":"1bcc8fefbc19ba3faf51564bf2a0e180","uid":0,"gid":0,"mode":33261,"protected":false,"expected_mtime":1586621600,"hash_mismatch":true,"expected_ftype":"reg","size":2048,"mtime_drift":"newer"}]"#,
+        ),
+        to_json(&results),
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn scan_report_json() {
+    let unreadable = || Error::DirUnreadable { path: "/usr/lib".into(), source: io::Error::from(io::ErrorKind::PermissionDenied) };
+    let report = ScanReport {
+        results: ResultSet::new(),
+        summary: Summary { aligned: 2, ..Default::default() },
+        errors: vec![unreadable()],
+        started: time::UNIX_EPOCH + time::Duration::from_secs(1586621688),
+        finished: time::UNIX_EPOCH + time::Duration::from_millis(1586621690500),
+        paths: vec!["/usr".into()],
+        options: CheckOptions::default(),
+    };
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(serde_json::json!([]), json["results"]);
+    assert_eq!(2, json["summary"]["aligned"]);
+    assert_eq!(serde_json::json!([unreadable().to_string()]), json["errors"]);
+    assert_eq!(1586621688, json["started"]);
+    assert_eq!(1586621690, json["finished"]);
+    assert_eq!(serde_json::json!(["/usr"]), json["paths"]);
+    assert!(json.get("options").is_none());
+}
+
+#[cfg(feature = "inotify")]
+#[test]
+fn watch_reports_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let owned = root.join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let mut vdb = vardbapi::VarDB::new();
+    vdb.insert(owned.clone(), vardbapi::VarDBRecord {
+        metadata: regular_metadata(&owned, None),
+        atom: "app-misc/foo-1.0".into(),
+    });
+    let options = CheckOptions::default();
+
+    let mut changes = watch(vec![root.clone()], &vdb, &options).unwrap();
+    fs::write(root.join("new"), "Hello, world!").unwrap();
+    let result = changes.next().unwrap().unwrap();
+    assert_eq!((root.join("new"), FileStatus::Orphan), (result.path, result.status));
+
+    fs::remove_file(&owned).unwrap();
+    let result = changes.map(Result::unwrap).find(|result| result.path == owned).unwrap();
+    assert_eq!((owned, FileStatus::Absent), (result.path, result.status));
+    assert_eq!(Some("app-misc/foo-1.0".to_string()), result.owner);
+}
+
+#[cfg(feature = "inotify")]
+#[test]
+fn watch_checks_new_trees() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir(&root).unwrap();
+    let outside = dir.path().join("tree");
+    fs::create_dir_all(outside.join("sub")).unwrap();
+    fs::write(outside.join("top"), "Hello, world!").unwrap();
+    fs::write(outside.join("sub").join("deep"), "Hello, world!").unwrap();
+    let vdb = vardbapi::VarDB::new();
+    let options = CheckOptions::default();
+
+    let mut changes = watch(vec![root.clone()], &vdb, &options).unwrap();
+    // Removed folders don't stop the watch.
+    fs::create_dir(root.join("gone")).unwrap();
+    fs::remove_dir(root.join("gone")).unwrap();
+    fs::rename(&outside, root.join("tree")).unwrap();
+    let mut found: Vec<ResultItem> = changes.by_ref().take(2).map(Result::unwrap).collect();
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(vec![root.join("tree").join("sub").join("deep"), root.join("tree").join("top")], found.iter().map(|result| result.path.clone()).collect::<Vec<_>>());
+    assert!(found.iter().all(|result| result.status == FileStatus::Orphan));
+
+    fs::write(root.join("tree").join("sub").join("new"), "Hello, world!").unwrap();
+    let result = changes.next().unwrap().unwrap();
+    assert_eq!((root.join("tree").join("sub").join("new"), FileStatus::Orphan), (result.path, result.status));
+}
+
+#[cfg(feature = "inotify")]
+#[test]
+fn watch_reports_edits() {
+    let dir = tempfile::tempdir().unwrap();
+    let owned = dir.path().join("owned");
+    fs::write(&owned, "Hello, world!").unwrap();
+    let mut vdb = vardbapi::VarDB::new();
+    // An old mtime, so that the content is always hashed, even when rewritten within the same second.
+    vdb.insert(owned.clone(), vardbapi::VarDBRecord {
+        metadata: vardbapi::FileMetadata::Regular {
+            mtime: Some(1586621688),
+            hash: vardbapi::FileHash::MD5([108, 211, 85, 109, 235, 13, 165, 75, 202, 6, 11, 76, 57, 71, 152, 57]),
+            xattrs: None,
+        },
+        atom: "app-misc/foo-1.0".into(),
+    });
+    let options = CheckOptions::default();
+
+    let mut changes = watch(vec![dir.path().into()], &vdb, &options).unwrap();
+    fs::write(&owned, "Goodbye").unwrap();
+    let result = changes.next().unwrap().unwrap();
+    assert_eq!((owned.clone(), FileStatus::Edited), (result.path, result.status));
+    assert_eq!(Some(true), result.hash_mismatch);
+
+    fs::write(&owned, "Hello, world!").unwrap();
+    let result = changes.next().unwrap().unwrap();
+    assert_eq!((owned, FileStatus::Touched), (result.path, result.status));
+}
+
+#[test]
+fn compare_custom_expectation() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("etc")).unwrap();
+    fs::write(dir.path().join("etc/foo"), "Hello, world!").unwrap();
+    let expected = |hash| vardbapi::FileMetadata::Regular { mtime: Some(0), hash, xattrs: None };
+    let options = CheckOptions { offset_root: dir.path().into(), ..Default::default() };
+
+    // echo -n 'Hello, world!' | md5sum
+    let right_hash = vardbapi::FileHash::MD5([108, 211, 85, 109, 235, 13, 165, 75, 202, 6, 11, 76, 57, 71, 152, 57]);
+    let result = compare(path::Path::new("/etc/foo"), &expected(right_hash), &options).unwrap();
+    assert_eq!((path::PathBuf::from("/etc/foo"), FileStatus::Touched, None), (result.path, result.status, result.owner));
+
+    let result = compare(path::Path::new("/etc/foo"), &expected(vardbapi::FileHash::MD5([1; 16])), &options).unwrap();
+    assert_eq!(FileStatus::Edited, result.status);
+    let result = compare(path::Path::new("/etc/bar"), &expected(vardbapi::FileHash::MD5([1; 16])), &options).unwrap();
+    assert_eq!(FileStatus::Absent, result.status);
+}
+
+#[test]
+fn compare_registered_hasher() {
+    struct Constant;
+
+    impl hasher::Hasher for Constant {
+        fn algorithm(&self) -> &str {
+            "constant"
+        }
+
+        fn hash(&self, _path: &path::Path) -> io::Result<Vec<u8>> {
+            Ok(vec![0xca, 0xfe])
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo");
+    fs::write(&path, "Hello, world!").unwrap();
+    let expected = |raw| vardbapi::FileMetadata::Regular {
+        mtime: Some(0),
+        hash: vardbapi::parse_hash(path::Path::new("CONTENTS"), 1, raw).unwrap(),
+        xattrs: None,
+    };
+
+    let result = compare(&path, &expected("constant:cafe"), &CheckOptions::default());
+    assert!(matches!(result, Err(Error::UnsupportedHash { .. })));
+
+    let mut hashers = Hashers::new();
+    hashers.register(Constant);
+    let options = CheckOptions { hashers, ..Default::default() };
+    assert_eq!(FileStatus::Touched, compare(&path, &expected("constant:cafe"), &options).unwrap().status);
+    assert_eq!(FileStatus::Edited, compare(&path, &expected("constant:beef"), &options).unwrap().status);
+    let result = compare(&path, &expected("other:cafe"), &options);
+    assert!(matches!(result, Err(Error::UnsupportedHash { .. })));
+}
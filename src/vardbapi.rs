@@ -1,25 +1,66 @@
 use std::collections;
-use std::io::BufRead;
 use std::num;
 use std::fmt;
 use std::error;
+use std::ffi;
 use std::io;
 use std::path;
 use std::fs;
+use std::time;
+
+use crate::sys::OsStrExt;
+
+mod trie;
+
+pub use trie::PathTrie;
 
 #[cfg(test)]
 mod tests;
 
-/**
+/*
  * Public structs and type aliases
  */
 
 /// A possible expected file hash.
 ///
-/// Based on supported values in vardbapi; for now, only MD5 is planned.
-#[derive(Eq, PartialEq, Debug)]
+/// As recorded in CONTENTS: a bare hex digest, told apart by its length (MD5, SHA-256 or
+/// BLAKE2B), `blake2b:<hex>`, or `<algorithm>:<hex>` for any other algorithm.
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub enum FileHash {
     MD5(super::MD5Hash),
+    SHA256(super::SHA256Hash),
+    /// A BLAKE2B digest, of any length up to `BLAKE2B_MAX_SIZE`.
+    ///
+    /// Verifying it requires the `blake2` feature.
+    Blake2b(Vec<u8>),
+    /// A `<algorithm>:<hex digest>` hash of another algorithm, e.g. `sha512:...`.
+    ///
+    /// Verifying it requires a `hasher::Hasher` for the algorithm, see `CheckOptions::hashers`.
+    Tagged {
+        algorithm: String,
+        digest: Vec<u8>,
+    },
+}
+
+/// Size of the longest (and default) BLAKE2B digest, in bytes.
+pub const BLAKE2B_MAX_SIZE: usize = 64;
+
+/// Optional tag before a BLAKE2B hash, required for digests shorter than `BLAKE2B_MAX_SIZE`.
+const BLAKE2B_PREFIX: &str = "blake2b:";
+
+impl FileHash {
+    /// Whether this is a placeholder recorded instead of an actual hash.
+    ///
+    /// Some builds record an all-zero hash when they couldn't compute one;
+    /// this is the only recognized placeholder.
+    pub fn is_placeholder(&self) -> bool {
+        match *self {
+            FileHash::MD5(ref hash) => hash.iter().all(|byte| *byte == 0),
+            FileHash::SHA256(ref hash) => hash.iter().all(|byte| *byte == 0),
+            FileHash::Blake2b(ref hash) => hash.iter().all(|byte| *byte == 0),
+            FileHash::Tagged { ref digest, .. } => digest.iter().all(|byte| *byte == 0),
+        }
+    }
 }
 
 /// Type alias for the mtime recorded in vardbapi.
@@ -27,13 +68,23 @@ pub enum FileHash {
 /// For now, only accurate to the second.
 type LowResSystemTime = u64;
 
+/// Extended attributes of a file, by name.
+pub type Xattrs = collections::BTreeMap<ffi::OsString, Vec<u8>>;
+
 /// Metadata from a vardbapi entry.
 ///
-#[derive(Eq, PartialEq, Debug)]
+/// CONTENTS has no entry type for sockets: those found on disk are either
+/// orphans, or replace another file type.
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub enum FileMetadata {
     Regular {
-        mtime: LowResSystemTime,
+        /// `None` for legacy entries recording no mtime; only the content can be compared then.
+        mtime: Option<LowResSystemTime>,
         hash: FileHash,
+        /// Recorded extended attributes, if the backend provides them.
+        ///
+        /// vardbapi's CONTENTS doesn't record them, so this is always `None` there.
+        xattrs: Option<Xattrs>,
     },
     Directory,
     Device,
@@ -49,6 +100,22 @@ pub enum FileMetadata {
 pub struct VarDBEntry {
     pub path: path::PathBuf,
     pub metadata: FileMetadata,
+    /// The package recording this entry, as `<category>/<name>-<version>`.
+    pub atom: String,
+}
+
+/// What the VarDB knows about a path.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct VarDBRecord {
+    pub metadata: FileMetadata,
+    /// The package owning the path, as `<category>/<name>-<version>`.
+    pub atom: String,
+}
+
+impl From<VarDBEntry> for VarDBRecord {
+    fn from(entry: VarDBEntry) -> Self {
+        VarDBRecord { metadata: entry.metadata, atom: entry.atom }
+    }
 }
 
 
@@ -60,7 +127,7 @@ impl VarDBEntry {
 
 
 /// Abstraction over the vardbapi.
-pub type VarDB = collections::HashMap<path::PathBuf, FileMetadata>;
+pub type VarDB = collections::HashMap<path::PathBuf, VarDBRecord>;
 
 
 /// Parse the VarDB.
@@ -68,17 +135,21 @@ pub type VarDB = collections::HashMap<path::PathBuf, FileMetadata>;
 /// This function returns an iterator containing both successfully parsed entries,
 /// and errors; this allows the caller to decide whether processing should stop
 /// at the first error or continue.
+///
+/// Portage's own files, e.g. `.keep` or lockfiles, are skipped; an unfinished
+/// merge (a `-MERGING-` atom) is reported as `AtomInvalidName`.
 pub fn parse_vdb<'a>(vdb_root: &'a path::Path) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>> + 'a, Error> {
     let categories = fs::read_dir(vdb_root);
     categories
         .map_err(|e| Error::VarDBUnreadable { path: vdb_root.into(), source: e})
         .map(
             |entries| entries
+            .filter(|category| category.as_ref().map_or(true, |entry| !is_reserved(&entry.path())))
             .flat_map(move |category| {
                 if let Ok(entry) = category {
-                    read_category(entry)
+                    read_category(entry.path())
                 } else {
-                    Err(Error::VarDBUnreadable { path: vdb_root.clone().into(), source: category.unwrap_err()})
+                    Err(Error::VarDBUnreadable { path: vdb_root.into(), source: category.unwrap_err()})
                 }.inline_err()
             }))
 }
@@ -87,132 +158,690 @@ pub fn parse_vdb<'a>(vdb_root: &'a path::Path) -> Result<impl Iterator<Item=Resu
 ///
 /// Processing will halt at the first error.
 pub fn get_vdb(vdb_root: &path::Path, bases: &super::SearchPaths) -> Result<VarDB, Error> {
+    get_vdb_with_stats(vdb_root, bases).map(|(vdb, _stats)| vdb)
+}
+
+/// Fetch the VarDB, along with every error met while parsing it.
+///
+/// Malformed entries and unreadable atoms are collected instead of halting;
+/// only an unreadable `vdb_root` fails the whole call.
+pub fn get_vdb_lenient(vdb_root: &path::Path, bases: &super::SearchPaths) -> Result<(VarDB, Vec<Error>), Error> {
+    let mut vdb = VarDB::new();
+    let mut errors = Vec::new();
+    for entry in parse_vdb(vdb_root)? {
+        match entry {
+            Ok(entry) if entry.in_tree(bases) => insert_entry(&mut vdb, entry),
+            Ok(_) => {},
+            Err(e) => errors.push(e),
+        }
+    }
+    Ok((vdb, errors))
+}
+
+/// Call `f` with each VarDB entry within `bases`, as soon as parsed.
+///
+/// Unlike `get_vdb`, entries aren't kept, nor deduplicated: a path recorded by
+/// several atoms is seen once per atom. Processing halts at the first error,
+/// from parsing or from `f`.
+pub fn for_each_entry(vdb_root: &path::Path, bases: &super::SearchPaths, mut f: impl FnMut(VarDBEntry) -> Result<(), Error>) -> Result<(), Error> {
+    for entry in parse_vdb(vdb_root)? {
+        let entry = entry?;
+        if entry.in_tree(bases) {
+            f(entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Same as `get_vdb`, reading category folders concurrently on rayon's global thread pool.
+///
+/// The VarDB is the same as with `get_vdb`. All categories are parsed before failing;
+/// the error reported is then the one `get_vdb` would have stopped at.
+#[cfg(feature = "parallel")]
+pub fn get_vdb_parallel(vdb_root: &path::Path, bases: &super::SearchPaths) -> Result<VarDB, Error> {
+    use rayon::prelude::*;
+
+    let categories = fs::read_dir(vdb_root)
+        .map_err(|e| Error::VarDBUnreadable { path: vdb_root.into(), source: e })?
+        .map(|category| category
+            .map(|entry| entry.path())
+            .map_err(|e| Error::VarDBUnreadable { path: vdb_root.into(), source: e }))
+        .filter(|category| category.as_ref().map_or(true, |path| !is_reserved(path)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let partial: Vec<Result<VarDB, Error>> = categories.into_par_iter()
+        .map(|category| {
+            let mut vdb = VarDB::new();
+            for entry in read_category(category)? {
+                let entry = entry?;
+                if entry.in_tree(bases) {
+                    insert_entry(&mut vdb, entry);
+                }
+            }
+            Ok(vdb)
+        })
+        .collect();
+    let mut vdb = VarDB::new();
+    for category in partial {
+        for (path, record) in category? {
+            insert_record(&mut vdb, path, record);
+        }
+    }
+    Ok(vdb)
+}
+
+/// Number of paths from the VarDB kept in `FilterStats::sample`.
+pub const FILTER_SAMPLE_SIZE: usize = 10;
+
+/// Statistics about the entries dropped when filtering the VarDB on search paths.
+#[derive(Eq, PartialEq, Debug, Default)]
+pub struct FilterStats {
+    /// Number of entries within the search paths
+    pub kept: usize,
+    /// Number of entries outside of the search paths
+    pub filtered: usize,
+    /// The first filtered paths, up to `FILTER_SAMPLE_SIZE`
+    pub sample: Vec<path::PathBuf>,
+}
+
+/// Fetch the VarDB, along with statistics about filtered entries.
+///
+/// Processing will halt at the first error.
+pub fn get_vdb_with_stats(vdb_root: &path::Path, bases: &super::SearchPaths) -> Result<(VarDB, FilterStats), Error> {
     let mut vdb = VarDB::new();
-    let filtered = parse_vdb(vdb_root)?.filter(
-        |entry| match entry {
-            Err(_) => true,
-            Ok(dbentry) => dbentry.in_tree(&bases),
-        });
-    for entry in filtered {
+    let mut stats = FilterStats::default();
+    for entry in parse_vdb(vdb_root)? {
+        let entry = entry?;
+        if entry.in_tree(bases) {
+            stats.kept += 1;
+            insert_entry(&mut vdb, entry);
+        } else {
+            stats.filtered += 1;
+            if stats.sample.len() < FILTER_SAMPLE_SIZE {
+                stats.sample.push(entry.path);
+            }
+        }
+    }
+    Ok((vdb, stats))
+}
+
+/// All records of each path, when several atoms may record the same one.
+pub type OwnersDB = collections::HashMap<path::PathBuf, Vec<VarDBRecord>>;
+
+/// Same as `get_vdb`, keeping the records of every atom owning a path, sorted by atom.
+pub fn get_vdb_all_owners(vdb_root: &path::Path, bases: &super::SearchPaths) -> Result<OwnersDB, Error> {
+    let mut vdb = OwnersDB::new();
+    for entry in parse_vdb(vdb_root)? {
         let entry = entry?;
-        vdb.insert(entry.path, entry.metadata);
+        if entry.in_tree(bases) {
+            let records = vdb.entry(entry.path.clone()).or_default();
+            // Within an atom, the last entry wins, as in `get_vdb`.
+            records.retain(|record| record.atom != entry.atom);
+            records.push(entry.into());
+        }
+    }
+    for records in vdb.values_mut() {
+        records.sort_by(|a, b| a.atom.cmp(&b.atom));
     }
     Ok(vdb)
 }
 
-fn read_category(direntry: fs::DirEntry) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>>, Error> {
-    let atoms = fs::read_dir(direntry.path());
+/// Add an entry to a VarDB.
+///
+/// When several atoms record the same path, the lexicographically greatest atom wins,
+/// whatever the reading order; within an atom, the last entry wins.
+fn insert_entry(vdb: &mut VarDB, entry: VarDBEntry) {
+    insert_record(vdb, entry.path.clone(), entry.into());
+}
+
+/// Add a record to a VarDB, unless the path is already recorded by a greater atom.
+fn insert_record(vdb: &mut VarDB, path: path::PathBuf, record: VarDBRecord) {
+    match vdb.get(&path) {
+        Some(existing) if existing.atom > record.atom => {},
+        _ => {
+            vdb.insert(path, record);
+        },
+    }
+}
+
+/// Find the most recent mtime of the atom folders, as a POSIX timestamp.
+///
+/// This approximates when packages were last installed or removed;
+/// returns `None` for an empty VarDB. Mtimes before the epoch are skipped.
+pub fn newest_atom_mtime(vdb_root: &path::Path) -> Result<Option<LowResSystemTime>, Error> {
+    let mut newest = None;
+    let categories = fs::read_dir(vdb_root).map_err(|e| Error::VarDBUnreadable { path: vdb_root.into(), source: e })?;
+    for category in categories {
+        let category = category.map_err(|e| Error::VarDBUnreadable { path: vdb_root.into(), source: e })?;
+        if is_reserved(&category.path()) {
+            continue;
+        }
+        let atoms = fs::read_dir(category.path()).map_err(|e| Error::CategoryUnreadable { path: category.path(), source: e })?;
+        for atom in atoms {
+            let atom = atom.map_err(|e| Error::CategoryUnreadable { path: category.path(), source: e })?;
+            if is_reserved(&atom.path()) {
+                continue;
+            }
+            let mtime = atom.metadata()
+                .and_then(|metadata| metadata.modified())
+                .map_err(|e| Error::AtomUnreadable { path: atom.path(), source: e })?;
+            // A pre-epoch mtime, e.g. from a restored backup, says nothing about recent installs.
+            newest = newest.max(mtime.duration_since(time::UNIX_EPOCH).ok().map(|elapsed| elapsed.as_secs()));
+        }
+    }
+    Ok(newest)
+}
+
+/// Read the `BUILD_TIME` of each atom, as a POSIX timestamp, by `<category>/<name>-<version>`.
+///
+/// Atoms without a `BUILD_TIME` file are left out.
+pub fn build_times(vdb_root: &path::Path) -> Result<collections::HashMap<String, LowResSystemTime>, Error> {
+    let mut times = collections::HashMap::new();
+    let categories = fs::read_dir(vdb_root).map_err(|e| Error::VarDBUnreadable { path: vdb_root.into(), source: e })?;
+    for category in categories {
+        let category = category.map_err(|e| Error::VarDBUnreadable { path: vdb_root.into(), source: e })?;
+        if is_reserved(&category.path()) {
+            continue;
+        }
+        let atoms = fs::read_dir(category.path()).map_err(|e| Error::CategoryUnreadable { path: category.path(), source: e })?;
+        for atom in atoms {
+            let atom = atom.map_err(|e| Error::CategoryUnreadable { path: category.path(), source: e })?;
+            if is_reserved(&atom.path()) {
+                continue;
+            }
+            let path = atom.path().join("BUILD_TIME");
+            let raw = match fs::read_to_string(&path) {
+                Ok(raw) => raw,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::AtomUnreadable { path, source: e }),
+            };
+            let build_time = raw.trim().parse()
+                .map_err(|e| Error::AtomInvalidBuildTime { path: path.clone(), raw: raw.trim().into(), source: e })?;
+            times.insert(atom_name(&atom.path()), build_time);
+        }
+    }
+    Ok(times)
+}
+
+/// Parse a single category of the VarDB, e.g. `dev-python`.
+///
+/// As for `parse_vdb`, errors are returned inline with the parsed entries.
+pub fn parse_category(vdb_root: &path::Path, category: &str) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>>, Error> {
+    read_category(vdb_root.join(category))
+}
+
+/// Fetch the entries of a single category, as a single HashMap.
+///
+/// Processing will halt at the first error.
+pub fn get_category_vdb(vdb_root: &path::Path, category: &str) -> Result<VarDB, Error> {
+    let mut vdb = VarDB::new();
+    for entry in parse_category(vdb_root, category)? {
+        let entry = entry?;
+        insert_entry(&mut vdb, entry);
+    }
+    Ok(vdb)
+}
+
+/// Parse the entries of a single package, e.g. `dev-lang/rust-1.41.1`.
+///
+/// Without a version, e.g. `dev-lang/rust`, all installed versions are parsed.
+/// As for `parse_vdb`, errors are returned inline with the parsed entries.
+pub fn parse_atom(vdb_root: &path::Path, atom: &str) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>>, Error> {
+    let not_found = || Error::AtomNotFound { atom: atom.into() };
+    let (category, package) = atom.split_once('/').ok_or_else(not_found)?;
+    let exact = vdb_root.join(atom);
+    let atom_dirs = if exact.is_dir() {
+        vec![exact]
+    } else {
+        let category_dir = vdb_root.join(category);
+        let entries = fs::read_dir(&category_dir)
+            .map_err(|e| Error::CategoryUnreadable { path: category_dir.clone(), source: e })?;
+        let mut atom_dirs = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::CategoryUnreadable { path: category_dir.clone(), source: e })?;
+            if is_version_of(entry.file_name().as_bytes(), package) {
+                atom_dirs.push(entry.path());
+            }
+        }
+        if atom_dirs.is_empty() {
+            return Err(not_found());
+        }
+        atom_dirs.sort();
+        atom_dirs
+    };
+    Ok(atom_dirs.into_iter().flat_map(|atom_dir| read_atom(atom_dir).inline_err()))
+}
+
+/// Whether an atom folder name, e.g. `rust-1.41.1`, is a version of `package`.
+///
+/// The version has to start with a digit: `foo-bar-1.0` isn't a version of `foo`.
+fn is_version_of(name: &[u8], package: &str) -> bool {
+    name.strip_prefix(package.as_bytes())
+        .and_then(|rest| rest.strip_prefix(b"-"))
+        .and_then(|version| version.first())
+        .is_some_and(u8::is_ascii_digit)
+}
+
+/// Fetch the entries of a single package, as a single HashMap.
+///
+/// Processing will halt at the first error.
+pub fn get_atom_vdb(vdb_root: &path::Path, atom: &str) -> Result<VarDB, Error> {
+    let mut vdb = VarDB::new();
+    for entry in parse_atom(vdb_root, atom)? {
+        let entry = entry?;
+        insert_entry(&mut vdb, entry);
+    }
+    Ok(vdb)
+}
+
+/// Prefix of the snapshot lines introducing the entries of an atom.
+const SNAPSHOT_ATOM_PREFIX: &str = "atom ";
+
+/// Write a snapshot of a VarDB, for later use with `read_snapshot`.
+///
+/// A snapshot uses the CONTENTS syntax, with one line per entry; entries are
+/// grouped by atom, each group starting with an `atom <category>/<name>-<version>` line.
+pub fn write_snapshot(vdb: &VarDB, out: &mut impl io::Write) -> io::Result<()> {
+    let mut paths: Vec<&path::PathBuf> = vdb.keys().collect();
+    paths.sort_by_key(|path| (&vdb[*path].atom, *path));
+    let mut current_atom = None;
+    for path in paths {
+        let record = &vdb[path];
+        if current_atom != Some(&record.atom) {
+            writeln!(out, "{}{}", SNAPSHOT_ATOM_PREFIX, record.atom)?;
+            current_atom = Some(&record.atom);
+        }
+        writeln!(out, "{}", ContentsLine { path, metadata: &record.metadata })?;
+    }
+    Ok(())
+}
+
+/// Read a snapshot written by `write_snapshot`.
+///
+/// `origin` is only used to locate errors.
+pub fn read_snapshot(reader: impl io::BufRead, origin: &path::Path) -> Result<VarDB, Error> {
+    let mut vdb = VarDB::new();
+    let mut atom = String::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| Error::EntryCorrupted { path: origin.into(), line: i + 1, source: e })?;
+        if let Some(name) = line.strip_prefix(SNAPSHOT_ATOM_PREFIX) {
+            atom = name.into();
+            continue;
+        }
+        let entry = read_entry(origin, i + 1, &atom, &line)?;
+        insert_entry(&mut vdb, entry);
+    }
+    Ok(vdb)
+}
+
+/// Load a snapshot file written by `write_snapshot`.
+pub fn load_snapshot(path: &path::Path) -> Result<VarDB, Error> {
+    let f = fs::File::open(path).map_err(|e| Error::SnapshotUnreadable { path: path.into(), source: e })?;
+    read_snapshot(io::BufReader::new(f), path)
+}
+
+/// Load a snapshot file, checking it against its detached signature first.
+///
+/// The signature is an ed25519 signature of the whole snapshot, stored as raw
+/// bytes next to it, in `<path>.sig`.
+#[cfg(feature = "sign")]
+pub fn load_signed_vdb(path: &path::Path, public_key: &ed25519_dalek::VerifyingKey) -> Result<VarDB, Error> {
+    let snapshot = fs::read(path).map_err(|e| Error::SnapshotUnreadable { path: path.into(), source: e })?;
+    let mut signature_path = path.as_os_str().to_owned();
+    signature_path.push(".sig");
+    let signature_path = path::PathBuf::from(signature_path);
+    let signature = fs::read(&signature_path)
+        .map_err(|e| Error::SnapshotUnreadable { path: signature_path, source: e })?;
+    let signature = ed25519_dalek::Signature::from_slice(&signature)
+        .map_err(|_| Error::SnapshotSignatureInvalid { path: path.into() })?;
+    public_key.verify_strict(&snapshot, &signature)
+        .map_err(|_| Error::SnapshotSignatureInvalid { path: path.into() })?;
+    read_snapshot(io::Cursor::new(snapshot), path)
+}
+
+/// Download a snapshot written by `write_snapshot` from an HTTP(S) server.
+#[cfg(feature = "http")]
+pub fn load_vdb_url(url: &str) -> Result<VarDB, Error> {
+    let unreachable = |e| Error::SnapshotUnreachable { url: url.into(), source: e };
+    let body = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(unreachable)?;
+    read_snapshot(io::Cursor::new(body), url.as_ref())
+}
+
+fn read_category(path: path::PathBuf) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>>, Error> {
+    let atoms = fs::read_dir(&path);
     atoms
-        .map_err(|e| Error::CategoryUnreadable { path: direntry.path(), source: e })
+        .map_err(|e| Error::CategoryUnreadable { path: path.clone(), source: e })
         .map(
             |entries| entries
+            .filter(|atom| atom.as_ref().map_or(true, |entry| !is_reserved(&entry.path())))
             .flat_map(move |atom| {
                 if let Ok(entry) = atom {
-                    read_atom(entry)
+                    read_atom(entry.path())
                 } else {
-                    Err(Error::CategoryUnreadable { path: direntry.path(), source: atom.unwrap_err() })
+                    Err(Error::CategoryUnreadable { path: path.clone(), source: atom.unwrap_err() })
                 }.inline_err()
             }))
 }
 
-fn read_atom(direntry: fs::DirEntry) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>>, Error> {
-    let fname = direntry.file_name().into_string().map_err(
-        |_| Error::AtomInvalidName { path: direntry.path() } )?;
-    if fname.starts_with("-MERGING-") {
-        return Err(Error::AtomInvalidName { path: direntry.path() });
-    }
-    let contents = direntry.path().join("CONTENTS");
-    let f = fs::File::open(&contents);
-    if let Err(e) = f {
-        return Err(Error::ContentsUnreadable { path: contents.clone(), source: e });
-    }
-    Ok(io::BufReader::new(f.unwrap())
-        .lines()
-        .enumerate()
-        .map(move |(i, l)| {
-            if let Ok(entry) = l {
-                read_entry(&contents, i + 1, &entry)
-            } else {
-                Err(Error::EntryCorrupted { path: contents.clone(), line: i + 1, source: l.unwrap_err() })
-            }
-        }))
+/// Whether a VarDB entry is one of Portage's own files rather than a category or atom,
+/// e.g. `.keep`, or a `.portage_lockfile` left while merging.
+fn is_reserved(path: &path::Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        let name = name.as_bytes();
+        name.starts_with(b".") || name.ends_with(b".portage_lockfile")
+    })
+}
+
+fn read_atom(atom_dir: path::PathBuf) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>>, Error> {
+    // Compare raw bytes: a non-UTF-8 folder name is still a valid atom.
+    if atom_dir.file_name().is_some_and(|name| name.as_bytes().starts_with(b"-MERGING-")) {
+        return Err(Error::AtomInvalidName { path: atom_dir });
+    }
+    let atom = atom_name(&atom_dir);
+    let (contents, reader) = atom_dir.as_path().open()?;
+    Ok(ContentsReader {
+        reader,
+        contents,
+        atom,
+        line: 0,
+        buffer: String::new(),
+    })
+}
+
+/// Where the CONTENTS of a package is read from.
+///
+/// Those of the VarDB are read from atom folders, but a package's CONTENTS may live elsewhere,
+/// e.g. within a binary package or an image; see `parse_contents`.
+pub trait ContentsSource {
+    /// Open the CONTENTS, along with a path naming it in errors.
+    fn open(&self) -> Result<(path::PathBuf, Box<dyn io::BufRead>), Error>;
+}
+
+/// An atom folder, e.g. `/var/db/pkg/dev-lang/rust-1.41.1`.
+///
+/// A missing CONTENTS is read as empty; with the `gzip` feature, `CONTENTS.gz` is read as well.
+impl ContentsSource for path::Path {
+    fn open(&self) -> Result<(path::PathBuf, Box<dyn io::BufRead>), Error> {
+        open_contents(self)
+    }
+}
+
+/// Parse the CONTENTS of `atom`, as `<category>/<name>-<version>`, from any source.
+///
+/// As for `parse_vdb`, errors are returned inline with the parsed entries.
+pub fn parse_contents<S: ContentsSource + ?Sized>(source: &S, atom: &str) -> Result<impl Iterator<Item=Result<VarDBEntry, Error>>, Error> {
+    let (contents, reader) = source.open()?;
+    Ok(ContentsReader {
+        reader,
+        contents,
+        atom: atom.into(),
+        line: 0,
+        buffer: String::new(),
+    })
+}
+
+/// Open the CONTENTS file of an atom folder, returning its path and a reader.
+///
+/// With the `gzip` feature, a `CONTENTS.gz` file is read instead when there's no `CONTENTS`.
+/// A missing CONTENTS, e.g. in a package being merged or unmerged, is read as an empty one.
+fn open_contents(atom_dir: &path::Path) -> Result<(path::PathBuf, Box<dyn io::BufRead>), Error> {
+    let contents = atom_dir.join("CONTENTS");
+    #[cfg(feature = "gzip")]
+    {
+        let compressed = atom_dir.join("CONTENTS.gz");
+        if !contents.exists() && compressed.exists() {
+            let file = open_regular(&compressed)?;
+            return Ok((compressed, Box::new(io::BufReader::new(flate2::read::GzDecoder::new(file)))));
+        }
+    }
+    match contents.symlink_metadata() {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((contents, Box::new(io::empty()))),
+        _ => {},
+    }
+    let file = open_regular(&contents)?;
+    Ok((contents, Box::new(io::BufReader::new(file))))
 }
 
-fn read_entry(contents: &path::Path, line: usize, entry: &str) -> Result<VarDBEntry, Error> {
-    if entry.len() < 5 {
-        return Err(Error::EntryMissingField { path: contents.into(), line: line, raw: entry.into() });
+fn open_regular(contents: &path::Path) -> Result<fs::File, Error> {
+    // Opening a FIFO would block, and a folder can't be read line by line.
+    let metadata = fs::metadata(contents).map_err(|e| Error::ContentsUnreadable { path: contents.into(), source: e })?;
+    if !metadata.is_file() {
+        return Err(Error::ContentsInvalidType { path: contents.into() });
     }
-    let prefix = &entry[0..3];
-    match prefix {
-        "obj" => parse_obj(&contents, line, &entry),
+    fs::File::open(contents).map_err(|e| Error::ContentsUnreadable { path: contents.into(), source: e })
+}
+
+/// Iterator over the entries of a CONTENTS file.
+///
+/// All lines are read into the same buffer, so that memory stays bounded by
+/// the longest line instead of growing with each entry.
+struct ContentsReader<R: io::BufRead> {
+    reader: R,
+    contents: path::PathBuf,
+    atom: String,
+    line: usize,
+    buffer: String,
+}
+
+impl<R: io::BufRead> Iterator for ContentsReader<R> {
+    type Item = Result<VarDBEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buffer.clear();
+        self.line += 1;
+        match self.reader.read_line(&mut self.buffer) {
+            Ok(0) => None,
+            Ok(_) => {
+                // Strip the line ending the same way as `BufRead::lines`.
+                if self.buffer.ends_with('\n') {
+                    self.buffer.pop();
+                    if self.buffer.ends_with('\r') {
+                        self.buffer.pop();
+                    }
+                }
+                Some(read_entry(&self.contents, self.line, &self.atom, &self.buffer))
+            },
+            Err(e) => Some(Err(Error::EntryCorrupted { path: self.contents.clone(), line: self.line, source: e })),
+        }
+    }
+}
+
+/// The `<category>/<name>-<version>` name of an atom folder.
+fn atom_name(atom_dir: &path::Path) -> String {
+    let name = |path: Option<&path::Path>| path
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{}/{}", name(atom_dir.parent()), name(Some(atom_dir)))
+}
+
+/// Parse a CONTENTS line.
+///
+/// A trailing `\r`, e.g. from a CRLF conversion, and leading whitespace are ignored;
+/// so is trailing whitespace after the last field of `obj` and `sym` entries. Other
+/// entries end with their path, which is kept verbatim as it may end with a space.
+fn read_entry(contents: &path::Path, line: usize, atom: &str, entry: &str) -> Result<VarDBEntry, Error> {
+    let entry = entry.strip_suffix('\r').unwrap_or(entry).trim_start();
+    let (kind, details) = entry.split_once(' ')
+        .filter(|(_, details)| !details.is_empty())
+        .ok_or(Error::EntryMissingField { path: contents.into(), line, raw: entry.into(), field: "path" })?;
+    match kind {
+        "obj" => parse_obj(contents, line, atom, entry.trim_end(), details.trim_end()),
         "dir" => Ok(VarDBEntry {
-            path: entry[4..].into(),
+            path: details.into(),
             metadata: FileMetadata::Directory,
+            atom: atom.into(),
         }),
         "dev" => Ok(VarDBEntry {
-            path: entry[4..].into(),
+            path: details.into(),
             metadata: FileMetadata::Device,
+            atom: atom.into(),
         }),
         "fif" => Ok(VarDBEntry {
-            path: entry[4..].into(),
+            path: details.into(),
             metadata: FileMetadata::Fifo,
+            atom: atom.into(),
         }),
-        "sym" => parse_sym(&contents, line, &entry),
-        _ => Err(Error::EntryUnhandledType { path: contents.into(), line: line, raw: entry.into() }),
+        "sym" => parse_sym(contents, line, atom, entry.trim_end(), details.trim_end()),
+        _ => Err(Error::EntryUnhandledType { path: contents.into(), line, raw: entry.into() }),
     }
 }
 
-fn parse_obj(contents: &path::Path, line: usize, entry: &str) -> Result<VarDBEntry, Error> {
-    let mut parts = entry[4..].rsplitn(3, ' ');
+/// Parse an `obj <path> <hash> <mtime>` entry.
+///
+/// Fields are separated by a single space, and the hash and mtime never contain any:
+/// they are peeled from the right, and everything left is the path, kept verbatim,
+/// including any inner, leading or trailing space. An empty path is invalid.
+///
+/// Legacy variants, as `obj <path> <mtime> <hash>` or `obj <path> <hash>`, are only
+/// tried when the standard syntax doesn't parse; so are extra fields after the mtime,
+/// as `obj <path> <hash> <mtime> <field>...`, which are ignored.
+/// Errors refer to the standard syntax.
+fn parse_obj(contents: &path::Path, line: usize, atom: &str, entry: &str, details: &str) -> Result<VarDBEntry, Error> {
+    parse_standard_obj(contents, line, atom, entry, details)
+        .or_else(|e| parse_legacy_obj(atom, details).ok_or(e))
+        .or_else(|e| parse_obj_trailing_fields(contents, line, atom, details).ok_or(e))
+}
+
+fn parse_standard_obj(contents: &path::Path, line: usize, atom: &str, entry: &str, details: &str) -> Result<VarDBEntry, Error> {
+    let mut parts = details.rsplitn(3, ' ');
     // mtime: POSIX timestamp
-    let mtime = parts.next().ok_or(Error::EntryMissingField { path: contents.into(), line, raw: entry.into() })?;
+    let mtime = parts.next().ok_or(Error::EntryMissingField { path: contents.into(), line, raw: entry.into(), field: "mtime" })?;
     let mtime = mtime.parse().map_err(|e| Error::EntryInvalidMTime { path: contents.into(), line, raw: mtime.into(), source: e })?;
 
     // Hash
-    let raw_hash = parts.next().ok_or(Error::EntryMissingField { path: contents.into(), line, raw: entry.into() })?;
-    let hash = parse_hash(&contents, line, &raw_hash)?;
+    let raw_hash = parts.next().ok_or(Error::EntryMissingField { path: contents.into(), line, raw: entry.into(), field: "hash" })?;
+    let hash = parse_hash(contents, line, raw_hash)?;
 
-    let path = parts.next().ok_or(Error::EntryMissingField { path: contents.into(), line, raw: entry.into() })?;
+    let path = parts.next()
+        .filter(|path| !path.is_empty())
+        .ok_or(Error::EntryMissingField { path: contents.into(), line, raw: entry.into(), field: "path" })?;
 
     Ok(VarDBEntry {
         path: path::PathBuf::from(path),
         metadata: FileMetadata::Regular {
-            mtime: mtime,
-            hash: hash,
+            mtime: Some(mtime),
+            hash,
+            xattrs: None,
         },
+        atom: atom.into(),
     })
 }
 
-fn parse_hash(contents: &path::Path, line: usize, raw_hash: &str) -> Result<FileHash, Error> {
-    if raw_hash.len() == 32 {
-        let md5_hash = parse_md5(&raw_hash)
-            .map_err(|e| Error::EntryInvalidHash { path: contents.into(), line, raw: raw_hash.into(), source: e })?;
-        Ok(FileHash::MD5(md5_hash))
-    } else {
-        Err(Error::EntryUnhandledHash { path: contents.into(), line, raw: raw_hash.into() })
+/// Parse an `obj <path> <hash> <mtime> <field>...` entry, dropping the trailing fields.
+///
+/// The rightmost `<hash> <mtime>` pair followed by at least one field wins.
+fn parse_obj_trailing_fields(contents: &path::Path, line: usize, atom: &str, details: &str) -> Option<VarDBEntry> {
+    let fields: Vec<&str> = details.split(' ').collect();
+    (2..fields.len().saturating_sub(1)).rev().find_map(|index| {
+        let raw_mtime = fields[index];
+        if raw_mtime.is_empty() || !raw_mtime.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mtime = raw_mtime.parse().ok()?;
+        let hash = parse_hash(contents, line, fields[index - 1]).ok()?;
+        let path = fields[..index - 1].join(" ");
+        if path.is_empty() {
+            return None;
+        }
+        Some(VarDBEntry {
+            path: path::PathBuf::from(path),
+            metadata: FileMetadata::Regular { mtime: Some(mtime), hash, xattrs: None },
+            atom: atom.into(),
+        })
+    })
+}
+
+/// Parse the legacy `obj` variants, telling the hash and the mtime apart by their syntax.
+///
+/// A path whose last word is all digits is read as the reordered variant.
+fn parse_legacy_obj(atom: &str, details: &str) -> Option<VarDBEntry> {
+    let as_hash = |raw: &str| match raw.len() {
+        32 => parse_md5(raw).ok().map(FileHash::MD5),
+        64 => parse_sha256(raw).ok().map(FileHash::SHA256),
+        128 if raw.is_ascii() => parse_hex(raw).ok().map(FileHash::Blake2b),
+        _ => None,
+    };
+    let as_mtime = |raw: &str| if raw.bytes().all(|b| b.is_ascii_digit()) { raw.parse().ok() } else { None };
+
+    let (rest, last) = details.rsplit_once(' ')?;
+    let hash = as_hash(last)?;
+    let (path, mtime) = match rest.rsplit_once(' ') {
+        Some((path, raw)) => match as_mtime(raw) {
+            Some(mtime) => (path, Some(mtime)),
+            None => (rest, None),
+        },
+        None => (rest, None),
+    };
+    if path.is_empty() {
+        return None;
     }
+    Some(VarDBEntry {
+        path: path::PathBuf::from(path),
+        metadata: FileMetadata::Regular { mtime, hash, xattrs: None },
+        atom: atom.into(),
+    })
 }
 
-fn parse_sym(contents: &path::Path, line: usize, entry: &str) -> Result<VarDBEntry, Error> {
-    const SEPARATOR : &'static str = " -> ";
-    let details = &entry[4..];
-    let sep_index = details.find(SEPARATOR).ok_or(
-        Error::EntryMissingField { path: contents.into(), line, raw: entry.into() })?;
+pub(crate) fn parse_hash(contents: &path::Path, line: usize, raw_hash: &str) -> Result<FileHash, Error> {
+    let invalid = |e| Error::EntryInvalidHash { path: contents.into(), line, raw: raw_hash.into(), source: e };
+    let unhandled = || Error::EntryUnhandledHash { path: contents.into(), line, raw: raw_hash.into() };
+    let bad_length = || Error::EntryInvalidHashLength { path: contents.into(), line, raw: raw_hash.into() };
+    if let Some(digest) = raw_hash.strip_prefix(BLAKE2B_PREFIX) {
+        // Hex digits are ASCII: this keeps the slicing in `parse_hex` on char boundaries.
+        if !digest.is_ascii() {
+            return Err(unhandled());
+        }
+        if digest.is_empty() || digest.len() % 2 != 0 || digest.len() > 2 * BLAKE2B_MAX_SIZE {
+            return Err(bad_length());
+        }
+        return Ok(FileHash::Blake2b(parse_hex(digest).map_err(invalid)?));
+    }
+    if let Some((algorithm, digest)) = raw_hash.split_once(':') {
+        let is_tag = !algorithm.is_empty()
+            && algorithm.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-' || byte == b'_');
+        if !is_tag || !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(unhandled());
+        }
+        if digest.is_empty() || digest.len() % 2 != 0 {
+            return Err(bad_length());
+        }
+        return Ok(FileHash::Tagged { algorithm: algorithm.into(), digest: parse_hex(digest).map_err(invalid)? });
+    }
+    match raw_hash.len() {
+        32 => Ok(FileHash::MD5(parse_md5(raw_hash).map_err(invalid)?)),
+        64 => Ok(FileHash::SHA256(parse_sha256(raw_hash).map_err(invalid)?)),
+        128 if raw_hash.is_ascii() => Ok(FileHash::Blake2b(parse_hex(raw_hash).map_err(invalid)?)),
+        // Hex digits only: a known algorithm, truncated or padded.
+        _ if raw_hash.bytes().all(|byte| byte.is_ascii_hexdigit()) => Err(bad_length()),
+        _ => Err(unhandled()),
+    }
+}
 
-    let path = &details[..sep_index];
+/// Parse a `sym <path> -> <dest> <mtime>` entry.
+///
+/// The mtime is the last word; the path and destination may both contain ` -> `,
+/// which makes the line ambiguous. As Portage does, the line is split at the first
+/// separator; the check resolves the ambiguity against the disk.
+fn parse_sym(contents: &path::Path, line: usize, atom: &str, entry: &str, details: &str) -> Result<VarDBEntry, Error> {
+    const SEPARATOR : &str = " -> ";
 
     let mtime_index = details.rfind(' ').ok_or(
-        Error::EntryMissingField { path: contents.into(), line, raw: entry.into() })?;
+        Error::EntryMissingField { path: contents.into(), line, raw: entry.into(), field: "mtime" })?;
     let mtime = &details[1 + mtime_index..];
     let mtime = mtime.parse().map_err(|e| Error::EntryInvalidMTime { path: contents.into(), line, raw: mtime.into(), source: e })?;
 
-    let dest = &details[sep_index + SEPARATOR.len()..mtime_index];
+    let (path, dest) = details[..mtime_index].split_once(SEPARATOR).ok_or(
+        Error::EntryMissingField { path: contents.into(), line, raw: entry.into(), field: "destination" })?;
     Ok(VarDBEntry {
         path: path::PathBuf::from(path),
         metadata: FileMetadata::Symlink {
-            mtime: mtime,
+            mtime,
             dest: path::PathBuf::from(dest),
         },
+        atom: atom.into(),
     })
 }
 
@@ -239,8 +868,97 @@ fn parse_md5(text: &str) -> Result<super::MD5Hash, num::ParseIntError> {
     ])
 }
 
+fn parse_sha256(text: &str) -> Result<super::SHA256Hash, num::ParseIntError> {
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(hash)
+}
+
+/// Parse an even-length hex string, of any size.
+fn parse_hex(text: &str) -> Result<Vec<u8>, num::ParseIntError> {
+    (0..text.len() / 2).map(|i| u8::from_str_radix(&text[2 * i..2 * i + 2], 16)).collect()
+}
+
+
+/// Formats an entry as a CONTENTS line.
+struct ContentsLine<'a> {
+    path: &'a path::Path,
+    metadata: &'a FileMetadata,
+}
+
+impl fmt::Display for ContentsLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.metadata {
+            FileMetadata::Regular { mtime: Some(mtime), ref hash, .. } => {
+                write!(f, "obj {} {} {}", self.path.display(), hash, mtime)
+            },
+            FileMetadata::Regular { mtime: None, ref hash, .. } => {
+                write!(f, "obj {} {}", self.path.display(), hash)
+            },
+            FileMetadata::Symlink { mtime, ref dest } => {
+                write!(f, "sym {} -> {} {}", self.path.display(), dest.display(), mtime)
+            },
+            FileMetadata::Directory => write!(f, "dir {}", self.path.display()),
+            FileMetadata::Device => write!(f, "dev {}", self.path.display()),
+            FileMetadata::Fifo => write!(f, "fif {}", self.path.display()),
+        }
+    }
+}
+
+impl fmt::Display for VarDBEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        ContentsLine { path: &self.path, metadata: &self.metadata }.fmt(f)
+    }
+}
+
+/// Serialized as its lowercase hex form, as in CONTENTS.
+#[cfg(feature = "serde")]
+impl serde::Serialize for FileHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
-/**
+impl fmt::Display for FileHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileHash::MD5(ref hash) => {
+                for byte in hash.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            },
+            FileHash::SHA256(ref hash) => {
+                for byte in hash.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            },
+            FileHash::Blake2b(ref hash) => {
+                // Without the tag, shorter digests would read back as other algorithms.
+                if hash.len() != BLAKE2B_MAX_SIZE {
+                    f.write_str(BLAKE2B_PREFIX)?;
+                }
+                for byte in hash.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            },
+            FileHash::Tagged { ref algorithm, ref digest } => {
+                write!(f, "{}:", algorithm)?;
+                for byte in digest.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+
+/*
  * Errors
  */
 
@@ -266,11 +984,19 @@ pub enum Error {
     AtomInvalidName {
         path: path::PathBuf,
     },
+    /// No installed package matches a requested atom
+    AtomNotFound {
+        atom: String,
+    },
     /// A `CONTENTS` file couldn't be read
     ContentsUnreadable {
         path: path::PathBuf,
         source: io::Error,
     },
+    /// A `CONTENTS` path isn't a regular file, e.g. a folder
+    ContentsInvalidType {
+        path: path::PathBuf,
+    },
     /// Reading a line failed
     /// e.g. invalid UTF-8
     EntryCorrupted {
@@ -288,15 +1014,12 @@ pub enum Error {
     },
     /// Missing a field for an entry.
     ///
-    /// Since the list of fields differ by entry type,
-    /// we'll stick to a generic error.
-    ///
-    /// XXX: Consider describing the missing field(s), or
-    /// at least the expected syntax.
+    /// `field` names the first missing one, e.g. `hash` or `destination`.
     EntryMissingField {
         path: path::PathBuf,
         line: usize,
         raw: String,
+        field: &'static str,
     },
     /// Failed to parse the `mtime` field.
     EntryInvalidMTime {
@@ -311,6 +1034,12 @@ pub enum Error {
         line: usize,
         raw: String,
     },
+    /// Hexadecimal hash whose length matches no supported algorithm, e.g. truncated
+    EntryInvalidHashLength {
+        path: path::PathBuf,
+        line: usize,
+        raw: String,
+    },
     /// Failed to parse the `hash` field of an `obj` entry
     EntryInvalidHash {
         path: path::PathBuf,
@@ -325,6 +1054,28 @@ pub enum Error {
         raw: String,
         source: num::ParseIntError,
     },
+    /// Unable to open a VarDB snapshot
+    SnapshotUnreadable {
+        path: path::PathBuf,
+        source: io::Error,
+    },
+    /// Failed to parse the `BUILD_TIME` of an atom
+    AtomInvalidBuildTime {
+        path: path::PathBuf,
+        raw: String,
+        source: num::ParseIntError,
+    },
+    /// Unable to download a VarDB snapshot
+    #[cfg(feature = "http")]
+    SnapshotUnreachable {
+        url: String,
+        source: reqwest::Error,
+    },
+    /// A VarDB snapshot doesn't match its signature
+    #[cfg(feature = "sign")]
+    SnapshotSignatureInvalid {
+        path: path::PathBuf,
+    },
 }
 
 
@@ -343,17 +1094,23 @@ impl fmt::Display for Error {
             Error::AtomInvalidName {ref path} => {
                 write!(f, "{}: potentially corrupted atom found", path.display())
             },
+            Error::AtomNotFound {ref atom} => {
+                write!(f, "{}: no such installed package", atom)
+            },
             Error::ContentsUnreadable {ref path, ref source} => {
                 write!(f, "{}: could not open CONTENTS file: {}", path.display(), source)
             },
+            Error::ContentsInvalidType {ref path} => {
+                write!(f, "{}: CONTENTS isn't a regular file", path.display())
+            },
             Error::EntryCorrupted {ref path, line, ref source} => {
                 write!(f, "{}:{}: entry corrupted: {}", path.display(), line, source)
             },
             Error::EntryUnhandledType {ref path, line, ref raw} => {
                 write!(f, "{}:{}: unhandled entry type in \"{}\"", path.display(), line, raw)
             },
-            Error::EntryMissingField {ref path, line, ref raw} => {
-                write!(f, "{}:{}: missing field(s) in \"{}\"", path.display(), line, raw)
+            Error::EntryMissingField {ref path, line, ref raw, field} => {
+                write!(f, "{}:{}: missing {} in \"{}\"", path.display(), line, field, raw)
             },
             Error::EntryInvalidMTime {ref path, line, ref raw, ref source} => {
                 write!(f, "{}:{}: could not parse mtime \"{}\": {}", path.display(), line, raw, source)
@@ -361,6 +1118,9 @@ impl fmt::Display for Error {
             Error::EntryUnhandledHash {ref path, line, ref raw} => {
                 write!(f, "{}:{}: unhandled hash found: {}", path.display(), line, raw)
             },
+            Error::EntryInvalidHashLength {ref path, line, ref raw} => {
+                write!(f, "{}:{}: hash \"{}\" has an unexpected length of {} characters", path.display(), line, raw, raw.len())
+            },
             Error::EntryInvalidHash {ref path, line, ref raw, ref source} => {
                 write!(f, "{}:{}: could not parse hash \"{}\": {}", path.display(), line, raw, source)
             },
@@ -368,6 +1128,20 @@ impl fmt::Display for Error {
                 write!(f, "{}:{}: could not parse symlink destination \"{}\": {}",
                        path.display(), line, raw, source)
             },
+            Error::SnapshotUnreadable {ref path, ref source} => {
+                write!(f, "{}: could not open VarDB snapshot: {}", path.display(), source)
+            },
+            Error::AtomInvalidBuildTime {ref path, ref raw, ref source} => {
+                write!(f, "{}: could not parse build time \"{}\": {}", path.display(), raw, source)
+            },
+            #[cfg(feature = "http")]
+            Error::SnapshotUnreachable {ref url, ref source} => {
+                write!(f, "{}: could not download VarDB snapshot: {}", url, source)
+            },
+            #[cfg(feature = "sign")]
+            Error::SnapshotSignatureInvalid {ref path} => {
+                write!(f, "{}: VarDB snapshot signature doesn't verify", path.display())
+            },
         }
     }
 }
@@ -379,14 +1153,23 @@ impl error::Error for Error {
             Error::CategoryUnreadable { ref source, .. } => Some(source),
             Error::AtomUnreadable { ref source, .. } => Some(source),
             Error::AtomInvalidName { .. } => None,
+            Error::AtomNotFound { .. } => None,
             Error::ContentsUnreadable { ref source, .. } => Some(source),
+            Error::ContentsInvalidType { .. } => None,
             Error::EntryCorrupted { ref source, .. } => Some(source),
             Error::EntryUnhandledType { .. } => None,
             Error::EntryMissingField { .. } => None,
             Error::EntryInvalidMTime { ref source, .. } => Some(source),
             Error::EntryUnhandledHash { .. } => None,
+            Error::EntryInvalidHashLength { .. } => None,
             Error::EntryInvalidHash { ref source, .. } => Some(source),
             Error::EntryInvalidDest { ref source, .. } => Some(source),
+            Error::SnapshotUnreadable { ref source, .. } => Some(source),
+            Error::AtomInvalidBuildTime { ref source, .. } => Some(source),
+            #[cfg(feature = "http")]
+            Error::SnapshotUnreachable { ref source, .. } => Some(source),
+            #[cfg(feature = "sign")]
+            Error::SnapshotSignatureInvalid { .. } => None,
         }
     }
 }
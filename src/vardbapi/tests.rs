@@ -1,6 +1,10 @@
 use super::*;
 
-const EXAMPLE_PATH: &'static str = "/example";
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+const EXAMPLE_PATH: &str = "/example";
 
 #[test]
 fn parse_hash_md5() {
@@ -8,7 +12,7 @@ fn parse_hash_md5() {
     let raw_hash = "6cd3556deb0da54bca060b4c39479839";
     assert_ok_eq(
         FileHash::MD5([108, 211, 85, 109, 235, 13, 165, 75, 202, 6, 11, 76, 57, 71, 152, 57]),
-        parse_hash(EXAMPLE_PATH.as_ref(), 42, &raw_hash),
+        parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash),
     );
 }
 
@@ -18,7 +22,7 @@ fn parse_hash_md5_mixed_case() {
     let raw_hash = "6CD3556DEB0DA54BCa060b4c39479839";
     assert_ok_eq(
         FileHash::MD5([108, 211, 85, 109, 235, 13, 165, 75, 202, 6, 11, 76, 57, 71, 152, 57]),
-        parse_hash(EXAMPLE_PATH.as_ref(), 42, &raw_hash),
+        parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash),
     );
 }
 
@@ -26,15 +30,76 @@ fn parse_hash_md5_mixed_case() {
 fn parse_hash_md5_bad() {
     // Hash with invalid chars for hex
     let raw_hash = "6cd3556deZ0da54!ca060=4c39479839";
-    let result = parse_hash(EXAMPLE_PATH.as_ref(), 42, &raw_hash);
+    let result = parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash);
     assert!(result.is_err());
 }
 
 #[test]
-fn parse_hash_unhandled() {
+fn parse_hash_sha256() {
+    // echo -n 'Hello, world!' | sha256sum
+    let raw_hash = "315f5bdb76d078c43b8ac0064e4a0164612b1fce77c869345bfc94c75894edd3";
+    let hash = parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash).unwrap();
+    assert_eq!(
+        FileHash::SHA256([
+            49, 95, 91, 219, 118, 208, 120, 196, 59, 138, 192, 6, 78, 74, 1, 100,
+            97, 43, 31, 206, 119, 200, 105, 52, 91, 252, 148, 199, 88, 148, 237, 211,
+        ]),
+        hash,
+    );
+    assert_eq!(raw_hash, hash.to_string());
+}
+
+#[test]
+fn parse_hash_blake2b() {
+    // echo -n 'Hello, world!' | b2sum
+    let raw_hash = "a2764d133a16816b5847a737a786f2ece4c148095c5faa73e24b4cc5d666c3e45ec271504e14dc6127ddfce4e144fb23b91a6f7b04b53d695502290722953b0f";
+    let hash = parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash).unwrap();
+    assert!(matches!(hash, FileHash::Blake2b(ref digest) if digest.len() == 64 && digest[..2] == [0xa2, 0x76]));
+    assert_eq!(raw_hash, hash.to_string());
+    assert_eq!(hash, parse_hash(EXAMPLE_PATH.as_ref(), 42, &format!("blake2b:{}", raw_hash)).unwrap());
+
+    // echo -n 'Hello, world!' | b2sum -l 256
+    let raw_hash = "blake2b:b5da441cfe72ae042ef4d2b17742907f675de4da57462d4c3609c2e2ed755970";
+    let hash = parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash).unwrap();
+    assert!(matches!(hash, FileHash::Blake2b(ref digest) if digest.len() == 32));
+    assert_eq!(raw_hash, hash.to_string());
+
+    for raw_hash in ["blake2b:", "blake2b:abc"] {
+        assert!(matches!(parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash), Err(Error::EntryInvalidHashLength { .. })));
+    }
+    assert!(matches!(parse_hash(EXAMPLE_PATH.as_ref(), 42, "blake2b:éé"), Err(Error::EntryUnhandledHash { .. })));
+}
+
+#[test]
+fn parse_hash_unexpected_length() {
+    for raw_hash in ["6cd3556deb0da54bca060b4c3947983", "6cd3556deb0da54bca060b4c394798390"] {
+        let error = parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash).unwrap_err();
+        assert!(matches!(error, Error::EntryInvalidHashLength { .. }));
+        assert_eq!(format!("/example:42: hash \"{}\" has an unexpected length of {} characters", raw_hash, raw_hash.len()), error.to_string());
+    }
+    // Not even hexadecimal: some other algorithm.
+    for raw_hash in ["SHA512:6cd3556deb0da54bca060b4c39479839", "6cd3556deb0da54bca060b4c3947983z0"] {
+        assert!(matches!(parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash), Err(Error::EntryUnhandledHash { .. })));
+    }
+}
+
+#[test]
+fn parse_hash_tagged() {
     let raw_hash = "sha1:943a702d06f34599aee1f8da8ef9f7296031d699";
-    let result = parse_hash(EXAMPLE_PATH.as_ref(), 42, &raw_hash);
-    assert!(result.is_err());
+    let hash = parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash).unwrap();
+    assert!(matches!(hash, FileHash::Tagged { ref algorithm, ref digest } if algorithm == "sha1" && digest.len() == 20));
+    assert_eq!(raw_hash, hash.to_string());
+
+    assert!(matches!(parse_hash(EXAMPLE_PATH.as_ref(), 42, "sha3-256:abc"), Err(Error::EntryInvalidHashLength { .. })));
+    for raw_hash in [":943a702d", "sha1:943a702z", "sha 1:943a702d"] {
+        assert!(matches!(parse_hash(EXAMPLE_PATH.as_ref(), 42, raw_hash), Err(Error::EntryUnhandledHash { .. })));
+    }
+}
+
+#[test]
+fn file_hash_placeholder() {
+    assert!(FileHash::MD5([0; 16]).is_placeholder());
+    assert!(!FileHash::MD5([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]).is_placeholder());
 }
 
 #[test]
@@ -44,14 +109,110 @@ fn read_entry_obj() {
         VarDBEntry {
             path: path::PathBuf::from("/usr/bin/rustc-1.41.1"),
             metadata: FileMetadata::Regular {
-                mtime: 1586621688,
+                mtime: Some(1586621688),
                 hash: FileHash::MD5([27, 204, 143, 239, 188, 25, 186, 63, 175, 81, 86, 75, 242, 160, 225, 128]),
+                xattrs: None,
             },
+            atom: "dev-lang/rust-1.41.1".into(),
         },
-        read_entry(EXAMPLE_PATH.as_ref(), 42, &raw_line),
+        read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line),
     );
 }
 
+#[test]
+fn read_entry_obj_reordered() {
+    let raw_line = "obj /usr/bin/rustc-1.41.1 1586621688 1bcc8fefbc19ba3faf51564bf2a0e180";
+    assert_ok_eq(
+        VarDBEntry {
+            path: path::PathBuf::from("/usr/bin/rustc-1.41.1"),
+            metadata: FileMetadata::Regular {
+                mtime: Some(1586621688),
+                hash: FileHash::MD5([27, 204, 143, 239, 188, 25, 186, 63, 175, 81, 86, 75, 242, 160, 225, 128]),
+                xattrs: None,
+            },
+            atom: "dev-lang/rust-1.41.1".into(),
+        },
+        read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line),
+    );
+}
+
+#[test]
+fn read_entry_obj_without_mtime() {
+    let raw_line = "obj /usr/share/doc/rust 1.41.1/README 1bcc8fefbc19ba3faf51564bf2a0e180";
+    assert_ok_eq(
+        VarDBEntry {
+            path: path::PathBuf::from("/usr/share/doc/rust 1.41.1/README"),
+            metadata: FileMetadata::Regular {
+                mtime: None,
+                hash: FileHash::MD5([27, 204, 143, 239, 188, 25, 186, 63, 175, 81, 86, 75, 242, 160, 225, 128]),
+                xattrs: None,
+            },
+            atom: "dev-lang/rust-1.41.1".into(),
+        },
+        read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line),
+    );
+}
+
+#[test]
+fn read_entry_obj_trailing_fields() {
+    let expected = |path: &str| VarDBEntry {
+        path: path::PathBuf::from(path),
+        metadata: FileMetadata::Regular {
+            mtime: Some(1586621688),
+            hash: FileHash::MD5([27, 204, 143, 239, 188, 25, 186, 63, 175, 81, 86, 75, 242, 160, 225, 128]),
+            xattrs: None,
+        },
+        atom: "dev-lang/rust-1.41.1".into(),
+    };
+    for raw_line in [
+        "obj /usr/bin/rustc-1.41.1 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688 extra",
+        "obj /usr/bin/rustc-1.41.1 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688 extra 42",
+    ] {
+        assert_ok_eq(expected("/usr/bin/rustc-1.41.1"), read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line));
+    }
+    let raw_line = "obj /usr/share/foo bar 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688 extra";
+    assert_ok_eq(expected("/usr/share/foo bar"), read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line));
+
+    // Without a hash and mtime before them, trailing words still fail, on the mtime.
+    let raw_line = "obj /usr/bin/rustc-1.41.1 1586621688 extra";
+    assert!(matches!(read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line), Err(Error::EntryInvalidMTime { .. })));
+}
+
+#[test]
+fn read_entry_obj_spaces() {
+    for path in ["/usr/share/foo bar/baz.txt", "/usr/share/fonts/Foo Sans .ttf", "/usr/share/foo/trailing "] {
+        let raw_line = format!("obj {} 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688", path);
+        let entry = read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", &raw_line).unwrap();
+        assert_eq!(path::Path::new(path), entry.path);
+    }
+}
+
+#[test]
+fn read_entry_obj_empty_path() {
+    for raw_line in ["obj  1bcc8fefbc19ba3faf51564bf2a0e180 1586621688", "obj  1bcc8fefbc19ba3faf51564bf2a0e180"] {
+        let result = read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line);
+        assert!(result.is_err(), "{}", raw_line);
+    }
+}
+
+#[test]
+fn read_entry_stray_whitespace() {
+    let parse = |raw_line: &str| read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line).unwrap();
+    let expected = parse("obj /usr/bin/foo 1bcc8fefbc19ba3faf51564bf2a0e180 123");
+    assert_eq!(expected, parse("obj /usr/bin/foo 1bcc8fefbc19ba3faf51564bf2a0e180 123\r"));
+    assert_eq!(expected, parse("  obj /usr/bin/foo 1bcc8fefbc19ba3faf51564bf2a0e180 123 \t\r"));
+    assert_eq!(parse("sym /usr/bin/bar -> foo 123"), parse("sym /usr/bin/bar -> foo 123 \r"));
+    // A folder's path may end with a space.
+    assert_eq!(path::Path::new("/usr/share/foo "), parse("dir /usr/share/foo \r").path);
+}
+
+#[test]
+fn read_entry_obj_invalid_keeps_standard_error() {
+    let raw_line = "obj /usr/bin/rustc-1.41.1 1bcc8fefbc19ba3faf51564bf2a0e180 yesterday";
+    let result = read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line);
+    assert!(matches!(result, Err(Error::EntryInvalidMTime { .. })));
+}
+
 #[test]
 fn read_entry_sym() {
     let raw_line = "sym /usr/bin/rustc -> rustc-1.41.1 1586621688";
@@ -62,16 +223,61 @@ fn read_entry_sym() {
                 mtime: 1586621688,
                 dest: path::PathBuf::from("rustc-1.41.1"),
             },
+            atom: "dev-lang/rust-1.41.1".into(),
         },
-        read_entry(EXAMPLE_PATH.as_ref(), 42, &raw_line),
+        read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line),
     );
 }
 
+#[test]
+fn read_entry_sym_dest_with_separator() {
+    let raw_line = "sym /usr/bin/rustc -> rustc -> 1.41.1 1586621688";
+    let entry = read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw_line).unwrap();
+    assert_eq!(path::Path::new("/usr/bin/rustc"), entry.path);
+    assert_eq!(FileMetadata::Symlink { mtime: 1586621688, dest: path::PathBuf::from("rustc -> 1.41.1") }, entry.metadata);
+    assert_eq!(raw_line, entry.to_string());
+}
+
+#[test]
+fn read_entry_sym_missing_dest() {
+    let result = read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", "sym /usr/bin/rustc -> 1586621688");
+    assert!(matches!(result, Err(Error::EntryMissingField { field: "destination", .. })));
+}
+
+#[test]
+fn read_entry_malformed_type() {
+    for raw in ["ob", "obé /usr/bin/rustc", "objé /usr/bin/rustc", "é", "objx /usr/bin/rustc"].iter().copied() {
+        let result = read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw);
+        assert!(matches!(result, Err(Error::EntryMissingField { .. }) | Err(Error::EntryUnhandledType { .. })), "{}: {:?}", raw, result);
+    }
+}
+
+#[test]
+fn read_entry_truncated_fields() {
+    let cases = [
+        ("obj 1586621688", "hash"),
+        ("obj 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688", "path"),
+        ("sym /usr/bin/rustc", "mtime"),
+        ("sym /usr/bin/rustc 1586621688", "destination"),
+        ("dir", "path"),
+    ];
+    for (raw, missing) in cases.iter().copied() {
+        match read_entry(EXAMPLE_PATH.as_ref(), 42, "dev-lang/rust-1.41.1", raw) {
+            Err(e @ Error::EntryMissingField { .. }) => {
+                assert!(matches!(e, Error::EntryMissingField { field, .. } if field == missing), "{}: {}", raw, e);
+                assert!(e.to_string().contains(&format!("missing {} in", missing)), "{}", e);
+            },
+            result => panic!("{}: unexpected {:?}", raw, result),
+        }
+    }
+}
+
 #[test]
 fn vardbentry_in_tree() {
     let entry = VarDBEntry {
         path: path::PathBuf::from("/usr/lib/rustlib"),
         metadata: FileMetadata::Directory,
+        atom: "dev-lang/rust-1.41.1".into(),
     };
     assert!(entry.in_tree(&vec![path::PathBuf::from("/etc"), path::PathBuf::from("/usr/lib")]));
 }
@@ -81,11 +287,373 @@ fn vardbentry_not_in_tree() {
     let entry = VarDBEntry {
         path: path::PathBuf::from("/usr/lib64/rustlib"),
         metadata: FileMetadata::Directory,
+        atom: "dev-lang/rust-1.41.1".into(),
     };
     assert!(! entry.in_tree(&vec![path::PathBuf::from("/etc"), path::PathBuf::from("/usr/lib")]));
 }
 
-fn assert_ok_eq<T: PartialEq + fmt::Debug>(expected: T, value: Result<T, Error>) -> () {
+/// Create an atom folder holding the given CONTENTS.
+fn write_atom(vdb_root: &path::Path, category: &str, atom: &OsStr, contents: &str) {
+    let atom_dir = vdb_root.join(category).join(atom);
+    fs::create_dir_all(&atom_dir).unwrap();
+    fs::write(atom_dir.join("CONTENTS"), contents).unwrap();
+}
+
+#[test]
+fn for_each_entry_counts() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/foo\ndir /usr/share/common\ndir /etc/foo\n");
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("bar-1.0"), "dir /usr/share/bar\ndir /usr/share/common\n");
+    let mut count = 0;
+    for_each_entry(vdb_root.path(), &vec![path::PathBuf::from("/usr")], |entry| {
+        assert!(entry.path.starts_with("/usr"));
+        count += 1;
+        Ok(())
+    }).unwrap();
+    assert_eq!(4, count);
+
+    // The callback's errors stop the walk.
+    let mut seen = 0;
+    let result = for_each_entry(vdb_root.path(), &vec![path::PathBuf::from("/")], |entry| {
+        seen += 1;
+        Err(Error::AtomNotFound { atom: entry.atom })
+    });
+    assert!(matches!(result, Err(Error::AtomNotFound { .. })));
+    assert_eq!(1, seen);
+}
+
+#[test]
+fn get_vdb_disjoint_bases() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /etc/foo\ndir /usr/lib/foo\ndir /usr/share/foo\n");
+    let vdb = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/etc"), path::PathBuf::from("/usr/lib")]).unwrap();
+    let mut paths: Vec<&path::Path> = vdb.keys().map(|path| path.as_path()).collect();
+    paths.sort();
+    assert_eq!(vec![path::Path::new("/etc/foo"), path::Path::new("/usr/lib/foo")], paths);
+}
+
+#[cfg(unix)]
+#[test]
+fn get_vdb_non_utf8_atom() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::from_bytes(b"foo-\xe9-1.0"), "dir /usr/share/foo\n");
+    let vdb = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]).unwrap();
+    let record = vdb.get(path::Path::new("/usr/share/foo")).unwrap();
+    assert_eq!(FileMetadata::Directory, record.metadata);
+    assert_eq!("app-misc/foo-\u{fffd}-1.0", record.atom);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn get_vdb_gzipped_contents() {
+    use std::io::Write;
+
+    let contents = "dir /usr/share/foo\nobj /usr/share/foo/bar 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688\nsym /usr/bin/foo -> bar 1586621688\n";
+    let plain = tempfile::tempdir().unwrap();
+    write_atom(plain.path(), "app-misc", OsStr::new("foo-1.0"), contents);
+    let compressed = tempfile::tempdir().unwrap();
+    let atom_dir = compressed.path().join("app-misc/foo-1.0");
+    fs::create_dir_all(&atom_dir).unwrap();
+    let mut encoder = flate2::write::GzEncoder::new(fs::File::create(atom_dir.join("CONTENTS.gz")).unwrap(), flate2::Compression::default());
+    encoder.write_all(contents.as_bytes()).unwrap();
+    encoder.finish().unwrap();
+
+    let bases = vec![path::PathBuf::from("/usr")];
+    let vdb = get_vdb(compressed.path(), &bases).unwrap();
+    assert_eq!(3, vdb.len());
+    assert_eq!(get_vdb(plain.path(), &bases).unwrap(), vdb);
+}
+
+#[test]
+fn get_vdb_lenient_collects_errors() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/foo\nobj /usr/share/foo/bar nothex 1586621688\n");
+    let bases = vec![path::PathBuf::from("/usr")];
+    assert!(get_vdb(vdb_root.path(), &bases).is_err());
+
+    let (vdb, errors) = get_vdb_lenient(vdb_root.path(), &bases).unwrap();
+    assert_eq!(1, vdb.len());
+    assert_eq!(FileMetadata::Directory, vdb[path::Path::new("/usr/share/foo")].metadata);
+    assert_eq!(1, errors.len());
+    assert!(errors[0].to_string().contains(":2"), "{}", errors[0]);
+}
+
+#[test]
+fn get_vdb_with_stats_filtered() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/foo\ndir /bin/foo\ndir /lib/foo\n");
+    let (vdb, stats) = get_vdb_with_stats(vdb_root.path(), &vec![path::PathBuf::from("/usr")]).unwrap();
+    assert_eq!(1, vdb.len());
+    assert_eq!(1, stats.kept);
+    assert_eq!(2, stats.filtered);
+    assert_eq!(vec![path::PathBuf::from("/bin/foo"), path::PathBuf::from("/lib/foo")], stats.sample);
+}
+
+#[test]
+fn get_vdb_duplicate_path_greatest_atom() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/common\n");
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-2.0"), "dir /usr/share/common\n");
+    write_atom(vdb_root.path(), "app-doc", OsStr::new("zzz-1.0"), "dir /usr/share/common\n");
+    let vdb = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]).unwrap();
+    assert_eq!("app-misc/foo-2.0", vdb[path::Path::new("/usr/share/common")].atom);
+}
+
+#[test]
+fn get_vdb_all_owners_keeps_every_atom() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-2.0"), "dir /usr/share/common\ndir /usr/share/foo\n");
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/common\ndir /usr/share/common\n");
+    let vdb = get_vdb_all_owners(vdb_root.path(), &vec![path::PathBuf::from("/usr")]).unwrap();
+    let atoms: Vec<&str> = vdb[path::Path::new("/usr/share/common")].iter().map(|record| record.atom.as_str()).collect();
+    assert_eq!(vec!["app-misc/foo-1.0", "app-misc/foo-2.0"], atoms);
+    assert_eq!(1, vdb[path::Path::new("/usr/share/foo")].len());
+}
+
+#[test]
+fn newest_atom_mtime_max() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    assert_eq!(None, newest_atom_mtime(vdb_root.path()).unwrap());
+
+    for (atom, mtime) in [("app-misc/foo-1.0", 1000), ("app-misc/bar-1.0", 3000), ("dev-lang/baz-1.0", 2000)] {
+        let (category, atom) = atom.split_at(atom.find('/').unwrap());
+        write_atom(vdb_root.path(), category, OsStr::new(&atom[1..]), "");
+        fs::File::open(vdb_root.path().join(category).join(&atom[1..])).unwrap()
+            .set_modified(time::UNIX_EPOCH + time::Duration::from_secs(mtime)).unwrap();
+    }
+    assert_eq!(Some(3000), newest_atom_mtime(vdb_root.path()).unwrap());
+
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("old-1.0"), "");
+    fs::File::open(vdb_root.path().join("app-misc").join("old-1.0")).unwrap()
+        .set_modified(time::UNIX_EPOCH - time::Duration::from_secs(315619200)).unwrap();
+    assert_eq!(Some(3000), newest_atom_mtime(vdb_root.path()).unwrap());
+}
+
+#[test]
+fn build_times_by_atom() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "");
+    fs::write(vdb_root.path().join("app-misc/foo-1.0/BUILD_TIME"), "1586621688\n").unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("bar-1.0"), "");
+
+    let times = build_times(vdb_root.path()).unwrap();
+    assert_eq!(vec![("app-misc/foo-1.0".to_string(), 1586621688)], times.into_iter().collect::<Vec<_>>());
+
+    fs::write(vdb_root.path().join("app-misc/bar-1.0/BUILD_TIME"), "yesterday\n").unwrap();
+    assert!(matches!(build_times(vdb_root.path()), Err(Error::AtomInvalidBuildTime { .. })));
+}
+
+#[test]
+fn get_vdb_contents_directory() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    fs::create_dir_all(vdb_root.path().join("app-misc/foo-1.0/CONTENTS")).unwrap();
+    let result = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]);
+    assert!(matches!(result, Err(Error::ContentsInvalidType { .. })));
+}
+
+#[test]
+fn get_vdb_missing_contents() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    fs::create_dir_all(vdb_root.path().join("app-misc/foo-1.0")).unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("bar-1.0"), "dir /usr/share/bar\n");
+    let vdb = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]).unwrap();
+    assert_eq!(vec![&path::PathBuf::from("/usr/share/bar")], vdb.keys().collect::<Vec<_>>());
+}
+
+#[cfg(unix)]
+#[test]
+fn get_vdb_unreadable_contents() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/foo\n");
+    let contents = vdb_root.path().join("app-misc/foo-1.0/CONTENTS");
+    fs::set_permissions(&contents, fs::Permissions::from_mode(0o000)).unwrap();
+    // Permissions don't apply to root, who can still read the file.
+    if fs::File::open(&contents).is_err() {
+        let result = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]);
+        assert!(matches!(result, Err(Error::ContentsUnreadable { .. })));
+    }
+
+    // A symlink loop exists, but can't be read even by root.
+    fs::remove_file(&contents).unwrap();
+    std::os::unix::fs::symlink("CONTENTS", &contents).unwrap();
+    let result = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]);
+    assert!(matches!(result, Err(Error::ContentsUnreadable { .. })));
+}
+
+#[test]
+fn get_vdb_merging_atom() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("-MERGING-foo-1.0"), "dir /usr/share/foo\n");
+    let result = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]);
+    assert!(matches!(result, Err(Error::AtomInvalidName { .. })));
+}
+
+#[test]
+fn get_vdb_reserved_names() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/foo\n");
+    fs::write(vdb_root.path().join(".keep"), "").unwrap();
+    fs::write(vdb_root.path().join("app-misc/.keep_app-misc_foo-0"), "").unwrap();
+    fs::write(vdb_root.path().join("app-misc/foo-1.0.portage_lockfile"), "").unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new(".bar-1.0.tmp"), "dir /usr/share/bar\n");
+    let vdb = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]).unwrap();
+    assert_eq!(vec![&path::PathBuf::from("/usr/share/foo")], vdb.keys().collect::<Vec<_>>());
+    assert!(build_times(vdb_root.path()).unwrap().is_empty());
+    assert!(newest_atom_mtime(vdb_root.path()).unwrap().is_some());
+
+    // An unfinished merge still fails.
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("-MERGING-foo-1.1"), "dir /usr/share/foo\n");
+    let result = get_vdb(vdb_root.path(), &vec![path::PathBuf::from("/usr")]);
+    assert!(matches!(result, Err(Error::AtomInvalidName { .. })));
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let record = |atom: &str, metadata| VarDBRecord { metadata, atom: atom.into() };
+    let mut vdb = VarDB::new();
+    vdb.insert("/usr/bin/rustc-1.41.1".into(), record("dev-lang/rust-1.41.1", FileMetadata::Regular {
+        mtime: Some(1586621688),
+        hash: FileHash::MD5([27, 204, 143, 239, 188, 25, 186, 63, 175, 81, 86, 75, 242, 160, 225, 128]),
+        xattrs: None,
+    }));
+    vdb.insert("/usr/bin/rustc".into(), record("app-eselect/eselect-rust-20200419", FileMetadata::Symlink { mtime: 1586621688, dest: "rustc-1.41.1".into() }));
+    vdb.insert("/usr/lib/rustlib".into(), record("dev-lang/rust-1.41.1", FileMetadata::Directory));
+    vdb.insert("/dev/null".into(), record("sys-apps/baselayout-2.7", FileMetadata::Device));
+    vdb.insert("/run/initctl".into(), record("sys-apps/baselayout-2.7", FileMetadata::Fifo));
+
+    let mut snapshot = Vec::new();
+    write_snapshot(&vdb, &mut snapshot).unwrap();
+    assert_eq!(
+        "atom app-eselect/eselect-rust-20200419\n\
+         sym /usr/bin/rustc -> rustc-1.41.1 1586621688\n\
+         atom dev-lang/rust-1.41.1\n\
+         obj /usr/bin/rustc-1.41.1 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688\n\
+         dir /usr/lib/rustlib\n\
+         atom sys-apps/baselayout-2.7\n\
+         dev /dev/null\n\
+         fif /run/initctl\n",
+        String::from_utf8(snapshot.clone()).unwrap(),
+    );
+    assert_ok_eq(vdb, read_snapshot(io::Cursor::new(snapshot), EXAMPLE_PATH.as_ref()));
+}
+
+#[cfg(feature = "sign")]
+#[test]
+fn load_signed_vdb_checks_signature() {
+    use ed25519_dalek::Signer;
+
+    let dir = tempfile::tempdir().unwrap();
+    let snapshot = dir.path().join("vdb.snapshot");
+    let contents = "atom app-misc/foo-1.0\ndir /usr/share/foo\n";
+    let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+    fs::write(&snapshot, contents).unwrap();
+    fs::write(dir.path().join("vdb.snapshot.sig"), key.sign(contents.as_bytes()).to_bytes()).unwrap();
+
+    let vdb = load_signed_vdb(&snapshot, &key.verifying_key()).unwrap();
+    assert_eq!(1, vdb.len());
+
+    fs::write(&snapshot, "atom app-misc/foo-1.0\ndir /usr/share/bar\n").unwrap();
+    let result = load_signed_vdb(&snapshot, &key.verifying_key());
+    assert!(matches!(result, Err(Error::SnapshotSignatureInvalid { .. })));
+}
+
+#[test]
+fn contents_reader_lines() {
+    let long_dir = format!("/usr/share/{}", "a".repeat(100_000));
+    let raw = format!("dir /usr/share/foo\r\ndir {}\nbad\ndir /usr/share/bar", long_dir);
+    let reader = ContentsReader {
+        reader: io::Cursor::new(raw),
+        contents: EXAMPLE_PATH.into(),
+        atom: "app-misc/foo-1.0".into(),
+        line: 0,
+        buffer: String::new(),
+    };
+    let entries: Vec<Result<VarDBEntry, Error>> = reader.collect();
+    assert_eq!(4, entries.len());
+    assert_eq!(path::Path::new("/usr/share/foo"), entries[0].as_ref().unwrap().path);
+    assert_eq!(path::Path::new(&long_dir), entries[1].as_ref().unwrap().path);
+    assert!(matches!(entries[2], Err(Error::EntryMissingField { line: 3, .. })));
+    assert_eq!(path::Path::new("/usr/share/bar"), entries[3].as_ref().unwrap().path);
+}
+
+#[test]
+fn path_trie_prefixes() {
+    let mut vdb = VarDB::new();
+    for path in ["/usr/lib/rustlib", "/usr/lib/rustlib/etc/gdb_load.py", "/usr/bin/rustc"] {
+        vdb.insert(path.into(), VarDBRecord { metadata: FileMetadata::Directory, atom: "dev-lang/rust-1.41.1".into() });
+    }
+    let trie = PathTrie::from(&vdb);
+
+    assert!(trie.contains(path::Path::new("/usr/bin/rustc")));
+    assert!(!trie.contains(path::Path::new("/usr/bin")));
+    assert!(trie.has_descendants(path::Path::new("/usr")));
+    assert!(trie.has_descendants(path::Path::new("/usr/lib/rustlib")));
+    assert!(!trie.has_descendants(path::Path::new("/usr/bin/rustc")));
+    assert!(!trie.has_descendants(path::Path::new("/usr/share")));
+
+    let mut under = trie.paths_under(path::Path::new("/usr/lib"));
+    under.sort();
+    assert_eq!(vec![path::PathBuf::from("/usr/lib/rustlib"), path::PathBuf::from("/usr/lib/rustlib/etc/gdb_load.py")], under);
+}
+
+fn assert_ok_eq<T: PartialEq + fmt::Debug>(expected: T, value: Result<T, Error>) {
     assert!(value.is_ok());
     assert_eq!(expected, value.unwrap());
 }
+
+#[cfg(feature = "parallel")]
+#[test]
+fn get_vdb_parallel_matches_serial() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/foo\nobj /usr/share/foo/bar 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688\n");
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("bar-2.0"), "dir /usr/share/bar\nsym /usr/bin/bar -> ../share/bar 1586621688\n");
+    write_atom(vdb_root.path(), "dev-libs", OsStr::new("baz-0.1"), "dir /usr/share\ndir /usr/share/foo\ndir /etc/baz\n");
+    write_atom(vdb_root.path(), "sys-apps", OsStr::new("qux-3"), "dir /usr/share\nobj /usr/bin/qux 6cd3556deb0da54bca060b4c39479839 1586621688\n");
+    let bases = vec![path::PathBuf::from("/usr")];
+
+    let vdb = get_vdb_parallel(vdb_root.path(), &bases).unwrap();
+    assert_eq!(get_vdb(vdb_root.path(), &bases).unwrap(), vdb);
+    assert_eq!(6, vdb.len());
+    // A path recorded in several categories goes to the greatest atom, as with `get_vdb`.
+    assert_eq!("sys-apps/qux-3", vdb[path::Path::new("/usr/share")].atom);
+    assert_eq!("dev-libs/baz-0.1", vdb[path::Path::new("/usr/share/foo")].atom);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn get_vdb_parallel_error() {
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), "dir /usr/share/foo\n");
+    write_atom(vdb_root.path(), "dev-libs", OsStr::new("bar-1.0"), "dir /usr/share/bar\nbogus /usr/share/bar/baz\n");
+    let bases = vec![path::PathBuf::from("/usr")];
+
+    let error = get_vdb_parallel(vdb_root.path(), &bases).unwrap_err();
+    assert_eq!(get_vdb(vdb_root.path(), &bases).unwrap_err().to_string(), error.to_string());
+}
+
+#[test]
+fn parse_contents_in_memory() {
+    struct InMemory(&'static str);
+
+    impl ContentsSource for InMemory {
+        fn open(&self) -> Result<(path::PathBuf, Box<dyn io::BufRead>), Error> {
+            Ok((path::PathBuf::from("foo-1.0.tbz2:CONTENTS"), Box::new(io::Cursor::new(self.0.as_bytes()))))
+        }
+    }
+
+    let contents = "dir /usr/share/foo\nobj /usr/share/foo/bar 1bcc8fefbc19ba3faf51564bf2a0e180 1586621688\nsym /usr/bin/foo -> bar 1586621688\n";
+    let entries: Vec<VarDBEntry> = parse_contents(&InMemory(contents), "app-misc/foo-1.0").unwrap().map(Result::unwrap).collect();
+    assert_eq!(3, entries.len());
+    assert!(entries.iter().all(|entry| entry.atom == "app-misc/foo-1.0"));
+
+    // Same entries as from an atom folder.
+    let vdb_root = tempfile::tempdir().unwrap();
+    write_atom(vdb_root.path(), "app-misc", OsStr::new("foo-1.0"), contents);
+    let atom_dir = vdb_root.path().join("app-misc/foo-1.0");
+    let from_disk: Vec<VarDBEntry> = parse_contents(atom_dir.as_path(), "app-misc/foo-1.0").unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries, from_disk);
+
+    let error = parse_contents(&InMemory("dir /usr\nbogus /usr/foo\n"), "app-misc/foo-1.0").unwrap()
+        .find_map(Result::err).unwrap();
+    assert!(error.to_string().starts_with("foo-1.0.tbz2:CONTENTS:2:"), "{}", error);
+}
@@ -0,0 +1,81 @@
+use std::collections;
+use std::ffi;
+use std::path;
+
+use super::VarDB;
+
+/// An index of recorded paths by component, for fast queries on path prefixes.
+///
+/// Built once from a `VarDB`, it answers "is anything recorded below this folder"
+/// without scanning every entry.
+#[derive(Debug, Default)]
+pub struct PathTrie {
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: collections::HashMap<ffi::OsString, Node>,
+    /// Whether the path leading to this node is itself recorded.
+    recorded: bool,
+}
+
+impl PathTrie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, path: &path::Path) {
+        let mut node = &mut self.root;
+        for component in path.components() {
+            node = node.children.entry(component.as_os_str().to_owned()).or_default();
+        }
+        node.recorded = true;
+    }
+
+    fn find(&self, path: &path::Path) -> Option<&Node> {
+        let mut node = &self.root;
+        for component in path.components() {
+            node = node.children.get(component.as_os_str())?;
+        }
+        Some(node)
+    }
+
+    /// Whether `path` itself is recorded.
+    pub fn contains(&self, path: &path::Path) -> bool {
+        self.find(path).is_some_and(|node| node.recorded)
+    }
+
+    /// Whether any path strictly below `dir` is recorded.
+    pub fn has_descendants(&self, dir: &path::Path) -> bool {
+        self.find(dir).is_some_and(|node| !node.children.is_empty())
+    }
+
+    /// All recorded paths below `dir`, including `dir` itself if recorded, in no particular order.
+    pub fn paths_under(&self, dir: &path::Path) -> Vec<path::PathBuf> {
+        let mut paths = Vec::new();
+        if let Some(node) = self.find(dir) {
+            collect(node, dir.to_path_buf(), &mut paths);
+        }
+        paths
+    }
+}
+
+fn collect(node: &Node, path: path::PathBuf, paths: &mut Vec<path::PathBuf>) {
+    for (name, child) in node.children.iter() {
+        collect(child, path.join(name), paths);
+    }
+    if node.recorded {
+        paths.push(path);
+    }
+}
+
+impl From<&VarDB> for PathTrie {
+    fn from(vdb: &VarDB) -> Self {
+        let mut trie = PathTrie::new();
+        for path in vdb.keys() {
+            trie.insert(path);
+        }
+        trie
+    }
+}
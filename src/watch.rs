@@ -0,0 +1,160 @@
+//! Live monitoring of checked trees, through inotify.
+
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::{check_file, compare, CheckOptions, Error, ManifestProvider, ResultItem};
+
+/// Events triggering a new classification of a file.
+const WATCHED_EVENTS: WatchMask = WatchMask::CLOSE_WRITE
+    .union(WatchMask::ATTRIB)
+    .union(WatchMask::CREATE)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::DELETE_SELF);
+
+/// Size of the buffer receiving inotify events; fits many events with long names.
+const EVENTS_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Watch the given paths, yielding an up-to-date result each time a file changes.
+///
+/// Paths are watched recursively, including folders created after the call, whose
+/// files are all checked, e.g. for a tree moved in; a removed owned file is reported
+/// as `Absent`, a new unowned one as `Orphan`.
+/// Removed orphans don't yield anything, as they have nothing left to report.
+///
+/// This doesn't scan the paths: run `statuses_against` first for a baseline.
+pub fn watch<'a>(
+    paths: impl IntoIterator<Item=path::PathBuf>,
+    expectations: &'a dyn ManifestProvider,
+    options: &'a CheckOptions,
+) -> Result<impl Iterator<Item=Result<ResultItem, Error>> + 'a, Error> {
+    let inotify = Inotify::init().map_err(|e| Error::WatchInterrupted { source: e })?;
+    let mut watcher = Watcher {
+        inotify,
+        dirs: collections::HashMap::new(),
+        expectations,
+        options,
+        buffer: vec![0; EVENTS_BUFFER_SIZE],
+        pending: collections::VecDeque::new(),
+    };
+    for base in paths {
+        watcher.add_tree(&base, &mut Vec::new())?;
+    }
+    Ok(watcher)
+}
+
+struct Watcher<'a> {
+    inotify: Inotify,
+    /// The watched folders, by watch descriptor.
+    dirs: collections::HashMap<WatchDescriptor, path::PathBuf>,
+    expectations: &'a dyn ManifestProvider,
+    options: &'a CheckOptions,
+    buffer: Vec<u8>,
+    /// Results from already read events, not yet yielded.
+    pending: collections::VecDeque<Result<ResultItem, Error>>,
+}
+
+impl<'a> Watcher<'a> {
+    /// Watch a folder and all its subfolders, adding the other files found to `files`.
+    fn add_tree(&mut self, base: &path::Path, files: &mut Vec<path::PathBuf>) -> Result<(), Error> {
+        let descriptor = self.inotify.watches().add(base, WATCHED_EVENTS)
+            .map_err(|e| Error::WatchFailed { path: base.into(), source: e })?;
+        self.dirs.insert(descriptor, base.into());
+
+        let entries = fs::read_dir(base).map_err(|e| Error::DirUnreadable { path: base.into(), source: e })?;
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::DirUnreadable { path: base.into(), source: e })?;
+            let file_type = entry.file_type().map_err(|e| Error::DirUnreadable { path: entry.path(), source: e })?;
+            if file_type.is_dir() {
+                self.add_tree(&entry.path(), files)?;
+            } else {
+                files.push(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    /// Classify a file after a change, or `None` if a removed orphan.
+    fn check(&self, disk_path: &path::Path) -> Option<Result<ResultItem, Error>> {
+        let path = self.options.recorded_path(disk_path);
+        let result = match self.expectations.expected(&path) {
+            Some(expected) => compare(&path, expected, self.options),
+            None => match fs::symlink_metadata(disk_path) {
+                Ok(metadata) => check_file(path.clone(), disk_path, &metadata, None, self.options),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+                Err(e) => Err(Error::FileUnreadable { path: disk_path.into(), source: e }),
+            },
+        };
+        Some(result.map(|mut result| {
+            result.owner = self.expectations.owner(&path).map(String::from);
+            result
+        }))
+    }
+
+    /// Wait for the next events, queuing their results.
+    fn read_events(&mut self) -> Result<(), Error> {
+        let events = self.inotify.read_events_blocking(&mut self.buffer)
+            .map_err(|e| Error::WatchInterrupted { source: e })?;
+        let mut changed = Vec::new();
+        let mut new_dirs = Vec::new();
+        for event in events {
+            // The folder is gone, and so is its watch.
+            if event.mask.intersects(EventMask::IGNORED | EventMask::DELETE_SELF) {
+                self.dirs.remove(&event.wd);
+                continue;
+            }
+            let (dir, name) = match (self.dirs.get(&event.wd), event.name) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => continue,
+            };
+            let path = dir.join(name);
+            if event.mask.contains(EventMask::ISDIR) {
+                if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    new_dirs.push(path);
+                }
+            } else if !changed.contains(&path) {
+                // e.g. a new file triggers both CREATE and CLOSE_WRITE
+                changed.push(path);
+            }
+        }
+        for dir in new_dirs {
+            // Files may have been created before the folder was watched.
+            let mut files = Vec::new();
+            match self.add_tree(&dir, &mut files) {
+                // Already removed again.
+                Err(Error::WatchFailed { ref source, .. }) | Err(Error::DirUnreadable { ref source, .. })
+                    if source.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => self.pending.push_back(Err(e)),
+                Ok(()) => {},
+            }
+            for path in files {
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+        }
+        for path in changed {
+            self.pending.extend(self.check(&path));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Watcher<'a> {
+    type Item = Result<ResultItem, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            if let Err(e) = self.read_events() {
+                return Some(Err(e));
+            }
+        }
+        self.pending.pop_front()
+    }
+}